@@ -1,7 +1,18 @@
-use std::{path::PathBuf, process};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
 
-use clap::{Parser, Subcommand, ValueEnum};
-use kindle_manager::{image_converter, KindleManager};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use kindle_manager::{
+    fbink::{Font, RefreshMode, RefreshOptions, TextOptions, TextRefresh, Waveform},
+    image_converter::{self, Background, ConvertOptions, Dither, Fit, Palette},
+    prep::PrepReport,
+    DeviceProfile, KindleManager, KindleModel, KindleTransport, TransferProgress,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -15,6 +26,15 @@ struct Cli {
     #[arg(short, long, default_value_t = String::from("/mnt/us/images"))]
     location: String,
 
+    /// Use a fake Kindle stored in this local directory instead of connecting through SSH
+    #[arg(long, value_name = "DIR")]
+    local: Option<PathBuf>,
+
+    /// Kindle model, used for its screen resolution and backlight. Detected when missing, except
+    /// by convert which assumes a pw2 so it works offline, pass auto to detect it there too
+    #[arg(short, long, value_enum)]
+    model: Option<Model>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,7 +42,18 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Prepares the Kindle by disabling the screensaver and other services.
-    Prep,
+    Prep {
+        /// Service to stop instead of the model's, repeat for each one, e.g. --service framework
+        #[arg(long = "service")]
+        services: Vec<String>,
+    },
+    /// Undoes prep, restarting the services and restoring the screensaver and CPU governor
+    #[command(alias = "unprep")]
+    Restore {
+        /// Service to start when the Kindle lost the record of prep, like the ones given to prep
+        #[arg(long = "service")]
+        services: Vec<String>,
+    },
     /// Lists all files in the specified location
     List,
     /// Deletes a file
@@ -42,16 +73,61 @@ enum Commands {
         new_filename: String,
     },
     /// Shows an image on screen from the specified location
-    Set { filename: String },
+    Set {
+        filename: String,
+        #[command(flatten)]
+        refresh: RefreshSettings,
+    },
     /// Prints information about the current battery state
     #[clap(visible_aliases = &["battery", "bat"])]
     BatteryInfo,
     /// Shows a debug message on screen
-    #[clap(visible_alias = "print")]
     DebugPrint { message: String },
+    /// Prints text over what the screen shows, each line of it on its own row
+    Print {
+        text: String,
+        #[command(flatten)]
+        settings: PrintSettings,
+    },
     Backlight {
-        #[arg(value_parser = clap::value_parser!(u8))]
-        intensity: u8,
+        #[arg(value_parser = clap::value_parser!(u16))]
+        intensity: u16,
+    },
+    /// Shows the Kindle model and its screen details
+    Info,
+    /// Saves what the screen shows as a PNG
+    Screenshot {
+        #[arg(default_value = "screenshot.png")]
+        file_path: PathBuf,
+    },
+    /// Controls the slideshow of a running kindle_server
+    Slideshow {
+        /// Address of the server
+        #[arg(long, default_value_t = String::from("http://localhost:8000"))]
+        server: String,
+        /// API token of an account on the server, admin unless only reading
+        #[arg(long, env = "KINDLE_SERVER_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        #[command(subcommand)]
+        action: SlideshowAction,
+    },
+    /// Syncs the images of a running kindle_server with the Kindle, by content
+    Sync {
+        /// Address of the server
+        #[arg(long, default_value_t = String::from("http://localhost:8000"))]
+        server: String,
+        /// API token of an account on the server, admin unless only reading
+        #[arg(long, env = "KINDLE_SERVER_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// Device on the server to sync, its default one if left out
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Only shows what would change
+        #[arg(short = 'n', long, action)]
+        dry_run: bool,
+        /// Version kept when an image changed on both sides, otherwise conflicts are skipped
+        #[arg(short, long, value_enum)]
+        prefer: Option<SyncSide>,
     },
     /// Convert an image into a Kindle-appropriate format
     Convert {
@@ -59,46 +135,323 @@ enum Commands {
         original_path: PathBuf,
         /// Path to destination
         final_path: PathBuf,
-        /// Background color
-        #[arg(
-            short, 
-            long, 
-            require_equals = true, 
-            num_args = 0..=1, 
-            default_value_t = BackgroundColor::Gray, 
-            default_missing_value = "Gray", 
-            value_enum
-        )]
-        background: BackgroundColor,
-        #[clap(long, short, action)]
-        stretch: bool,
+        #[command(flatten)]
+        settings: ConvertSettings,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum SlideshowAction {
+    /// Starts cycling through images already on the Kindle
+    Start {
+        /// Images to show, `IMAGE:SECONDS` keeps one on screen for longer or shorter
+        #[arg(required = true, value_name = "IMAGE[:SECONDS]", value_parser = parse_slide)]
+        slides: Vec<(String, Option<u64>)>,
+        /// Seconds between images
+        #[arg(short, long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Shows the images in a random order
+        #[arg(short, long, action)]
+        shuffle: bool,
+        #[command(flatten)]
+        refresh: RefreshSettings,
+        /// Full refresh after this many partial ones, cleaning up ghosts, 0 never does
+        #[arg(long, default_value_t = 0)]
+        full_every: u32,
+    },
+    Stop,
+    /// Shows the next image right away
+    Skip,
+    /// Shows what the slideshow is doing
+    Status,
+}
+
+/// Slideshow state as reported by the server
+#[derive(Debug, Deserialize)]
+struct SlideshowStatus {
+    running: bool,
+    current: Option<Slide>,
+    slides: Vec<Slide>,
+    interval: u64,
+    order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Slide {
+    image: String,
+    duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SyncSide {
+    Server,
+    Kindle,
+}
+
+/// Image that differs between the server and the Kindle, as reported by the server
+#[derive(Debug, Deserialize)]
+struct SyncEntry {
+    name: String,
+    action: String,
+}
+
+/// Outcome of syncing an image, as reported by the server
+#[derive(Debug, Deserialize)]
+struct SyncResult {
+    name: String,
+    action: String,
+    error: Option<String>,
+}
+
+/// Settings used when converting images
+#[derive(Args, Debug)]
+struct ConvertSettings {
+    /// Background color
+    #[arg(
+        short,
+        long,
+        require_equals = true,
+        num_args = 0..=1,
+        default_value_t = BackgroundColor::Gray,
+        default_missing_value = "Gray",
+        value_enum
+    )]
+    background: BackgroundColor,
+    /// Stretch the image to cover the screen, same as `--fit stretch`
+    #[clap(long, short, action, conflicts_with = "fit")]
+    stretch: bool,
+    /// How the image is sized to the screen
+    #[arg(short, long, default_value_t = FitMode::Fit, value_enum)]
+    fit: FitMode,
+    /// Point kept on screen when filling, as `X,Y` from 0,0 (top left) to 1,1 (bottom right).
+    /// Picked automatically when missing
+    #[arg(long, value_name = "X,Y", value_parser = parse_focal_point)]
+    focus: Option<(f32, f32)>,
+    /// Image whose gray levels are used instead of the Kindle's palette
+    #[arg(short, long, value_name = "FILE")]
+    palette: Option<PathBuf>,
+    /// Dithering algorithm, `none` or `ordered` work best for text and line-art
+    #[arg(short, long, default_value_t = DitherArg::FloydSteinberg, value_enum)]
+    dither: DitherArg,
+    /// Brightness adjustment, from -100 to 100
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-100..=100))]
+    brightness: i32,
+    /// Contrast adjustment, from -100 to 100
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-100..=100))]
+    contrast: i32,
+    /// Gamma correction, above 1 brightens the midtones
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f32,
+    /// Sharpening radius, 0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    sharpen: f32,
+}
+
+#[derive(Debug, Args)]
+struct RefreshSettings {
+    /// full, partial (faster, no flashing but leaves ghosts) or region:X,Y,WIDTH,HEIGHT to only
+    /// redraw part of the screen
+    #[arg(long, default_value_t = RefreshMode::Full, value_parser = str::parse::<RefreshMode>)]
+    refresh: RefreshMode,
+    /// E-ink waveform: auto, du, gc16, gc4, a2, gl16, reagl or reagld. Anything but a full refresh
+    /// with auto needs FBInk on the Kindle
+    #[arg(short, long, default_value_t = Waveform::Auto, value_parser = str::parse::<Waveform>)]
+    waveform: Waveform,
+}
+
+#[derive(Debug, Args)]
+struct PrintSettings {
+    /// Built-in FBInk font, like ibm, unscii, terminus, spleen or cozette
+    #[arg(short, long, default_value_t = Font::Ibm, value_parser = str::parse::<Font>)]
+    font: Font,
+    /// Multiplier of the font size, 0 picks one for the screen
+    #[arg(short, long, default_value_t = 0)]
+    size: u8,
+    /// Row of the first line, negative counts from the bottom
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    row: i16,
+    /// Column of the first character, negative counts from the right
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    column: i16,
+    /// Centers the lines horizontally
+    #[arg(long, action)]
+    centered: bool,
+    /// Centers the text vertically
+    #[arg(long, action)]
+    halfway: bool,
+    /// White text on black
+    #[arg(short, long, action)]
+    inverse: bool,
+    /// Fills the rest of the lines with blanks, hiding what's under them
+    #[arg(short, long, action)]
+    padded: bool,
+    /// Clears the screen first
+    #[arg(long, action)]
+    clear: bool,
+    /// partial, flash to clean up ghosts, or none to wait for the next refresh
+    #[arg(long, default_value_t = TextRefresh::Partial, value_parser = str::parse::<TextRefresh>)]
+    refresh: TextRefresh,
+}
+
+impl PrintSettings {
+    fn options(&self) -> TextOptions {
+        TextOptions {
+            font: self.font,
+            size: self.size,
+            row: self.row,
+            column: self.column,
+            centered: self.centered,
+            halfway: self.halfway,
+            inverse: self.inverse,
+            padded: self.padded,
+            clear: self.clear,
+            refresh: self.refresh,
+        }
+    }
+}
+
+impl ConvertSettings {
+    fn options(&self) -> ConvertOptions {
+        let palette = match self.palette.as_deref().map(Palette::from_image).transpose() {
+            Ok(palette) => palette,
+            Err(err) => {
+                eprintln!("Failed to read the palette!");
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        };
+
+        ConvertOptions {
+            background: match self.background {
+                BackgroundColor::White => Background::White,
+                BackgroundColor::LightGray => Background::LightGray,
+                BackgroundColor::Gray => Background::DarkGray,
+                BackgroundColor::Black => Background::Black,
+                BackgroundColor::Auto => Background::Auto,
+            },
+            fit: match (self.stretch, self.fit) {
+                (true, _) | (false, FitMode::Stretch) => Fit::Stretch,
+                (false, FitMode::Fit) => Fit::Contain,
+                (false, FitMode::Fill) => Fit::Fill,
+            },
+            focal_point: self.focus,
+            palette,
+            dither: match self.dither {
+                DitherArg::None => Dither::None,
+                DitherArg::Ordered => Dither::Ordered,
+                DitherArg::FloydSteinberg => Dither::FloydSteinberg,
+                DitherArg::Atkinson => Dither::Atkinson,
+                DitherArg::Stucki => Dither::Stucki,
+                DitherArg::Sierra => Dither::Sierra,
+            },
+            brightness: self.brightness,
+            contrast: self.contrast,
+            gamma: self.gamma,
+            sharpen: self.sharpen,
+        }
+    }
+}
+
+fn parse_slide(value: &str) -> Result<(String, Option<u64>), String> {
+    match value.rsplit_once(':') {
+        Some((image, seconds)) => match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Ok((image.to_string(), Some(seconds))),
+            _ => Err(format!("\"{seconds}\" isn't a number of seconds")),
+        },
+        None => Ok((value.to_string(), None)),
+    }
+}
+
+fn parse_focal_point(value: &str) -> Result<(f32, f32), String> {
+    let (x, y) = value
+        .split_once(',')
+        .ok_or_else(|| "expected X,Y".to_string())?;
+    let parse = |coordinate: &str| match coordinate.trim().parse::<f32>() {
+        Ok(coordinate) if (0.0..=1.0).contains(&coordinate) => Ok(coordinate),
+        _ => Err(format!("\"{coordinate}\" isn't a number between 0 and 1")),
+    };
+    Ok((parse(x)?, parse(y)?))
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum FitMode {
+    /// Fit the whole image, filling the rest with the background color
+    Fit,
+    /// Cover the screen, ignoring the aspect ratio
+    Stretch,
+    /// Cover the screen, cropping around the focal point
+    Fill,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum DitherArg {
+    None,
+    /// Ordered Bayer matrix
+    Ordered,
+    FloydSteinberg,
+    Atkinson,
+    Stucki,
+    Sierra,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Model {
+    /// Detect the model from the Kindle's serial number
+    Auto,
+    Pw2,
+    Pw3,
+    Voyage,
+    Oasis,
+    Basic,
+}
+
+impl Model {
+    fn kindle_model(self) -> Option<KindleModel> {
+        match self {
+            Model::Auto => None,
+            Model::Pw2 => Some(KindleModel::PaperWhite2),
+            Model::Pw3 => Some(KindleModel::PaperWhite3),
+            Model::Voyage => Some(KindleModel::Voyage),
+            Model::Oasis => Some(KindleModel::Oasis),
+            Model::Basic => Some(KindleModel::Basic),
+        }
+    }
+}
+
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 enum BackgroundColor {
     White,
     LightGray,
     Gray,
     Black,
-    // TODO: Automatically find background color based on image
-    // Auto,
+    /// Matches the edges of the image
+    Auto,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
 
-    let kindle_manager = KindleManager::new(args.address, args.location);
+    let mut kindle_manager = match args.local {
+        Some(root) => KindleManager::new_local(root, args.location),
+        None => KindleManager::new(args.address, args.location),
+    };
+    if let Some(model) = args.model.and_then(Model::kindle_model) {
+        kindle_manager = kindle_manager.with_profile(DeviceProfile::for_model(model));
+    }
+    let detect = matches!(args.model, None | Some(Model::Auto));
 
     match args.command {
         Commands::Convert { original_path, 
             final_path, 
-            background , 
-            stretch} => {
-                convert_image(background, stretch, &original_path, &final_path).await;
+            settings } => {
+                // Converting a local file shouldn't need the Kindle unless asked to
+                let detect = args.model == Some(Model::Auto);
+                let profile = resolve_profile(&kindle_manager, detect).await;
+                convert_image(&settings.options(), &profile, &original_path, &final_path).await;
             },
-        Commands::Prep => prep(&kindle_manager).await,
+        Commands::Prep { services } => prep(&kindle_manager, detect, services).await,
+        Commands::Restore { services } => restore(&kindle_manager, detect, services).await,
         Commands::List => list_files(&kindle_manager).await,
         Commands::Delete { filename } => delete_file(&kindle_manager, &filename).await,
         Commands::Push {
@@ -110,14 +463,19 @@ async fn main() {
             file_path,
         } => pull_file(&kindle_manager, &filename, &file_path).await,
         Commands::Rename { old_filename, new_filename } => rename(&kindle_manager, &old_filename, &new_filename).await,
-        Commands::Set { filename } => set_image(&kindle_manager, &filename).await,
+        Commands::Set { filename, refresh } => set_image(&kindle_manager, detect, &filename, &refresh).await,
         Commands::BatteryInfo => info_battery(&kindle_manager).await,
         Commands::DebugPrint { message } => debug_print(&kindle_manager, &message).await,
-        Commands::Backlight { intensity } => set_backlight(&kindle_manager, intensity).await,
+        Commands::Print { text, settings } => print_text(&kindle_manager, &text, &settings.options()).await,
+        Commands::Backlight { intensity } => set_backlight(&kindle_manager, detect, intensity).await,
+        Commands::Info => info(&kindle_manager, detect).await,
+        Commands::Screenshot { file_path } => screenshot(&kindle_manager, detect, &file_path).await,
+        Commands::Slideshow { server, token, action } => slideshow(&server, token.as_deref(), action).await,
+        Commands::Sync { server, token, device, dry_run, prefer } => sync(&server, token.as_deref(), device.as_deref(), dry_run, prefer).await,
     }
 }
 
-async fn new_session(kindle_manager: &KindleManager) -> openssh::Session{
+async fn new_session(kindle_manager: &KindleManager) -> Box<dyn KindleTransport> {
     match kindle_manager.new_session().await {
         Ok(session) => session,
         Err(err) => {
@@ -128,15 +486,30 @@ async fn new_session(kindle_manager: &KindleManager) -> openssh::Session{
     }
 }
 
-async fn convert_image(background: BackgroundColor, stretch: bool, origin: &PathBuf, destination: &PathBuf) {
-    let color = match background {
-        BackgroundColor::White => "white",
-        BackgroundColor::LightGray => "gray60",
-        BackgroundColor::Gray => "gray20",
-        BackgroundColor::Black => "black",
+/// Profile chosen with `--model`, or detected from the Kindle when it is `auto`.
+/// Falls back to the default profile if the Kindle can't be reached.
+async fn resolve_profile(kindle_manager: &KindleManager, detect: bool) -> DeviceProfile {
+    if !detect {
+        return kindle_manager.profile();
+    }
+
+    let detected = match kindle_manager.new_session().await {
+        Ok(session) => kindle_manager.detect_profile(&*session).await,
+        Err(err) => Err(err),
     };
+    match detected {
+        Ok(profile) => profile,
+        Err(err) => {
+            let profile = kindle_manager.profile();
+            eprintln!("Failed to detect the Kindle model, assuming {}", profile.name);
+            eprintln!("{err}");
+            profile
+        }
+    }
+}
 
-    match image_converter::convert_image(color, stretch, origin, destination) {
+async fn convert_image(options: &ConvertOptions, profile: &DeviceProfile, origin: &Path, destination: &Path) {
+    match image_converter::convert_image(options, profile, origin, destination) {
         Ok(_) => println!("Converted successfully"),
         Err(err) => {
             eprintln!("Failed to convert the image!");
@@ -146,21 +519,57 @@ async fn convert_image(background: BackgroundColor, stretch: bool, origin: &Path
     }
 }
 
-async fn prep(kindle_manager: &KindleManager) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.prep(&session).await {
-        Ok(_) => println!("Kindle is prepared to show images now"),
+async fn prep(kindle_manager: &KindleManager, detect: bool, services: Vec<String>) {
+    let services = match services.is_empty() {
+        true => resolve_profile(kindle_manager, detect).await.prep_services,
+        false => services,
+    };
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.prep_with(&*session, &services).await {
+        Ok(report) => {
+            print_report(&report);
+            match report.changed() {
+                true => println!("Kindle is prepared to show images now"),
+                false => println!("Kindle was already prepared"),
+            }
+        }
         Err(err) => {
-            eprintln!("Failed to prepare the Kindle. Restart it before trying again");
+            eprintln!("Failed to prepare the Kindle, prep can be run again or undone with restore");
             eprintln!("{err}");
             process::exit(1);
         }
     }
 }
 
+async fn restore(kindle_manager: &KindleManager, detect: bool, services: Vec<String>) {
+    let services = match services.is_empty() {
+        true => resolve_profile(kindle_manager, detect).await.prep_services,
+        false => services,
+    };
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.restore_with(&*session, &services).await {
+        Ok(report) => {
+            print_report(&report);
+            println!("Kindle is back to reading mode");
+        }
+        Err(err) => {
+            eprintln!("Failed to restore the Kindle. Restart it to get back to reading mode");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+}
+
+/// One step per line, what it changed first
+fn print_report(report: &PrepReport) {
+    for step in &report.steps {
+        println!("{:<10} {}", step.outcome.id(), step.description);
+    }
+}
+
 async fn list_files(kindle_manager: &KindleManager) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.list_files(&session).await {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.list_files(&*session).await {
         Ok(files) => {
             if files.is_empty() {
                 println!("No files found!")
@@ -179,8 +588,8 @@ async fn list_files(kindle_manager: &KindleManager) {
 }
 
 async fn delete_file(kindle_manager: &KindleManager, filename: &str) {
-    let session = new_session(&kindle_manager).await;
-    if let Err(err) = kindle_manager.delete_file(&session, filename).await {
+    let session = new_session(kindle_manager).await;
+    if let Err(err) = kindle_manager.delete_file(&*session, filename).await {
         eprintln!("Failed to delete \"{filename}\"");
         eprintln!("{err}");
         process::exit(1);
@@ -189,10 +598,22 @@ async fn delete_file(kindle_manager: &KindleManager, filename: &str) {
     }
 }
 
-async fn pull_file(kindle_manager: &KindleManager, filename: &str, file_path: &PathBuf) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.pull_file(&session, filename, file_path).await {
-        Ok(_) => println!("Pulled \"{filename}\""),
+fn print_progress(progress: TransferProgress) {
+    let percent = match progress.total {
+        0 => 100,
+        total => progress.transferred * 100 / total,
+    };
+    print!("\r{percent:>3}% ({}/{} bytes)", progress.transferred, progress.total);
+    let _ = io::stdout().flush();
+}
+
+async fn pull_file(kindle_manager: &KindleManager, filename: &str, file_path: &Path) {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager
+        .pull_file_with_progress(&*session, filename, file_path, Some(&print_progress))
+        .await
+    {
+        Ok(_) => println!("\nPulled \"{filename}\""),
         Err(err) => {
             eprintln!("Failed to pull file");
             eprintln!("{err}");
@@ -201,10 +622,13 @@ async fn pull_file(kindle_manager: &KindleManager, filename: &str, file_path: &P
     }
 }
 
-async fn push_file(kindle_manager: &KindleManager, file_path: &PathBuf, filename: &str) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.push_file(&session, file_path, filename).await {
-        Ok(_) => println!("Pushed \"{filename}\""),
+async fn push_file(kindle_manager: &KindleManager, file_path: &Path, filename: &str) {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager
+        .push_file_with_progress(&*session, file_path, filename, Some(&print_progress))
+        .await
+    {
+        Ok(_) => println!("\nPushed \"{filename}\""),
         Err(err) => {
             eprintln!("Failed to push file");
             eprintln!("{err}");
@@ -214,8 +638,8 @@ async fn push_file(kindle_manager: &KindleManager, file_path: &PathBuf, filename
 }
 
 async fn rename(kindle_manager: &KindleManager, old_filename: &str, new_filename: &str) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.rename_file(&session, old_filename, new_filename).await {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.rename_file(&*session, old_filename, new_filename).await {
         Ok(_) => println!("Renamed \"{old_filename}\" to \"{new_filename}\""),
         Err(err) => {
             eprintln!("Failed to rename file");
@@ -225,9 +649,18 @@ async fn rename(kindle_manager: &KindleManager, old_filename: &str, new_filename
     }
 }
 
-async fn set_image(kindle_manager: &KindleManager, filename: &str) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.set_image(&session, filename).await {
+async fn set_image(kindle_manager: &KindleManager, detect: bool, filename: &str, refresh: &RefreshSettings) {
+    // Regions are checked against the screen
+    if matches!(refresh.refresh, RefreshMode::Region { .. }) {
+        resolve_profile(kindle_manager, detect).await;
+    }
+    let options = RefreshOptions {
+        mode: refresh.refresh,
+        waveform: refresh.waveform,
+        full_every: 0,
+    };
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.set_image_with(&*session, filename, &options).await {
         Ok(_) => println!("Image \"{filename}\" set"),
         Err(err) => {
             eprintln!("Failed to set image \"{filename}\"");
@@ -238,8 +671,8 @@ async fn set_image(kindle_manager: &KindleManager, filename: &str) {
 }
 
 async fn info_battery(kindle_manager: &KindleManager) {
-    let session = new_session(&kindle_manager).await;
-    let charge = match kindle_manager.battery_charge(&session).await {
+    let session = new_session(kindle_manager).await;
+    let charge = match kindle_manager.battery_charge(&*session).await {
         Ok(charge) => charge,
         Err(err) => {
             eprintln!("Failed to get battery charge");
@@ -248,7 +681,7 @@ async fn info_battery(kindle_manager: &KindleManager) {
         }
     };
 
-    let load = match kindle_manager.battery_load(&session).await {
+    let load = match kindle_manager.battery_load(&*session).await {
         Ok(load) => load,
         Err(err) => {
             eprintln!("Failed to get battery load");
@@ -261,8 +694,8 @@ async fn info_battery(kindle_manager: &KindleManager) {
 }
 
 async fn debug_print(kindle_manager: &KindleManager, text: &str) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.debug_print(&session, text).await {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.debug_print(&*session, text).await {
         Ok(_) => println!("Printed \"{text}\""),
         Err(err) => {
             eprintln!("Failed to print debug message!");
@@ -272,9 +705,22 @@ async fn debug_print(kindle_manager: &KindleManager, text: &str) {
     }
 }
 
-async fn set_backlight(kindle_manager: &KindleManager, intensity: u8) {
-    let session = new_session(&kindle_manager).await;
-    match kindle_manager.set_backlight(&session, intensity).await {
+async fn print_text(kindle_manager: &KindleManager, text: &str, options: &TextOptions) {
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.print_text(&*session, text, options).await {
+        Ok(_) => println!("Printed \"{text}\""),
+        Err(err) => {
+            eprintln!("Failed to print the text!");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+}
+
+async fn set_backlight(kindle_manager: &KindleManager, detect: bool, intensity: u16) {
+    resolve_profile(kindle_manager, detect).await;
+    let session = new_session(kindle_manager).await;
+    match kindle_manager.set_backlight(&*session, intensity).await {
         Ok(_) => println!("Backlight set at \"{intensity}\""),
         Err(err) => {
             eprintln!("Failed to set backlight intensity!");
@@ -283,3 +729,203 @@ async fn set_backlight(kindle_manager: &KindleManager, intensity: u8) {
         }
     }
 }
+
+async fn info(kindle_manager: &KindleManager, detect: bool) {
+    let profile = resolve_profile(kindle_manager, detect).await;
+    println!("{}", profile.name);
+    println!("- Resolution: {} ({} DPI)", profile.resolution(), profile.dpi);
+    println!("- Gray levels: {}", profile.gray_levels);
+    match profile.backlight_path {
+        Some(path) => println!("- Backlight: {path} (0..={})", profile.max_intensity),
+        None => println!("- Backlight: none"),
+    }
+    println!("- Rotation: {}", if profile.supports_rotation { "supported" } else { "unsupported" });
+}
+
+async fn screenshot(kindle_manager: &KindleManager, detect: bool, file_path: &Path) {
+    resolve_profile(kindle_manager, detect).await;
+    let session = new_session(kindle_manager).await;
+    let png = match kindle_manager.screenshot(&*session).await {
+        Ok(png) => png,
+        Err(err) => {
+            eprintln!("Failed to take a screenshot");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+    match fs::write(file_path, png) {
+        Ok(_) => println!("Saved the screen to \"{}\"", file_path.display()),
+        Err(err) => {
+            eprintln!("Failed to save the screenshot");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+}
+
+async fn slideshow(server: &str, token: Option<&str>, action: SlideshowAction) {
+    let client = reqwest::Client::new();
+    let server = server.trim_end_matches('/');
+
+    let (request, done) = match action {
+        SlideshowAction::Start {
+            slides,
+            interval,
+            shuffle,
+            refresh,
+            full_every,
+        } => {
+            let mut form = vec![
+                ("interval".to_string(), interval.to_string()),
+                ("shuffle".to_string(), shuffle.to_string()),
+                ("refresh.mode".to_string(), refresh.refresh.to_string()),
+                ("refresh.waveform".to_string(), refresh.waveform.to_string()),
+                ("refresh.full_every".to_string(), full_every.to_string()),
+            ];
+            for (i, (image, duration)) in slides.into_iter().enumerate() {
+                form.push((format!("slides[{i}].enabled"), "true".into()));
+                form.push((format!("slides[{i}].image"), image));
+                if let Some(duration) = duration {
+                    form.push((format!("slides[{i}].duration"), duration.to_string()));
+                }
+            }
+            let request = client
+                .post(format!("{server}/api/v1/slideshow/start"))
+                .form(&form);
+            (request, Some("Slideshow started"))
+        }
+        SlideshowAction::Stop => (
+            client.post(format!("{server}/api/v1/slideshow/stop")),
+            Some("Slideshow stopped"),
+        ),
+        SlideshowAction::Skip => (
+            client.post(format!("{server}/api/v1/slideshow/skip")),
+            Some("Skipped to the next image"),
+        ),
+        SlideshowAction::Status => (
+            client.get(format!("{server}/api/v1/slideshow")),
+            None,
+        ),
+    };
+
+    let request = match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let response = match request.send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Failed to control the slideshow through the server at {server}");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+    if let Some(done) = done {
+        println!("{done}");
+        return;
+    }
+
+    let status = match response.json::<SlideshowStatus>().await {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Failed to read the slideshow status");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+    match status.current {
+        Some(current) if status.running => {
+            println!("Showing \"{}\"", current.image);
+            println!("Order: {}, every {}s", status.order, status.interval);
+            for slide in status.slides {
+                match slide.duration {
+                    Some(duration) => println!("- {} ({duration}s)", slide.image),
+                    None => println!("- {}", slide.image),
+                }
+            }
+        }
+        _ => println!("Slideshow is stopped"),
+    }
+}
+
+async fn sync(
+    server: &str,
+    token: Option<&str>,
+    device: Option<&str>,
+    dry_run: bool,
+    prefer: Option<SyncSide>,
+) {
+    let client = reqwest::Client::new();
+    let server = server.trim_end_matches('/');
+
+    let request = if dry_run {
+        client.get(format!("{server}/api/v1/sync"))
+    } else {
+        let form: Vec<(&str, &str)> = match prefer {
+            Some(SyncSide::Server) => vec![("prefer", "server")],
+            Some(SyncSide::Kindle) => vec![("prefer", "kindle")],
+            None => vec![],
+        };
+        client.post(format!("{server}/api/v1/sync")).form(&form)
+    };
+
+    let request = match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let request = match device {
+        Some(device) => request.header("X-Kindle-Device", device),
+        None => request,
+    };
+    let response = match request.send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Failed to sync through the server at {server}");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+
+    if dry_run {
+        let plan = match response.json::<Vec<SyncEntry>>().await {
+            Ok(plan) => plan,
+            Err(err) => {
+                eprintln!("Failed to read the sync plan");
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        };
+        if plan.is_empty() {
+            println!("Already in sync");
+        }
+        for entry in plan {
+            println!("{:<20} {}", entry.action, entry.name);
+        }
+        return;
+    }
+
+    let results = match response.json::<Vec<SyncResult>>().await {
+        Ok(results) => results,
+        Err(err) => {
+            eprintln!("Failed to read the sync results");
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+    if results.is_empty() {
+        println!("Already in sync");
+    }
+    let mut failed = false;
+    for result in results {
+        match result.error {
+            Some(error) => {
+                failed = true;
+                println!("{:<20} {} failed: {error}", result.action, result.name);
+            }
+            None => println!("{:<20} {}", result.action, result.name),
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,420 @@
+//! Options of FBInk, which prints text straight to the Kindle's framebuffer over whatever the
+//! screen shows, see [`crate::KindleManager::print_text`], and shows images with more control over
+//! the e-ink refresh than `eips`, see [`crate::KindleManager::set_image_with`].
+//!
+//! Text is laid out on a grid of cells the size of a glyph of the fixed-cell font, rows and
+//! columns count cells from the top left, or from the bottom right when negative.
+
+use std::{fmt, str::FromStr};
+
+/// Fixed-cell fonts built into FBInk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Font {
+    #[default]
+    Ibm,
+    Unscii,
+    UnsciiAlt,
+    UnsciiThin,
+    UnsciiFantasy,
+    UnsciiMcr,
+    UnsciiTall,
+    Block,
+    Leggie,
+    Veggie,
+    Kates,
+    Fkp,
+    Ctrld,
+    Orp,
+    OrpBold,
+    OrpItalic,
+    Scientifica,
+    ScientificaBold,
+    ScientificaItalic,
+    Terminus,
+    TerminusBold,
+    Fatty,
+    Spleen,
+    Tewi,
+    TewiBold,
+    Topaz,
+    Microknight,
+    Vga,
+    Cozette,
+}
+
+impl Font {
+    pub const ALL: [Font; 29] = [
+        Font::Ibm,
+        Font::Unscii,
+        Font::UnsciiAlt,
+        Font::UnsciiThin,
+        Font::UnsciiFantasy,
+        Font::UnsciiMcr,
+        Font::UnsciiTall,
+        Font::Block,
+        Font::Leggie,
+        Font::Veggie,
+        Font::Kates,
+        Font::Fkp,
+        Font::Ctrld,
+        Font::Orp,
+        Font::OrpBold,
+        Font::OrpItalic,
+        Font::Scientifica,
+        Font::ScientificaBold,
+        Font::ScientificaItalic,
+        Font::Terminus,
+        Font::TerminusBold,
+        Font::Fatty,
+        Font::Spleen,
+        Font::Tewi,
+        Font::TewiBold,
+        Font::Topaz,
+        Font::Microknight,
+        Font::Vga,
+        Font::Cozette,
+    ];
+
+    /// Name FBInk knows the font by, it ignores the case
+    pub fn id(&self) -> &'static str {
+        match self {
+            Font::Ibm => "ibm",
+            Font::Unscii => "unscii",
+            Font::UnsciiAlt => "unscii_alt",
+            Font::UnsciiThin => "unscii_thin",
+            Font::UnsciiFantasy => "unscii_fantasy",
+            Font::UnsciiMcr => "unscii_mcr",
+            Font::UnsciiTall => "unscii_tall",
+            Font::Block => "block",
+            Font::Leggie => "leggie",
+            Font::Veggie => "veggie",
+            Font::Kates => "kates",
+            Font::Fkp => "fkp",
+            Font::Ctrld => "ctrld",
+            Font::Orp => "orp",
+            Font::OrpBold => "orpb",
+            Font::OrpItalic => "orpi",
+            Font::Scientifica => "scientifica",
+            Font::ScientificaBold => "scientificab",
+            Font::ScientificaItalic => "scientificai",
+            Font::Terminus => "terminus",
+            Font::TerminusBold => "terminusb",
+            Font::Fatty => "fatty",
+            Font::Spleen => "spleen",
+            Font::Tewi => "tewi",
+            Font::TewiBold => "tewib",
+            Font::Topaz => "topaz",
+            Font::Microknight => "microknight",
+            Font::Vga => "vga",
+            Font::Cozette => "cozette",
+        }
+    }
+}
+
+impl fmt::Display for Font {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for Font {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Font::ALL
+            .into_iter()
+            .find(|font| font.id().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown FBInk font \"{s}\""))
+    }
+}
+
+/// How the screen is updated once the text is in the framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextRefresh {
+    /// Only the text's area, fast but may leave ghosts behind
+    #[default]
+    Partial,
+    /// Flashes the text's area to black first, cleaning up ghosts
+    Flash,
+    /// Leaves the screen as is, the text shows up with the next refresh
+    None,
+}
+
+impl TextRefresh {
+    pub const ALL: [TextRefresh; 3] = [TextRefresh::Partial, TextRefresh::Flash, TextRefresh::None];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            TextRefresh::Partial => "partial",
+            TextRefresh::Flash => "flash",
+            TextRefresh::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for TextRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for TextRefresh {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TextRefresh::ALL
+            .into_iter()
+            .find(|refresh| refresh.id() == s)
+            .ok_or_else(|| format!("unknown refresh \"{s}\""))
+    }
+}
+
+/// Settings for [`crate::KindleManager::print_text`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TextOptions {
+    pub font: Font,
+    /// Multiplier of the font's cell size, 0 lets FBInk pick one for the screen's DPI
+    pub size: u8,
+    /// Row of the first line, negative counts from the bottom
+    pub row: i16,
+    /// Column of the first character, negative counts from the right
+    pub column: i16,
+    /// Centers each line horizontally, `column` then moves it off center
+    pub centered: bool,
+    /// Centers the text vertically, `row` then moves it off center
+    pub halfway: bool,
+    /// White text on black
+    pub inverse: bool,
+    /// Fills the rest of each line with blanks, hiding what was under it
+    pub padded: bool,
+    /// Clears the whole screen first
+    pub clear: bool,
+    pub refresh: TextRefresh,
+}
+
+impl TextOptions {
+    /// Arguments of `fbink` printing `text`, each line of it on its own row. Every argument is
+    /// passed as is, the transport takes care of quoting them for the remote shell, and the text
+    /// comes after `--` so lines starting with a dash aren't read as options.
+    pub fn args(&self, text: &str) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-q".into(), "-F".into(), self.font.id().into()];
+        if self.size > 0 {
+            args.extend(["-S".into(), self.size.to_string()]);
+        }
+        args.extend([
+            "-y".into(),
+            self.row.to_string(),
+            "-x".into(),
+            self.column.to_string(),
+        ]);
+
+        let flags = [
+            (self.centered, "-m"),
+            (self.halfway, "-M"),
+            (self.inverse, "-h"),
+            // Left padding only fills both sides when centered, otherwise it'd push the text right
+            (self.padded && self.centered, "-p"),
+            (self.padded && !self.centered, "-r"),
+            (self.clear, "-c"),
+            (self.refresh == TextRefresh::Flash, "-f"),
+            (self.refresh == TextRefresh::None, "-b"),
+        ];
+        args.extend(
+            flags
+                .into_iter()
+                .filter(|(enabled, _)| *enabled)
+                .map(|(_, flag)| flag.to_string()),
+        );
+
+        args.push("--".into());
+        args.extend(text.lines().map(str::to_string));
+        args
+    }
+}
+
+/// Waveform mode the e-ink controller refreshes with, trading speed for gray levels and ghosting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    /// Picked by the controller for what changed
+    #[default]
+    Auto,
+    /// Black and white only, fast, good for text and menus
+    Du,
+    /// Every gray level, the usual full-quality mode
+    Gc16,
+    /// Four gray levels, faster than GC16
+    Gc4,
+    /// Black and white only, fastest, for animations
+    A2,
+    /// Every gray level, with less flashing on white backgrounds
+    Gl16,
+    /// Every gray level with almost no ghosting, on newer Kindles
+    Reagl,
+    /// REAGL with dithering, on newer Kindles
+    Reagld,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 8] = [
+        Waveform::Auto,
+        Waveform::Du,
+        Waveform::Gc16,
+        Waveform::Gc4,
+        Waveform::A2,
+        Waveform::Gl16,
+        Waveform::Reagl,
+        Waveform::Reagld,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Waveform::Auto => "auto",
+            Waveform::Du => "du",
+            Waveform::Gc16 => "gc16",
+            Waveform::Gc4 => "gc4",
+            Waveform::A2 => "a2",
+            Waveform::Gl16 => "gl16",
+            Waveform::Reagl => "reagl",
+            Waveform::Reagld => "reagld",
+        }
+    }
+
+    /// Name FBInk knows the waveform by
+    fn fbink_name(&self) -> String {
+        self.id().to_ascii_uppercase()
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Waveform::ALL
+            .into_iter()
+            .find(|waveform| waveform.id().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown waveform \"{s}\""))
+    }
+}
+
+/// How much of the screen is redrawn when an image is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RefreshMode {
+    /// Clears the screen and flashes it black, slow but leaves no ghosts behind
+    #[default]
+    Full,
+    /// Redraws the screen without flashing, fast but ghosts build up over time
+    Partial,
+    /// Only redraws this rectangle, in pixels, the rest keeps showing the previous image
+    Region {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+/// `full`, `partial` or `region:X,Y,WIDTH,HEIGHT`
+impl fmt::Display for RefreshMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshMode::Full => write!(f, "full"),
+            RefreshMode::Partial => write!(f, "partial"),
+            RefreshMode::Region {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "region:{x},{y},{width},{height}"),
+        }
+    }
+}
+
+impl FromStr for RefreshMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => return Ok(RefreshMode::Full),
+            "partial" => return Ok(RefreshMode::Partial),
+            _ => {}
+        }
+
+        let Some(region) = s.strip_prefix("region:") else {
+            return Err(format!(
+                "unknown refresh \"{s}\", expected full, partial or region:X,Y,WIDTH,HEIGHT"
+            ));
+        };
+        let values = region
+            .split(',')
+            .map(|value| value.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("\"{region}\" isn't X,Y,WIDTH,HEIGHT in pixels"))?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(RefreshMode::Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            [_, _, _, _] => Err("the refreshed region can't be empty".to_string()),
+            _ => Err(format!("\"{region}\" isn't X,Y,WIDTH,HEIGHT in pixels")),
+        }
+    }
+}
+
+/// Settings for [`crate::KindleManager::set_image_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RefreshOptions {
+    pub mode: RefreshMode,
+    pub waveform: Waveform,
+    /// Turns the refresh after this many partial or region ones into a full one to clean up
+    /// ghosts, 0 never does. Counted per Kindle, across every caller.
+    pub full_every: u32,
+}
+
+impl RefreshOptions {
+    /// Whether the defaults can keep using `eips`, which every Kindle has
+    pub(crate) fn is_eips(&self, mode: RefreshMode) -> bool {
+        mode == RefreshMode::Full && self.waveform == Waveform::Auto
+    }
+
+    /// `fbink` invocations showing the image at `path` with `mode`, run one after the other
+    pub(crate) fn image_commands(&self, mode: RefreshMode, path: &str) -> Vec<Vec<String>> {
+        let waveform = self.waveform.fbink_name();
+        let image = format!("file={path}");
+        match mode {
+            RefreshMode::Full => vec![vec![
+                "-q".into(),
+                "-c".into(),
+                "-f".into(),
+                "-W".into(),
+                waveform,
+                "-g".into(),
+                image,
+            ]],
+            RefreshMode::Partial => {
+                vec![vec!["-q".into(), "-W".into(), waveform, "-g".into(), image]]
+            }
+            // Draw the whole image without refreshing, then only refresh the region
+            RefreshMode::Region {
+                x,
+                y,
+                width,
+                height,
+            } => vec![
+                vec!["-q".into(), "-b".into(), "-g".into(), image],
+                vec![
+                    "-q".into(),
+                    "-s".into(),
+                    format!("top={y},left={x},width={width},height={height},wfm={waveform}"),
+                ],
+            ],
+        }
+    }
+}
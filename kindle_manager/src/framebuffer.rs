@@ -0,0 +1,118 @@
+//! Reading back what the Kindle's screen shows from its framebuffer, `/dev/fb0`.
+//!
+//! The framebuffer is usually wider and taller than the screen, rows are padded to `stride` bytes
+//! and there's room for a second page below, so only the visible rows are read and the padding is
+//! cut off when decoding.
+
+use image::GrayImage;
+
+use crate::{DeviceProfile, KindleManagerError};
+
+/// sysfs files describing the framebuffer, read in this order
+pub const SYSFS_FILES: [&str; 2] = [
+    "/sys/class/graphics/fb0/bits_per_pixel",
+    "/sys/class/graphics/fb0/stride",
+];
+
+/// Layout of the visible part of the framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row, padding included
+    pub stride: u32,
+    pub bits_per_pixel: u32,
+}
+
+impl Framebuffer {
+    /// Layout from the contents of [`SYSFS_FILES`], one per line. The screen size comes from the
+    /// profile, swapped when the rows are too short to be portrait since the screen is rotated.
+    pub fn from_sysfs(info: &str, profile: &DeviceProfile) -> Result<Self, KindleManagerError> {
+        let values = info
+            .split_whitespace()
+            .map(|value| value.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|err| {
+                KindleManagerError::CommandError(format!(
+                    "Failed conversion of framebuffer info {info:?}: {err}"
+                ))
+            })?;
+        let [bits_per_pixel, stride] = values[..] else {
+            return Err(KindleManagerError::CommandError(format!(
+                "Unexpected framebuffer info {info:?}"
+            )));
+        };
+        if ![4, 8, 16, 32].contains(&bits_per_pixel) {
+            return Err(KindleManagerError::Unsupported(format!(
+                "screenshots of a {bits_per_pixel}-bit framebuffer"
+            )));
+        }
+
+        let row_pixels = stride * 8 / bits_per_pixel;
+        let (width, height) = if row_pixels >= profile.width {
+            (profile.width, profile.height)
+        } else {
+            (profile.height, profile.width)
+        };
+        if row_pixels < width {
+            return Err(KindleManagerError::CommandError(format!(
+                "Framebuffer rows of {row_pixels} pixels are shorter than the {}'s screen",
+                profile.name
+            )));
+        }
+
+        Ok(Framebuffer {
+            width,
+            height,
+            stride,
+            bits_per_pixel,
+        })
+    }
+
+    /// Bytes to read from the start of the framebuffer
+    pub fn size(&self) -> usize {
+        self.stride as usize * self.height as usize
+    }
+
+    /// Decodes the raw framebuffer into a grayscale image. 4-bit framebuffers of older Kindles
+    /// store black as 15, colors are assumed to be RGB565 or XRGB, little endian.
+    pub fn decode(&self, raw: &[u8]) -> Result<GrayImage, KindleManagerError> {
+        if raw.len() < self.size() {
+            return Err(KindleManagerError::CommandError(format!(
+                "Read {} bytes of the framebuffer instead of {}",
+                raw.len(),
+                self.size()
+            )));
+        }
+
+        let stride = self.stride as usize;
+        Ok(GrayImage::from_fn(self.width, self.height, |x, y| {
+            let (x, row) = (x as usize, &raw[y as usize * stride..]);
+            let gray = match self.bits_per_pixel {
+                4 => {
+                    let byte = row[x / 2];
+                    let level = if x % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+                    255 - level * 17
+                }
+                8 => row[x],
+                16 => {
+                    let pixel = u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]);
+                    let r = ((pixel >> 11) & 0x1f) as u32 * 255 / 31;
+                    let g = ((pixel >> 5) & 0x3f) as u32 * 255 / 63;
+                    let b = (pixel & 0x1f) as u32 * 255 / 31;
+                    luma(r, g, b)
+                }
+                _ => {
+                    let pixel = &row[x * 4..x * 4 + 3];
+                    luma(pixel[2] as u32, pixel[1] as u32, pixel[0] as u32)
+                }
+            };
+            image::Luma([gray])
+        }))
+    }
+}
+
+/// Rec. 601 luma
+fn luma(r: u32, g: u32, b: u32) -> u8 {
+    ((r * 299 + g * 587 + b * 114) / 1000) as u8
+}
@@ -0,0 +1,259 @@
+use std::{fmt, str::FromStr};
+
+use image::{GrayImage, Luma};
+
+use super::Palette;
+
+/// How gray values between two palette levels are approximated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Every pixel is rounded to the nearest level, best for text and line-art
+    None,
+    /// 8x8 Bayer matrix, regular cross-hatch pattern that doesn't shimmer between refreshes
+    Ordered,
+    #[default]
+    FloydSteinberg,
+    /// Only spreads 3/4 of the error, keeping more contrast
+    Atkinson,
+    Stucki,
+    Sierra,
+}
+
+impl Dither {
+    pub const ALL: [Dither; 6] = [
+        Dither::None,
+        Dither::Ordered,
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Stucki,
+        Dither::Sierra,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Ordered => "ordered",
+            Dither::FloydSteinberg => "floyd_steinberg",
+            Dither::Atkinson => "atkinson",
+            Dither::Stucki => "stucki",
+            Dither::Sierra => "sierra",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dither::None => "None",
+            Dither::Ordered => "Ordered (Bayer)",
+            Dither::FloydSteinberg => "Floyd-Steinberg",
+            Dither::Atkinson => "Atkinson",
+            Dither::Stucki => "Stucki",
+            Dither::Sierra => "Sierra",
+        }
+    }
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Dither::ALL
+            .into_iter()
+            .find(|dither| dither.id() == s)
+            .ok_or_else(|| format!("unknown dithering algorithm \"{s}\""))
+    }
+}
+
+/// Error diffusion kernels as (dx, dy, weight) and the sum the weights are divided by
+const FLOYD_STEINBERG: (&[(i64, i64, f32)], f32) =
+    (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0);
+
+// Divides by 8 on purpose, only 6/8 of the error is spread
+const ATKINSON: (&[(i64, i64, f32)], f32) = (
+    &[
+        (1, 0, 1.0),
+        (2, 0, 1.0),
+        (-1, 1, 1.0),
+        (0, 1, 1.0),
+        (1, 1, 1.0),
+        (0, 2, 1.0),
+    ],
+    8.0,
+);
+
+const STUCKI: (&[(i64, i64, f32)], f32) = (
+    &[
+        (1, 0, 8.0),
+        (2, 0, 4.0),
+        (-2, 1, 2.0),
+        (-1, 1, 4.0),
+        (0, 1, 8.0),
+        (1, 1, 4.0),
+        (2, 1, 2.0),
+        (-2, 2, 1.0),
+        (-1, 2, 2.0),
+        (0, 2, 4.0),
+        (1, 2, 2.0),
+        (2, 2, 1.0),
+    ],
+    42.0,
+);
+
+const SIERRA: (&[(i64, i64, f32)], f32) = (
+    &[
+        (1, 0, 5.0),
+        (2, 0, 3.0),
+        (-2, 1, 2.0),
+        (-1, 1, 4.0),
+        (0, 1, 5.0),
+        (1, 1, 4.0),
+        (2, 1, 2.0),
+        (-1, 2, 2.0),
+        (0, 2, 3.0),
+        (1, 2, 2.0),
+    ],
+    32.0,
+);
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Reduces `values` (one per pixel, 0.0..=255.0) to the palette
+pub fn dither(
+    values: Vec<f32>,
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    algorithm: Dither,
+) -> GrayImage {
+    match algorithm {
+        Dither::None => GrayImage::from_fn(width, height, |x, y| {
+            Luma([palette.nearest(values[(y * width + x) as usize])])
+        }),
+        Dither::Ordered => ordered(&values, width, height, palette),
+        Dither::FloydSteinberg => error_diffusion(values, width, height, palette, FLOYD_STEINBERG),
+        Dither::Atkinson => error_diffusion(values, width, height, palette, ATKINSON),
+        Dither::Stucki => error_diffusion(values, width, height, palette, STUCKI),
+        Dither::Sierra => error_diffusion(values, width, height, palette, SIERRA),
+    }
+}
+
+/// Offsets every pixel by a threshold from the Bayer matrix, scaled to the palette's spacing
+fn ordered(values: &[f32], width: u32, height: u32, palette: &Palette) -> GrayImage {
+    let levels = palette.levels();
+    let spacing = match levels.len() {
+        0 | 1 => 255.0,
+        count => (levels[count - 1] as f32 - levels[0] as f32) / (count - 1) as f32,
+    };
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let threshold = BAYER_8X8[(y % 8) as usize][(x % 8) as usize] as f32 / 64.0 - 0.5;
+        let value = values[(y * width + x) as usize] + threshold * spacing;
+        Luma([palette.nearest(value)])
+    })
+}
+
+/// Reduces the image to the palette, spreading the error to the neighbouring pixels
+fn error_diffusion(
+    mut values: Vec<f32>,
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    (kernel, divisor): (&[(i64, i64, f32)], f32),
+) -> GrayImage {
+    let mut output = GrayImage::new(width, height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let index = (y * width + x) as usize;
+            let old = values[index].clamp(0.0, 255.0);
+            let new = palette.nearest(old);
+            output.put_pixel(x, y, Luma([new]));
+
+            let error = old - new as f32;
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                if nx >= 0 && nx < width as i64 && ny < height as i64 {
+                    values[(ny as u32 * width + nx as u32) as usize] += error * weight / divisor;
+                }
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_levels_are_kept_as_is() {
+        let palette = Palette::evenly_spaced(16);
+        // Ordered dithering shifts every pixel by its threshold, so it's left out
+        for algorithm in Dither::ALL
+            .into_iter()
+            .filter(|&algorithm| algorithm != Dither::Ordered)
+        {
+            let dithered = dither(vec![0x88 as f32; 64], 8, 8, &palette, algorithm);
+            assert!(
+                dithered.pixels().all(|pixel| pixel.0[0] == 0x88),
+                "{algorithm} changed a palette level"
+            );
+        }
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_gray() {
+        let palette = Palette::evenly_spaced(2);
+        let average = |algorithm| {
+            let dithered = dither(vec![64.0; 32 * 32], 32, 32, &palette, algorithm);
+            dithered
+                .pixels()
+                .map(|pixel| pixel.0[0] as f32)
+                .sum::<f32>()
+                / 1024.0
+        };
+        for algorithm in [
+            Dither::Ordered,
+            Dither::FloydSteinberg,
+            Dither::Stucki,
+            Dither::Sierra,
+        ] {
+            let average = average(algorithm);
+            assert!(
+                (average - 64.0).abs() < 4.0,
+                "{algorithm} averages {average}"
+            );
+        }
+        // Atkinson drops a quarter of the error, darkening midtones but still dithering them
+        let atkinson = average(Dither::Atkinson);
+        assert!(
+            atkinson > 32.0 && atkinson < 64.0,
+            "atkinson averages {atkinson}"
+        );
+
+        let rounded = dither(vec![64.0; 16], 4, 4, &palette, Dither::None);
+        assert!(rounded.pixels().all(|pixel| pixel.0[0] == 0));
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for algorithm in Dither::ALL {
+            assert_eq!(algorithm.id().parse::<Dither>(), Ok(algorithm));
+        }
+    }
+}
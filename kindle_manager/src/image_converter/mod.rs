@@ -0,0 +1,694 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use image::{
+    codecs::png::PngEncoder,
+    imageops::{self, FilterType},
+    DynamicImage, ExtendedColorType, GrayImage, ImageEncoder, ImageReader, Rgba, RgbaImage,
+};
+
+use crate::{DeviceProfile, KindleManagerError};
+
+mod dither;
+pub use dither::Dither;
+
+/// Color used to fill the space around images that don't cover the whole screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    White,
+    LightGray,
+    DarkGray,
+    Black,
+    /// Picked from the edges of the image, see [`detect_background`]
+    Auto,
+}
+
+impl Background {
+    /// Gray value of the color, `None` for [`Background::Auto`] since it depends on the image
+    pub fn gray(&self) -> Option<u8> {
+        match self {
+            Background::White => Some(255),
+            // Same as ImageMagick's gray60 and gray20
+            Background::LightGray => Some(153),
+            Background::DarkGray => Some(51),
+            Background::Black => Some(0),
+            Background::Auto => None,
+        }
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Background::White => "white",
+            Background::LightGray => "light_gray",
+            Background::DarkGray => "dark_gray",
+            Background::Black => "black",
+            Background::Auto => "auto",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "white" => Ok(Background::White),
+            "light_gray" => Ok(Background::LightGray),
+            "dark_gray" => Ok(Background::DarkGray),
+            "black" => Ok(Background::Black),
+            "auto" => Ok(Background::Auto),
+            _ => Err(format!("unknown background color \"{s}\"")),
+        }
+    }
+}
+
+/// How the image is sized to the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// Fits the whole image on the screen, filling the rest with the background color
+    #[default]
+    Contain,
+    /// Covers the whole screen, ignoring the image's aspect ratio
+    Stretch,
+    /// Covers the whole screen, cropping what doesn't fit around the focal point
+    Fill,
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fit::Contain => "fit",
+            Fit::Stretch => "stretch",
+            Fit::Fill => "fill",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fit" => Ok(Fit::Contain),
+            "stretch" => Ok(Fit::Stretch),
+            "fill" => Ok(Fit::Fill),
+            _ => Err(format!("unknown fit mode \"{s}\"")),
+        }
+    }
+}
+
+/// Settings for [`convert_image`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertOptions {
+    pub background: Background,
+    pub fit: Fit,
+    /// Point of the image kept on screen when cropping with [`Fit::Fill`], from (0.0, 0.0) at the
+    /// top left to (1.0, 1.0) at the bottom right. Found with [`find_focal_point`] when `None`
+    pub focal_point: Option<(f32, f32)>,
+    /// Gray levels to reduce the image to, defaults to the ones the Kindle can show
+    pub palette: Option<Palette>,
+    pub dither: Dither,
+    /// From -100 to 100
+    pub brightness: i32,
+    /// From -100 to 100
+    pub contrast: i32,
+    /// Above 1 brightens the midtones, below 1 darkens them
+    pub gamma: f32,
+    /// Radius of the unsharp mask, 0 to disable it
+    pub sharpen: f32,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            background: Background::White,
+            fit: Fit::Contain,
+            focal_point: None,
+            palette: None,
+            dither: Dither::FloydSteinberg,
+            brightness: 0,
+            contrast: 0,
+            gamma: 1.0,
+            sharpen: 0.0,
+        }
+    }
+}
+
+/// Gray levels the converted image is reduced to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    levels: Vec<u8>,
+}
+
+impl Palette {
+    /// `count` gray levels spread evenly from black to white, 16 gives 0x00, 0x11, .., 0xFF
+    pub fn evenly_spaced(count: u16) -> Self {
+        let count = count.clamp(2, 256) as u32;
+        let levels = (0..count)
+            .map(|i| ((i * 255 + (count - 1) / 2) / (count - 1)) as u8)
+            .collect();
+        Palette { levels }
+    }
+
+    /// Every gray level the screen of this Kindle can show
+    pub fn for_profile(profile: &DeviceProfile) -> Self {
+        Palette::evenly_spaced(profile.gray_levels)
+    }
+
+    /// Uses every gray level found in an image as the palette, for custom palettes
+    pub fn from_image(path: &Path) -> Result<Self, KindleManagerError> {
+        let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+        let mut levels: Vec<u8> = image.to_luma8().pixels().map(|pixel| pixel.0[0]).collect();
+        levels.sort_unstable();
+        levels.dedup();
+
+        if levels.is_empty() {
+            return Err(KindleManagerError::OutOfRange(format!(
+                "palette {} has no colors",
+                path.to_string_lossy()
+            )));
+        }
+
+        Ok(Palette { levels })
+    }
+
+    pub fn levels(&self) -> &[u8] {
+        &self.levels
+    }
+
+    /// Closest gray level of the palette to `value`
+    pub fn nearest(&self, value: f32) -> u8 {
+        let mut nearest = self.levels[0];
+        for &level in &self.levels {
+            if (level as f32 - value).abs() < (nearest as f32 - value).abs() {
+                nearest = level;
+            }
+        }
+        nearest
+    }
+}
+
+/// Converts the image at `origin` into a grayscale PNG the Kindle can show, saved at `destination`
+pub fn convert_image(
+    options: &ConvertOptions,
+    profile: &DeviceProfile,
+    origin: &Path,
+    destination: &Path,
+) -> Result<(), KindleManagerError> {
+    let image = ImageReader::open(origin)?.with_guessed_format()?.decode()?;
+
+    let converted = convert(&image, options, profile);
+    std::fs::write(destination, encode_png(&converted)?)?;
+
+    Ok(())
+}
+
+/// Converts an uploaded image without storing anything, returning the PNG as it will look on
+/// the Kindle. `rotate` works like in [`store_original`], the result is rotated back so it can be
+/// shown the way the Kindle is held.
+pub fn preview(
+    data: &[u8],
+    rotate: bool,
+    options: &ConvertOptions,
+    profile: &DeviceProfile,
+) -> Result<Vec<u8>, KindleManagerError> {
+    let mut image = image::load_from_memory(data)?;
+    if rotate {
+        image = image.rotate90();
+    }
+
+    let mut converted = convert(&image, options, profile);
+    if rotate {
+        converted = imageops::rotate270(&converted);
+    }
+
+    encode_png(&converted)
+}
+
+/// Shrinks a PNG, like a screenshot, to `height` pixels keeping its aspect ratio
+pub fn thumbnail(png: &[u8], height: u32) -> Result<Vec<u8>, KindleManagerError> {
+    let image = image::load_from_memory(png)?;
+    let width = (image.width() * height).div_ceil(image.height().max(1));
+    let thumbnail = image.resize_exact(width, height, FilterType::Triangle);
+    encode_png(&thumbnail.to_luma8())
+}
+
+/// Runs the whole conversion pipeline in memory
+pub fn convert(
+    image: &DynamicImage,
+    options: &ConvertOptions,
+    profile: &DeviceProfile,
+) -> GrayImage {
+    let palette = match &options.palette {
+        Some(palette) => palette.clone(),
+        None => Palette::for_profile(profile),
+    };
+
+    let mut resized = resize_to_screen(image, options, profile);
+    adjust_tone(&mut resized, options);
+    let background = match options.background.gray() {
+        Some(gray) => gray,
+        None => detect_background(&resized, profile, &palette),
+    };
+    let canvas = place_on_canvas(&resized, background, profile);
+
+    let gray = DynamicImage::ImageRgba8(canvas).to_luma8();
+    let values = gray.pixels().map(|pixel| pixel.0[0] as f32).collect();
+    dither::dither(
+        values,
+        gray.width(),
+        gray.height(),
+        &palette,
+        options.dither,
+    )
+}
+
+/// Resizes the image to fit the screen, to cover it exactly when stretching, or to cover it and
+/// crop the rest when filling
+fn resize_to_screen(
+    image: &DynamicImage,
+    options: &ConvertOptions,
+    profile: &DeviceProfile,
+) -> RgbaImage {
+    let (screen_width, screen_height) = (profile.width, profile.height);
+    let scale_x = screen_width as f64 / image.width() as f64;
+    let scale_y = screen_height as f64 / image.height() as f64;
+
+    match options.fit {
+        Fit::Stretch => imageops::resize(
+            &image.to_rgba8(),
+            screen_width,
+            screen_height,
+            FilterType::Lanczos3,
+        ),
+        Fit::Contain => {
+            let scale = f64::min(scale_x, scale_y);
+            let width = ((image.width() as f64 * scale).round() as u32).clamp(1, screen_width);
+            let height = ((image.height() as f64 * scale).round() as u32).clamp(1, screen_height);
+            imageops::resize(&image.to_rgba8(), width, height, FilterType::Lanczos3)
+        }
+        Fit::Fill => {
+            let (focus_x, focus_y) = options
+                .focal_point
+                .unwrap_or_else(|| find_focal_point(image));
+
+            // Crop the original first so only the visible part is resized
+            let scale = f64::max(scale_x, scale_y);
+            let crop_width = ((screen_width as f64 / scale).round() as u32).clamp(1, image.width());
+            let crop_height =
+                ((screen_height as f64 / scale).round() as u32).clamp(1, image.height());
+            let x = crop_origin(focus_x, image.width(), crop_width);
+            let y = crop_origin(focus_y, image.height(), crop_height);
+
+            let cropped = image.crop_imm(x, y, crop_width, crop_height);
+            imageops::resize(
+                &cropped.to_rgba8(),
+                screen_width,
+                screen_height,
+                FilterType::Lanczos3,
+            )
+        }
+    }
+}
+
+/// Left or top edge of a crop of `crop` pixels centered on `focus`, kept inside the image
+fn crop_origin(focus: f32, size: u32, crop: u32) -> u32 {
+    let center = focus.clamp(0.0, 1.0) as f64 * size as f64;
+    let origin = (center - crop as f64 / 2.0).round().max(0.0) as u32;
+    origin.min(size - crop)
+}
+
+/// Guesses the most interesting point of the image, used as the focal point when cropping.
+///
+/// The image is split in a grid and the entropy of the gray levels in each cell is measured,
+/// detailed areas have a high entropy while flat backgrounds and skies are close to 0. The focal
+/// point is the center of the cells weighted by their entropy squared, so the busiest areas win.
+pub fn find_focal_point(image: &DynamicImage) -> (f32, f32) {
+    const GRID: u32 = 8;
+    const CELL: u32 = 16;
+
+    let small = imageops::resize(
+        &image.to_luma8(),
+        GRID * CELL,
+        GRID * CELL,
+        FilterType::Triangle,
+    );
+
+    let (mut sum_x, mut sum_y, mut total) = (0.0, 0.0, 0.0);
+    for cell_y in 0..GRID {
+        for cell_x in 0..GRID {
+            // Histogram of 32 buckets, finer buckets are mostly noise at this size
+            let mut histogram = [0u32; 32];
+            for y in 0..CELL {
+                for x in 0..CELL {
+                    let pixel = small.get_pixel(cell_x * CELL + x, cell_y * CELL + y);
+                    histogram[(pixel.0[0] / 8) as usize] += 1;
+                }
+            }
+
+            let count = (CELL * CELL) as f32;
+            let entropy: f32 = histogram
+                .iter()
+                .filter(|&&bucket| bucket > 0)
+                .map(|&bucket| {
+                    let p = bucket as f32 / count;
+                    -p * p.log2()
+                })
+                .sum();
+
+            let weight = entropy * entropy;
+            sum_x += weight * (cell_x as f32 + 0.5) / GRID as f32;
+            sum_y += weight * (cell_y as f32 + 0.5) / GRID as f32;
+            total += weight;
+        }
+    }
+
+    if total == 0.0 {
+        (0.5, 0.5)
+    } else {
+        (sum_x / total, sum_y / total)
+    }
+}
+
+/// Picks a background color that blends in with the edges of the image, from the palette.
+///
+/// Only the edges touching the empty space around the image are sampled, so letterboxed images
+/// blend in with the bars. The most common palette gray wins if it covers at least 40% of the
+/// edge, otherwise the average gray of the edge is used.
+pub fn detect_background(image: &RgbaImage, profile: &DeviceProfile, palette: &Palette) -> u8 {
+    let (width, height) = image.dimensions();
+    let padded_vertically = height < profile.height;
+    let padded_horizontally = width < profile.width;
+    let no_padding = !padded_vertically && !padded_horizontally;
+
+    let mut samples = Vec::new();
+    if padded_vertically || no_padding {
+        for x in 0..width {
+            samples.push(image.get_pixel(x, 0));
+            samples.push(image.get_pixel(x, height - 1));
+        }
+    }
+    if padded_horizontally || no_padding {
+        for y in 0..height {
+            samples.push(image.get_pixel(0, y));
+            samples.push(image.get_pixel(width - 1, y));
+        }
+    }
+    if samples.is_empty() {
+        return 255;
+    }
+
+    let mut counts = [0usize; 256];
+    let mut sum = 0.0;
+    for pixel in &samples {
+        let [r, g, b, a] = pixel.0;
+        let luma = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+        // Transparent pixels end up on white, like when they are shown in a browser
+        let alpha = a as f32 / 255.0;
+        let luma = luma * alpha + 255.0 * (1.0 - alpha);
+        counts[palette.nearest(luma) as usize] += 1;
+        sum += luma;
+    }
+
+    let (dominant, count) = counts
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, count)| **count)
+        .unwrap_or((255, &0));
+    if count * 5 >= samples.len() * 2 {
+        dominant as u8
+    } else {
+        palette.nearest(sum / samples.len() as f32)
+    }
+}
+
+/// Centers the image on a screen-sized canvas filled with the background color
+fn place_on_canvas(image: &RgbaImage, gray: u8, profile: &DeviceProfile) -> RgbaImage {
+    let (screen_width, screen_height) = (profile.width, profile.height);
+
+    let mut canvas =
+        RgbaImage::from_pixel(screen_width, screen_height, Rgba([gray, gray, gray, 255]));
+    imageops::overlay(
+        &mut canvas,
+        image,
+        ((screen_width - image.width()) / 2) as i64,
+        ((screen_height - image.height()) / 2) as i64,
+    );
+
+    canvas
+}
+
+/// Applies sharpening, brightness, contrast and gamma to the image, leaving the background as is
+fn adjust_tone(image: &mut RgbaImage, options: &ConvertOptions) {
+    // Subnormal amounts would trip unsharpen's assertion and change nothing anyway
+    if options.sharpen.is_normal() && options.sharpen > 0.0 {
+        *image = imageops::unsharpen(image, options.sharpen, 0);
+    }
+
+    // Brightness and contrast go from -100 to 100, like most image editors
+    let brightness = options.brightness.clamp(-100, 100) as f32 * 2.55;
+    let contrast = options.contrast.clamp(-100, 100) as f32 * 2.55;
+    let contrast_factor = (259.0 * (contrast + 255.0)) / (255.0 * (259.0 - contrast));
+    let gamma = options.gamma.max(0.01);
+    if brightness == 0.0 && contrast == 0.0 && gamma == 1.0 {
+        return;
+    }
+
+    for pixel in image.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            let mut value = *channel as f32 + brightness;
+            value = contrast_factor * (value - 128.0) + 128.0;
+            value = value.clamp(0.0, 255.0);
+            *channel = (255.0 * (value / 255.0).powf(1.0 / gamma)).round() as u8;
+        }
+    }
+}
+
+/// Encodes the image as an 8-bit grayscale PNG
+pub fn encode_png(image: &GrayImage) -> Result<Vec<u8>, KindleManagerError> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).write_image(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ExtendedColorType::L8,
+    )?;
+    Ok(png)
+}
+
+/// Width and height of the image at `path`, only its header is read
+pub fn dimensions(path: &Path) -> Result<(u32, u32), KindleManagerError> {
+    Ok(ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_dimensions()?)
+}
+
+/// Whether the converted image at `path` can be shown on a screen as is, being its size and only
+/// using gray levels of `palette`
+pub fn fits_screen(
+    path: &Path,
+    profile: &DeviceProfile,
+    palette: &Palette,
+) -> Result<bool, KindleManagerError> {
+    let image = ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?
+        .to_luma8();
+    Ok(image.dimensions() == (profile.width, profile.height)
+        && image
+            .pixels()
+            .all(|pixel| palette.levels().contains(&pixel.0[0])))
+}
+
+/// Stores an uploaded image as a PNG, rotating it if needed and shrinking it to fit in
+/// `max_width`x`max_height`, so the original is kept around without taking too much space
+pub fn store_original(
+    origin: &Path,
+    destination: &Path,
+    max_width: u32,
+    max_height: u32,
+    rotate: bool,
+) -> Result<(), KindleManagerError> {
+    let mut image = ImageReader::open(origin)?.with_guessed_format()?.decode()?;
+    if rotate {
+        image = image.rotate90();
+    }
+    if image.width() > max_width || image.height() > max_height {
+        image = image.resize(max_width, max_height, FilterType::Lanczos3);
+    }
+    image.save_with_format(destination, image::ImageFormat::Png)?;
+
+    Ok(())
+}
+
+/// Turns an original kept by [`store_original`] between vertical and horizontal, `horizontal`
+/// being the orientation it should end up in
+pub fn reorient_original(path: &Path, horizontal: bool) -> Result<(), KindleManagerError> {
+    let image = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    let image = if horizontal {
+        image.rotate90()
+    } else {
+        image.rotate270()
+    };
+    image.save_with_format(path, image::ImageFormat::Png)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Golden images live next to the input, `UPDATE_GOLDEN=1 cargo test` rewrites them after an
+    /// intended change to the pipeline
+    fn golden(name: &str, options: &ConvertOptions) {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/image_converter/testdata");
+        let original = image::open(testdata.join("original.png")).unwrap();
+
+        let converted = encode_png(&convert(&original, options, &small_screen())).unwrap();
+        let expected_path = testdata.join(format!("{name}.png"));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&expected_path, &converted).unwrap();
+        }
+        let expected = std::fs::read(&expected_path).unwrap();
+        assert!(
+            converted == expected,
+            "{name}.png differs from the conversion"
+        );
+    }
+
+    /// Portrait like a Kindle, small enough for the golden images to be checked in
+    fn small_screen() -> DeviceProfile {
+        DeviceProfile {
+            name: "Test screen".into(),
+            width: 24,
+            height: 40,
+            ..DeviceProfile::default()
+        }
+    }
+
+    #[test]
+    fn golden_dither_modes() {
+        for dither in Dither::ALL {
+            let options = ConvertOptions {
+                dither,
+                ..ConvertOptions::default()
+            };
+            golden(&format!("dither_{}", dither.id()), &options);
+        }
+    }
+
+    #[test]
+    fn golden_fit_modes() {
+        for fit in [Fit::Contain, Fit::Stretch, Fit::Fill] {
+            let options = ConvertOptions {
+                fit,
+                background: Background::Auto,
+                ..ConvertOptions::default()
+            };
+            golden(&format!("fit_{fit}"), &options);
+        }
+    }
+
+    #[test]
+    fn evenly_spaced_palettes() {
+        let levels: Vec<u8> = (0..16).map(|i| i * 0x11).collect();
+        assert_eq!(Palette::evenly_spaced(16).levels(), levels);
+        assert_eq!(Palette::evenly_spaced(4).levels(), [0, 85, 170, 255]);
+        // Fewer than 2 levels can't show anything, more than 256 don't exist
+        assert_eq!(Palette::evenly_spaced(1).levels(), [0, 255]);
+        assert_eq!(Palette::evenly_spaced(1000).levels().len(), 256);
+    }
+
+    #[test]
+    fn nearest_palette_level() {
+        let palette = Palette::evenly_spaced(16);
+        assert_eq!(palette.nearest(9.0), 0x11);
+        assert_eq!(palette.nearest(8.0), 0x00);
+        // Ties go to the darker level
+        assert_eq!(palette.nearest(8.5), 0x00);
+        assert_eq!(palette.nearest(-20.0), 0x00);
+        assert_eq!(palette.nearest(300.0), 0xFF);
+    }
+
+    #[test]
+    fn crop_origin_stays_inside_the_image() {
+        assert_eq!(crop_origin(0.5, 100, 50), 25);
+        assert_eq!(crop_origin(0.0, 100, 50), 0);
+        assert_eq!(crop_origin(0.1, 100, 50), 0);
+        assert_eq!(crop_origin(1.0, 100, 50), 50);
+        assert_eq!(crop_origin(2.0, 100, 50), 50);
+        assert_eq!(crop_origin(0.9, 100, 100), 0);
+    }
+
+    #[test]
+    fn focal_point_follows_the_details() {
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, Rgba([90, 90, 90, 255])));
+        assert_eq!(find_focal_point(&flat), (0.5, 0.5));
+
+        // A checkerboard in the bottom right corner of a white image
+        let busy = RgbaImage::from_fn(64, 64, |x, y| {
+            match x >= 48 && y >= 48 && (x + y) % 2 == 0 {
+                true => Rgba([0, 0, 0, 255]),
+                false => Rgba([255, 255, 255, 255]),
+            }
+        });
+        let (x, y) = find_focal_point(&DynamicImage::ImageRgba8(busy));
+        assert!(x > 0.75 && y > 0.75, "focal point at {x}, {y}");
+    }
+
+    #[test]
+    fn background_matches_the_padded_edges() {
+        let profile = small_screen();
+        let palette = Palette::evenly_spaced(16);
+
+        // Padded above and below, only the top and bottom rows count
+        let letterboxed = RgbaImage::from_fn(24, 20, |x, y| match y {
+            0 | 19 => Rgba([0, 0, 0, 255]),
+            _ if x == 0 || x == 23 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([128, 128, 128, 255]),
+        });
+        assert_eq!(detect_background(&letterboxed, &profile, &palette), 0x00);
+
+        // Transparent edges end up on white
+        let transparent = RgbaImage::from_pixel(24, 20, Rgba([0, 0, 0, 0]));
+        assert_eq!(detect_background(&transparent, &profile, &palette), 0xFF);
+
+        // No gray covers 40% of the edge, their average is used
+        let striped = RgbaImage::from_fn(24, 20, |x, _| {
+            let gray = [0, 68, 170, 255][x as usize % 4];
+            Rgba([gray, gray, gray, 255])
+        });
+        assert_eq!(detect_background(&striped, &profile, &palette), 0x77);
+    }
+
+    #[test]
+    fn converted_images_only_fit_their_screen() {
+        let converted = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/image_converter/testdata/dither_floyd_steinberg.png");
+        let profile = small_screen();
+        let palette = Palette::for_profile(&profile);
+        assert!(fits_screen(&converted, &profile, &palette).unwrap());
+
+        let larger = DeviceProfile {
+            height: 48,
+            ..small_screen()
+        };
+        assert!(!fits_screen(&converted, &larger, &palette).unwrap());
+        assert!(!fits_screen(&converted, &profile, &Palette::evenly_spaced(4)).unwrap());
+    }
+
+    #[test]
+    fn subnormal_sharpening_is_skipped() {
+        let mut image = RgbaImage::from_pixel(4, 4, Rgba([128, 128, 128, 255]));
+        let options = ConvertOptions {
+            sharpen: 1e-40,
+            ..ConvertOptions::default()
+        };
+        adjust_tone(&mut image, &options);
+        assert!(image.pixels().all(|pixel| pixel.0 == [128, 128, 128, 255]));
+    }
+}
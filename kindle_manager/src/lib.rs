@@ -1,12 +1,34 @@
 use std::{
-    path::Path,
-    process::{Command, Output},
-    u8,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Output,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        RwLock,
+    },
+    time::Duration,
 };
 
-use openssh::{KnownHosts, Session};
 use thiserror::Error;
 
+use fbink::{RefreshMode, RefreshOptions, TextOptions};
+use framebuffer::Framebuffer;
+use prep::{PrepRecord, PrepReport, ServiceState, StepOutcome, GOVERNOR, POWERSAVE, PREP_RECORD};
+
+pub mod fbink;
+pub mod framebuffer;
+pub mod image_converter;
+pub mod pool;
+pub mod prep;
+pub mod profile;
+pub mod transport;
+pub use pool::SessionPool;
+pub use profile::{DeviceProfile, KindleModel};
+pub use transport::{
+    KindleTransport, LocalTransport, ProgressCallback, SshTransport, TransferError,
+    TransferProgress,
+};
+
 #[derive(Debug, Error)]
 pub enum KindleManagerError {
     #[error("SSH error occurred: {0}")]
@@ -18,6 +40,9 @@ pub enum KindleManagerError {
     #[error("IO error occurred: {0}")]
     StdioError(#[from] std::io::Error),
 
+    #[error("Image processing failed: {0}")]
+    ImageError(#[from] image::ImageError),
+
     #[error("Command failed: {0}")]
     CommandError(String),
 
@@ -29,12 +54,45 @@ pub enum KindleManagerError {
 
     #[error("Command failed, file doesn't exist: {0}")]
     FileMissing(String),
+
+    #[error("Not supported by this Kindle: {0}")]
+    Unsupported(String),
+
+    #[error("Couldn't identify the Kindle model from its serial number: {0}")]
+    UnknownModel(String),
+
+    #[error("Kindle is unreachable, next connection attempt in {}s", .0.as_secs())]
+    Unreachable(Duration),
+
+    #[error("Transfer of {path} failed: {source}")]
+    Transfer { path: String, source: TransferError },
+}
+
+/// How the [`KindleManager`] reaches the Kindle
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// A real Kindle, reachable through SSH at this address
+    Ssh { address: String },
+    /// A fake Kindle living in a local directory, see [`LocalTransport`]
+    Local { root: PathBuf },
+}
+
+impl Backend {
+    pub async fn connect(&self) -> Result<Box<dyn KindleTransport>, KindleManagerError> {
+        match self {
+            Backend::Ssh { address } => Ok(Box::new(SshTransport::connect(address).await?)),
+            Backend::Local { root } => Ok(Box::new(LocalTransport::new(root.clone()))),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct KindleManager {
-    address: String,
+    backend: Backend,
     location: String,
+    profile: RwLock<DeviceProfile>,
+    /// Partial refreshes since the last full one, see [`RefreshOptions::full_every`]
+    partial_refreshes: AtomicU32,
 }
 
 trait CheckStdout {
@@ -57,229 +115,630 @@ impl CheckStdout for Output {
 
 impl KindleManager {
     pub fn new(address: String, location: String) -> Self {
-        KindleManager { address, location }
+        KindleManager {
+            backend: Backend::Ssh { address },
+            location,
+            profile: RwLock::new(DeviceProfile::default()),
+            partial_refreshes: AtomicU32::new(0),
+        }
+    }
+
+    /// Manages a fake Kindle stored in `root` instead of a real device
+    pub fn new_local(root: PathBuf, location: String) -> Self {
+        KindleManager {
+            backend: Backend::Local { root },
+            location,
+            profile: RwLock::new(DeviceProfile::default()),
+            partial_refreshes: AtomicU32::new(0),
+        }
+    }
+
+    pub fn with_profile(self, profile: DeviceProfile) -> Self {
+        self.set_profile(profile);
+        self
+    }
+
+    /// Profile of the managed Kindle, a PW2 unless told otherwise
+    pub fn profile(&self) -> DeviceProfile {
+        self.profile.read().unwrap().clone()
+    }
+
+    pub fn set_profile(&self, profile: DeviceProfile) {
+        *self.profile.write().unwrap() = profile;
     }
 
-    pub async fn new_session(&self) -> Result<Session, KindleManagerError> {
-        Ok(Session::connect_mux(&self.address, KnownHosts::Strict).await?)
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    pub async fn new_session(&self) -> Result<Box<dyn KindleTransport>, KindleManagerError> {
+        self.backend.connect().await
+    }
+
+    fn remote_path(&self, kindle_filename: &str) -> String {
+        format!("{}/{}", self.location, kindle_filename)
     }
 
     pub async fn debug_print(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         text: &str,
     ) -> Result<(), KindleManagerError> {
-        let _ = session
-            .command("fbink")
-            .arg("-q")
-            .arg(text)
-            .args(["-x", "1"])
-            .args(["-y", "2"])
-            .output()
-            .await?
-            .check_stdout()?;
+        let options = TextOptions {
+            row: 2,
+            column: 1,
+            ..TextOptions::default()
+        };
+        self.print_text(session, text, &options).await
+    }
+
+    /// Prints `text` over what the screen shows with FBInk, see [`TextOptions`]
+    pub async fn print_text(
+        &self,
+        session: &dyn KindleTransport,
+        text: &str,
+        options: &TextOptions,
+    ) -> Result<(), KindleManagerError> {
+        let args = options.args(text);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let _ = session.run("fbink", &args).await?.check_stdout()?;
 
         Ok(())
     }
 
     // Credit to https://github.com/mattzzw/kindle-clock
-    /// Prepares the Kindle to act as a display, disabling services to save power,
-    /// entering power-saving mode and disabling the screen-saver.
-    pub async fn prep(&self, session: &Session) -> Result<(), KindleManagerError> {
-        // TODO: Check if we can stop framework and powerd
-        let services_to_stop = ["lab126_gui", "otaupd", "phd", "tmd", "x", "todo"];
-        for service in services_to_stop {
-            self.stop_service(&session, service).await?;
+    /// Prepares the Kindle to act as a display, stopping the services of the profile to save
+    /// power, entering power-saving mode and disabling the screen-saver.
+    pub async fn prep(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<PrepReport, KindleManagerError> {
+        let services = self.profile().prep_services;
+        self.prep_with(session, &services).await
+    }
+
+    /// [`KindleManager::prep`] stopping `services` instead of the profile's, e.g. `framework`
+    /// too. Only what isn't done yet is changed, so prepping again is harmless, and the report
+    /// tells which steps changed anything.
+    ///
+    /// The governor, screen-saver setting and stopped services are recorded on the Kindle for
+    /// [`KindleManager::restore`]. Prepping again adds to the first record, which still holds the
+    /// reader's settings.
+    pub async fn prep_with(
+        &self,
+        session: &dyn KindleTransport,
+        services: &[String],
+    ) -> Result<PrepReport, KindleManagerError> {
+        if let Some(service) = services
+            .iter()
+            .find(|service| prep::REQUIRED_SERVICES.contains(&service.as_str()))
+        {
+            return Err(KindleManagerError::Unsupported(format!(
+                "{service} has to keep running while prepped"
+            )));
         }
 
+        let mut record = match self.prep_record(session).await? {
+            Some(record) => record,
+            None => {
+                let record = PrepRecord {
+                    governor: self.governor(session).await?,
+                    prevent_screen_saver: self.prevents_screen_saver(session).await?,
+                    stopped: Vec::new(),
+                };
+                session
+                    .upload(PREP_RECORD, record.to_string().as_bytes(), None)
+                    .await?;
+                record
+            }
+        };
+
+        let mut report = PrepReport::default();
+        let stopped = self
+            .stop_services(session, services, &mut record, &mut report)
+            .await;
+        // Saved even when a service failed to stop, so the ones before it can be restarted
+        if report.changed() {
+            session
+                .upload(PREP_RECORD, record.to_string().as_bytes(), None)
+                .await?;
+        }
+        stopped?;
+
         // Set lowest CPU clock
-        let _ = session
-            .command("sh")
-            .arg("-c")
-            .arg("echo powersave > /sys/devices/system/cpu/cpu0/cpufreq/scaling_governor")
-            .output()
-            .await?
-            .check_stdout()?;
+        let outcome = match self.governor(session).await? == POWERSAVE {
+            true => StepOutcome::Unchanged,
+            false => {
+                self.set_governor(session, POWERSAVE).await?;
+                StepOutcome::Changed
+            }
+        };
+        report.push(format!("Set the CPU governor to {POWERSAVE}"), outcome);
 
         // Disable Screensaver
-        let _ = session
-            .command("sh")
-            .arg("-c")
-            .arg("lipc-set-prop com.lab126.powerd preventScreenSaver 1")
-            .output()
-            .await?
-            .check_stdout()?;
+        let outcome = match self.prevents_screen_saver(session).await? {
+            true => StepOutcome::Unchanged,
+            false => {
+                self.set_prevent_screen_saver(session, true).await?;
+                StepOutcome::Changed
+            }
+        };
+        report.push("Disable the screensaver", outcome);
+
+        Ok(report)
+    }
+
+    /// Undoes [`KindleManager::prep`], back to a Kindle that can be read: restarts the services it
+    /// stopped and puts back the governor and screen-saver setting it recorded. Without a record,
+    /// e.g. when the Kindle restarted since, the profile's services and the Kindle's defaults are
+    /// used.
+    pub async fn restore(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<PrepReport, KindleManagerError> {
+        let services = self.profile().prep_services;
+        self.restore_with(session, &services).await
+    }
+
+    /// [`KindleManager::restore`] for a Kindle prepped with [`KindleManager::prep_with`], starting
+    /// `services` instead of the profile's when there is no record
+    pub async fn restore_with(
+        &self,
+        session: &dyn KindleTransport,
+        services: &[String],
+    ) -> Result<PrepReport, KindleManagerError> {
+        let recorded = self.prep_record(session).await?;
+        let record = recorded
+            .clone()
+            .unwrap_or_else(|| PrepRecord::defaults(services));
+        let mut report = PrepReport::default();
+
+        let outcome = match self.governor(session).await? == record.governor {
+            true => StepOutcome::Unchanged,
+            false => {
+                self.set_governor(session, &record.governor).await?;
+                StepOutcome::Changed
+            }
+        };
+        report.push(
+            format!("Set the CPU governor to {}", record.governor),
+            outcome,
+        );
+
+        let outcome =
+            match self.prevents_screen_saver(session).await? == record.prevent_screen_saver {
+                true => StepOutcome::Unchanged,
+                false => {
+                    self.set_prevent_screen_saver(session, record.prevent_screen_saver)
+                        .await?;
+                    StepOutcome::Changed
+                }
+            };
+        let description = match record.prevent_screen_saver {
+            true => "Disable the screensaver",
+            false => "Enable the screensaver",
+        };
+        report.push(description, outcome);
+
+        // The reverse order of prep, so X is up before the GUI
+        for service in record.stopped.iter().rev() {
+            let outcome = match self.service_state(session, service).await? {
+                ServiceState::Stopped => {
+                    let _ = session.run("start", &[service]).await?.check_stdout()?;
+                    StepOutcome::Changed
+                }
+                ServiceState::Running => StepOutcome::Unchanged,
+                ServiceState::Missing => StepOutcome::Missing,
+            };
+            report.push(format!("Start {service}"), outcome);
+        }
 
+        if recorded.is_some() {
+            session.remove(PREP_RECORD).await?;
+        }
+        Ok(report)
+    }
+
+    /// Stops the running `services`, adding them to the record
+    async fn stop_services(
+        &self,
+        session: &dyn KindleTransport,
+        services: &[String],
+        record: &mut PrepRecord,
+        report: &mut PrepReport,
+    ) -> Result<(), KindleManagerError> {
+        for service in services {
+            let outcome = match self.service_state(session, service).await? {
+                ServiceState::Running => {
+                    self.stop_service(session, service).await?;
+                    if !record.stopped.contains(service) {
+                        record.stopped.push(service.clone());
+                    }
+                    StepOutcome::Changed
+                }
+                ServiceState::Stopped => StepOutcome::Unchanged,
+                ServiceState::Missing => StepOutcome::Missing,
+            };
+            report.push(format!("Stop {service}"), outcome);
+        }
         Ok(())
     }
 
-    async fn stop_service(
+    async fn service_state(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         service: &str,
+    ) -> Result<ServiceState, KindleManagerError> {
+        match session.run("status", &[service]).await?.check_stdout() {
+            Ok(status) => ServiceState::parse(&status).ok_or_else(|| {
+                KindleManagerError::CommandError(format!(
+                    "Unexpected status of {service}: {}",
+                    status.trim()
+                ))
+            }),
+            Err(KindleManagerError::CommandError(stderr)) if stderr.contains("Unknown job") => {
+                Ok(ServiceState::Missing)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// What `prep` recorded, if the Kindle is prepped
+    async fn prep_record(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<Option<PrepRecord>, KindleManagerError> {
+        let (directory, name) = PREP_RECORD
+            .rsplit_once('/')
+            .expect("PREP_RECORD should be an absolute path");
+        let recorded = session
+            .list_dir(directory)
+            .await?
+            .iter()
+            .any(|file| file == name);
+        if !recorded {
+            return Ok(None);
+        }
+        let saved = String::from_utf8(session.download(PREP_RECORD, None).await?)?;
+        Ok(Some(PrepRecord::parse(&saved)))
+    }
+
+    async fn governor(&self, session: &dyn KindleTransport) -> Result<String, KindleManagerError> {
+        let stdout = session.run("cat", &[GOVERNOR]).await?.check_stdout()?;
+        Ok(stdout.trim().to_string())
+    }
+
+    async fn set_governor(
+        &self,
+        session: &dyn KindleTransport,
+        governor: &str,
     ) -> Result<(), KindleManagerError> {
         let _ = session
-            .command("stop")
-            .arg(service)
-            .output()
+            .run("sh", &["-c", "echo \"$0\" > \"$1\"", governor, GOVERNOR])
             .await?
             .check_stdout()?;
-
         Ok(())
     }
 
-    pub async fn list_files(&self, session: &Session) -> Result<Vec<String>, KindleManagerError> {
+    async fn prevents_screen_saver(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<bool, KindleManagerError> {
         let stdout = session
-            .command("ls")
-            .arg(&self.location)
-            .output()
+            .run(
+                "lipc-get-prop",
+                &["com.lab126.powerd", "preventScreenSaver"],
+            )
             .await?
             .check_stdout()?;
+        Ok(stdout.trim() == "1")
+    }
 
-        let files = stdout
-            .split('\n')
-            .map(|s| s.to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
+    async fn set_prevent_screen_saver(
+        &self,
+        session: &dyn KindleTransport,
+        prevent: bool,
+    ) -> Result<(), KindleManagerError> {
+        let value = if prevent { "1" } else { "0" };
+        let _ = session
+            .run(
+                "lipc-set-prop",
+                &["com.lab126.powerd", "preventScreenSaver", value],
+            )
+            .await?
+            .check_stdout()?;
+        Ok(())
+    }
 
-        Ok(files)
+    async fn stop_service(
+        &self,
+        session: &dyn KindleTransport,
+        service: &str,
+    ) -> Result<(), KindleManagerError> {
+        let _ = session.run("stop", &[service]).await?.check_stdout()?;
+
+        Ok(())
+    }
+
+    pub async fn list_files(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<Vec<String>, KindleManagerError> {
+        session.list_dir(&self.location).await
+    }
+
+    /// MD5 of every file, the same as `md5sum` prints it, by file name
+    pub async fn checksums(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<HashMap<String, String>, KindleManagerError> {
+        session.checksums(&self.location).await
     }
 
     pub async fn file_exists(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         kindle_filename: &str,
     ) -> Result<bool, KindleManagerError> {
-        return Ok(self
+        Ok(self
             .list_files(session)
             .await?
             .iter()
-            .any(|filename| filename == kindle_filename));
+            .any(|filename| filename == kindle_filename))
     }
 
     pub async fn push_file(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
+        local_file_path: &Path,
+        kindle_filename: &str,
+    ) -> Result<(), KindleManagerError> {
+        self.push_file_with_progress(session, local_file_path, kindle_filename, None)
+            .await
+    }
+
+    /// Same as [`Self::push_file`], calling `progress` as the file is sent
+    pub async fn push_file_with_progress(
+        &self,
+        session: &dyn KindleTransport,
         local_file_path: &Path,
         kindle_filename: &str,
+        progress: Option<ProgressCallback<'_>>,
     ) -> Result<(), KindleManagerError> {
-        if self.file_exists(&session, kindle_filename).await? {
-            return Err(KindleManagerError::FileExists(format!("{kindle_filename}")));
+        if self.file_exists(session, kindle_filename).await? {
+            return Err(KindleManagerError::FileExists(kindle_filename.to_string()));
         }
 
-        let _ = Command::new("scp")
-            .arg(local_file_path)
-            .arg(format!(
-                "{}:{}/{}",
-                self.address, self.location, kindle_filename
-            ))
-            .output()?
-            .check_stdout()?;
+        let data = tokio::fs::read(local_file_path).await?;
+        session
+            .upload(&self.remote_path(kindle_filename), &data, progress)
+            .await
+    }
 
-        Ok(())
+    /// Same as [`Self::push_file`], overwriting the file if it's already on the Kindle
+    pub async fn replace_file(
+        &self,
+        session: &dyn KindleTransport,
+        local_file_path: &Path,
+        kindle_filename: &str,
+    ) -> Result<(), KindleManagerError> {
+        let data = tokio::fs::read(local_file_path).await?;
+        session
+            .upload(&self.remote_path(kindle_filename), &data, None)
+            .await
     }
 
     pub async fn pull_file(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         kindle_filename: &str,
         local_file_path: &Path,
     ) -> Result<(), KindleManagerError> {
-        if !self.file_exists(&session, kindle_filename).await? {
-            return Err(KindleManagerError::FileMissing(format!(
-                "{kindle_filename}"
-            )));
+        self.pull_file_with_progress(session, kindle_filename, local_file_path, None)
+            .await
+    }
+
+    /// Same as [`Self::pull_file`], calling `progress` as the file is received
+    pub async fn pull_file_with_progress(
+        &self,
+        session: &dyn KindleTransport,
+        kindle_filename: &str,
+        local_file_path: &Path,
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<(), KindleManagerError> {
+        if !self.file_exists(session, kindle_filename).await? {
+            return Err(KindleManagerError::FileMissing(kindle_filename.to_string()));
         }
 
-        let _ = Command::new("scp")
-            .arg(format!(
-                "{}:{}/{}",
-                self.address, self.location, kindle_filename
-            ))
-            .arg(local_file_path)
-            .output()?
-            .check_stdout()?;
+        let data = session
+            .download(&self.remote_path(kindle_filename), progress)
+            .await?;
+        tokio::fs::write(local_file_path, data).await?;
 
         Ok(())
     }
 
     pub async fn rename_file(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         old_filename: &str,
         new_filename: &str,
     ) -> Result<(), KindleManagerError> {
-        if !self.file_exists(&session, old_filename).await? {
-            return Err(KindleManagerError::FileMissing(format!("{old_filename}")));
+        if !self.file_exists(session, old_filename).await? {
+            return Err(KindleManagerError::FileMissing(old_filename.to_string()));
         }
-        if self.file_exists(&session, new_filename).await? {
-            return Err(KindleManagerError::FileExists(format!("{new_filename}")));
+        if self.file_exists(session, new_filename).await? {
+            return Err(KindleManagerError::FileExists(new_filename.to_string()));
         }
 
-        let _ = session
-            .command("mv")
-            .arg(format!("{}/{}", self.location, old_filename))
-            .arg(format!("{}/{}", self.location, new_filename))
-            .output()
-            .await?
-            .check_stdout()?;
-
-        Ok(())
+        session
+            .rename(
+                &self.remote_path(old_filename),
+                &self.remote_path(new_filename),
+            )
+            .await
     }
 
     pub async fn delete_file(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         kindle_filename: &str,
     ) -> Result<(), KindleManagerError> {
-        if !self.file_exists(&session, kindle_filename).await? {
-            return Err(KindleManagerError::FileMissing(format!(
-                "{kindle_filename}"
-            )));
+        if !self.file_exists(session, kindle_filename).await? {
+            return Err(KindleManagerError::FileMissing(kindle_filename.to_string()));
         }
 
-        let _ = session
-            .command("rm")
-            .arg(format!("{}/{}", self.location, kindle_filename))
-            .output()
-            .await?
-            .check_stdout()?;
-
-        Ok(())
+        session.remove(&self.remote_path(kindle_filename)).await
     }
 
+    /// Shows the image with a full refresh, clearing and flashing the screen
     pub async fn set_image(
         &self,
-        session: &Session,
+        session: &dyn KindleTransport,
         filename: &str,
     ) -> Result<(), KindleManagerError> {
-        if !self.file_exists(&session, filename).await? {
-            return Err(KindleManagerError::FileMissing(format!("{filename}")));
+        self.set_image_with(session, filename, &RefreshOptions::default())
+            .await
+    }
+
+    /// Shows the image refreshing the screen as asked. Full refreshes with the default waveform use
+    /// `eips`, anything else needs FBInk.
+    pub async fn set_image_with(
+        &self,
+        session: &dyn KindleTransport,
+        filename: &str,
+        options: &RefreshOptions,
+    ) -> Result<(), KindleManagerError> {
+        if !self.file_exists(session, filename).await? {
+            return Err(KindleManagerError::FileMissing(filename.to_string()));
+        }
+        let path = self.remote_path(filename);
+
+        let mode = match options.mode {
+            RefreshMode::Full => RefreshMode::Full,
+            _ if options.full_every > 0
+                && self.partial_refreshes.load(Ordering::Relaxed) >= options.full_every =>
+            {
+                RefreshMode::Full
+            }
+            mode => mode,
+        };
+        if let RefreshMode::Region {
+            x,
+            y,
+            width,
+            height,
+        } = mode
+        {
+            // The screen may be held either way, so the region has to fit one of them
+            let profile = self.profile();
+            // Regions so large they overflow are off every screen
+            let fits = |screen_width: u32, screen_height: u32| {
+                x.checked_add(width)
+                    .is_some_and(|right| right <= screen_width)
+                    && y.checked_add(height)
+                        .is_some_and(|bottom| bottom <= screen_height)
+            };
+            if !fits(profile.width, profile.height) && !fits(profile.height, profile.width) {
+                return Err(KindleManagerError::OutOfRange(format!(
+                    "region {width}x{height} at {x},{y} is off the {}'s screen",
+                    profile.name
+                )));
+            }
         }
 
-        let _ = session
-            .command("sh")
-            .arg("-c")
-            .arg(format!(
-                "eips -c; eips -f; eips -g \"{}/{}\"",
-                self.location, filename
-            ))
-            .output()
+        if options.is_eips(mode) {
+            let _ = session
+                .run(
+                    "sh",
+                    &["-c", &format!("eips -c; eips -f; eips -g \"{path}\"")],
+                )
+                .await?
+                .check_stdout()?;
+        } else {
+            // FBInk splits its image options on commas, there's no escaping them
+            if path.contains(',') {
+                return Err(KindleManagerError::Unsupported(format!(
+                    "refreshing {filename} with FBInk, its name has a comma"
+                )));
+            }
+            for args in options.image_commands(mode, &path) {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let _ = session.run("fbink", &args).await?.check_stdout()?;
+            }
+        }
+
+        match mode {
+            RefreshMode::Full => self.partial_refreshes.store(0, Ordering::Relaxed),
+            _ => {
+                self.partial_refreshes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the Kindle's serial number to figure out which model it is
+    pub async fn detect_model(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<KindleModel, KindleManagerError> {
+        let serial = session.run("cat", &["/proc/usid"]).await?.check_stdout()?;
+
+        KindleModel::from_serial(&serial)
+            .ok_or_else(|| KindleManagerError::UnknownModel(serial.trim().to_string()))
+    }
+
+    /// Detects the model of the Kindle and uses its profile from now on
+    pub async fn detect_profile(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<DeviceProfile, KindleManagerError> {
+        let profile = DeviceProfile::for_model(self.detect_model(session).await?);
+        self.set_profile(profile.clone());
+        Ok(profile)
+    }
+
+    /// What the screen shows right now, as a grayscale PNG
+    pub async fn screenshot(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<Vec<u8>, KindleManagerError> {
+        let info = session
+            .run("cat", &framebuffer::SYSFS_FILES)
             .await?
             .check_stdout()?;
+        let framebuffer = Framebuffer::from_sysfs(&info, &self.profile())?;
+
+        // Raw bytes, so the output can't go through `check_stdout`
+        let output = session
+            .run(
+                "dd",
+                &[
+                    "if=/dev/fb0",
+                    &format!("bs={}", framebuffer.stride),
+                    &format!("count={}", framebuffer.height),
+                ],
+            )
+            .await?;
+        if !output.status.success() {
+            return Err(KindleManagerError::CommandError(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
 
-        Ok(())
+        image_converter::encode_png(&framebuffer.decode(&output.stdout)?)
     }
 
-    pub async fn battery_charge(&self, session: &Session) -> Result<u8, KindleManagerError> {
+    pub async fn battery_charge(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<u8, KindleManagerError> {
         let stdout = session
-            .command("gasgauge-info")
-            .arg("-c")
-            .output()
+            .run("gasgauge-info", &["-c"])
             .await?
             .check_stdout()?;
 
-        let stdout: String = stdout.chars().filter(|c| c.is_digit(10)).collect();
+        let stdout: String = stdout.chars().filter(|c| c.is_ascii_digit()).collect();
         match stdout.parse::<u8>() {
             Ok(battery) => Ok(battery),
             Err(err) => Err(KindleManagerError::CommandError(format!(
@@ -288,11 +747,12 @@ impl KindleManager {
         }
     }
 
-    pub async fn battery_load(&self, session: &Session) -> Result<String, KindleManagerError> {
+    pub async fn battery_load(
+        &self,
+        session: &dyn KindleTransport,
+    ) -> Result<String, KindleManagerError> {
         let stdout = session
-            .command("gasgauge-info")
-            .arg("-l")
-            .output()
+            .run("gasgauge-info", &["-l"])
             .await?
             .check_stdout()?;
 
@@ -301,19 +761,28 @@ impl KindleManager {
 
     pub async fn set_backlight(
         &self,
-        session: &Session,
-        intensity: u8,
+        session: &dyn KindleTransport,
+        intensity: u16,
     ) -> Result<(), KindleManagerError> {
-        // Intensity seems to be between 0..=255
-        // Higher values don't do anything more
+        let profile = self.profile();
+        let Some(backlight_path) = profile.backlight_path else {
+            return Err(KindleManagerError::Unsupported(format!(
+                "the {} has no frontlight",
+                profile.name
+            )));
+        };
+        if intensity > profile.max_intensity {
+            return Err(KindleManagerError::OutOfRange(format!(
+                "intensity {intensity} is above the maximum of {} for the {}",
+                profile.max_intensity, profile.name
+            )));
+        }
+
         let _ = session
-            .command("sh")
-            .arg("-c")
-            .arg(format!(
-                "echo -n {} > /sys/devices/system/fl_tps6116x/fl_tps6116x0/fl_intensity",
-                intensity
-            ))
-            .output()
+            .run(
+                "sh",
+                &["-c", &format!("echo -n {} > {}", intensity, backlight_path)],
+            )
             .await?
             .check_stdout()?;
 
@@ -321,54 +790,147 @@ impl KindleManager {
     }
 }
 
-pub mod image_converter {
-    use std::{path::PathBuf, process::Command};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager of an empty fake Kindle in a fresh directory, with a session to it
+    async fn local_kindle(name: &str) -> (KindleManager, LocalTransport, PathBuf) {
+        let root =
+            std::env::temp_dir().join(format!("kindle_manager_{name}_{}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let manager = KindleManager::new_local(root.clone(), "/mnt/us/images".into());
+        (manager, LocalTransport::new(root.clone()), root)
+    }
 
-    use crate::{CheckStdout, KindleManagerError};
+    #[tokio::test]
+    async fn prep_then_restore_puts_the_kindle_back() {
+        let (manager, session, root) = local_kindle("round_trip").await;
+
+        let report = manager.prep(&session).await.unwrap();
+        assert!(report.changed());
+        assert_eq!(manager.governor(&session).await.unwrap(), POWERSAVE);
+        assert!(manager.prevents_screen_saver(&session).await.unwrap());
+        for service in manager.profile().prep_services {
+            let state = manager.service_state(&session, &service).await.unwrap();
+            assert_eq!(state, ServiceState::Stopped, "{service}");
+        }
 
-    // TODO: Check if the raster library can replace this
-    pub fn convert_image(
-        background: &str,
-        stretch: bool,
-        origin: &PathBuf,
-        destination: &PathBuf,
-    ) -> Result<(), KindleManagerError> {
-        let mut resize = "";
-        if stretch {
-            resize = "!";
+        manager.prep(&session).await.unwrap();
+
+        let report = manager.restore(&session).await.unwrap();
+        assert!(report.changed());
+        assert_eq!(manager.governor(&session).await.unwrap(), "ondemand");
+        assert!(!manager.prevents_screen_saver(&session).await.unwrap());
+        for service in manager.profile().prep_services {
+            let state = manager.service_state(&session, &service).await.unwrap();
+            assert_eq!(state, ServiceState::Running, "{service}");
         }
-        let stdout = Command::new("magick")
-            .arg(origin)
-            .args([
-                "-filter",
-                "LanczosSharp",
-                "-resize",
-                &format!("758x1024{}", resize),
-                "-background",
-                background,
-                "-gravity",
-                "center",
-                "-extent",
-                "758x1024",
-                "-colorspace",
-                "Gray",
-                "-dither",
-                "FloydSteinberg",
-                "-remap",
-                "kindle_colors.gif", // TODO: Include this in the final binary somehow? https://stackoverflow.com/questions/76252932/creating-palette-image-on-the-fly-for-remap-behind-palette-is-not-reset-each-t
-                "-quality",
-                "75",
-                "-define",
-                "png:color-type=0",
-                "-define",
-                "png:bit-depth=8",
-            ])
-            .arg(destination)
-            .output()?
-            .check_stdout()?;
+        assert!(manager.prep_record(&session).await.unwrap().is_none());
 
-        println!("{stdout}");
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn prepping_twice_changes_nothing_the_second_time() {
+        let (manager, session, root) = local_kindle("prep_twice").await;
+
+        manager.prep(&session).await.unwrap();
+        let report = manager.prep(&session).await.unwrap();
+        assert!(!report.changed());
+        for step in &report.steps {
+            assert_eq!(step.outcome, StepOutcome::Unchanged, "{}", step.description);
+        }
+
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
+
+    #[tokio::test]
+    async fn prep_record_adds_the_services_of_later_preps() {
+        let (manager, session, root) = local_kindle("prep_record").await;
+        let services = manager.profile().prep_services;
+
+        manager.prep(&session).await.unwrap();
+        let report = manager
+            .prep_with(&session, &["framework".to_string()])
+            .await
+            .unwrap();
+        assert!(report.changed());
+
+        // Still the reader's settings from the first prep
+        let record = manager.prep_record(&session).await.unwrap().unwrap();
+        assert_eq!(record.governor, "ondemand");
+        assert!(!record.prevent_screen_saver);
+        let mut stopped = services.clone();
+        stopped.push("framework".into());
+        assert_eq!(record.stopped, stopped);
+
+        manager.restore(&session).await.unwrap();
+        for service in stopped {
+            let state = manager.service_state(&session, &service).await.unwrap();
+            assert_eq!(state, ServiceState::Running, "{service}");
+        }
+
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
+
+    #[tokio::test]
+    async fn restore_without_a_record_starts_the_given_services() {
+        let (manager, session, root) = local_kindle("restore_with").await;
+        manager.stop_service(&session, "framework").await.unwrap();
+
+        let services = ["framework".to_string()];
+        let report = manager.restore_with(&session, &services).await.unwrap();
+        let start = report
+            .steps
+            .iter()
+            .find(|step| step.description == "Start framework")
+            .unwrap();
+        assert_eq!(start.outcome, StepOutcome::Changed);
+        let state = manager.service_state(&session, "framework").await.unwrap();
+        assert_eq!(state, ServiceState::Running);
+
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
+
+    #[tokio::test]
+    async fn checksums_skip_directories() {
+        let (manager, session, root) = local_kindle("checksums").await;
+        session
+            .upload("/mnt/us/images/cat.png", b"cat", None)
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(root.join("mnt/us/images/thumbnails"))
+            .await
+            .unwrap();
+
+        let checksums = manager.checksums(&session).await.unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(checksums["cat.png"], "d077f244def8a70e5ea758bd8352fcd8");
+
+        let _ = tokio::fs::remove_dir_all(root).await;
+    }
+
+    #[tokio::test]
+    async fn regions_overflowing_the_screen_are_refused() {
+        let (manager, session, root) = local_kindle("overflowing_region").await;
+        session
+            .upload("/mnt/us/images/cat.png", b"cat", None)
+            .await
+            .unwrap();
+
+        let options = RefreshOptions {
+            mode: RefreshMode::Region {
+                x: u32::MAX,
+                y: 0,
+                width: 2,
+                height: 1,
+            },
+            ..RefreshOptions::default()
+        };
+        let result = manager.set_image_with(&session, "cat.png", &options).await;
+        assert!(matches!(result, Err(KindleManagerError::OutOfRange(_))));
+
+        let _ = tokio::fs::remove_dir_all(root).await;
     }
 }
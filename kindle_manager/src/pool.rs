@@ -0,0 +1,162 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{Backend, KindleManagerError, KindleTransport};
+
+/// Keeps a single connection to the Kindle alive so it can be shared between callers.
+///
+/// The connection is checked every time it is borrowed and re-established if it dropped.
+/// Failed connection attempts back off exponentially, so an unreachable Kindle makes callers
+/// fail fast with [`KindleManagerError::Unreachable`] instead of waiting on SSH every time.
+///
+/// The state is never locked during network I/O, so a slow check or reconnect only holds up the
+/// callers that need the connection. Only one caller reconnects at a time, the others wait for it
+/// and share its connection.
+#[derive(Debug)]
+pub struct SessionPool {
+    backend: Backend,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    state: Mutex<PoolState>,
+    connecting: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    session: Option<Arc<dyn KindleTransport>>,
+    failed_attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+impl std::fmt::Debug for PoolState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoolState")
+            .field("connected", &self.session.is_some())
+            .field("failed_attempts", &self.failed_attempts)
+            .field("retry_at", &self.retry_at)
+            .finish()
+    }
+}
+
+impl SessionPool {
+    pub fn new(backend: Backend) -> Self {
+        SessionPool {
+            backend,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            state: Mutex::new(PoolState::default()),
+            connecting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Changes how long to wait after the first failed connection attempt and the most it will
+    /// ever wait between attempts
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Borrows the shared connection, reconnecting if needed
+    pub async fn get(&self) -> Result<Arc<dyn KindleTransport>, KindleManagerError> {
+        let session = self.state.lock().unwrap().session.clone();
+        if let Some(session) = session {
+            match session.check().await {
+                Ok(_) => return Ok(session),
+                Err(err) => {
+                    eprintln!("> Connection to the Kindle was lost, reconnecting");
+                    eprintln!("{err}");
+                    self.drop_session(&session);
+                }
+            }
+        }
+
+        self.backing_off()?;
+        let _connecting = self.connecting.lock().await;
+        // Another caller may have reconnected, or failed to, while this one waited
+        if let Some(session) = self.state.lock().unwrap().session.clone() {
+            return Ok(session);
+        }
+        self.backing_off()?;
+
+        let connection = self.backend.connect().await;
+        let mut state = self.state.lock().unwrap();
+        match connection {
+            Ok(session) => {
+                let session: Arc<dyn KindleTransport> = Arc::from(session);
+                state.session = Some(session.clone());
+                state.failed_attempts = 0;
+                state.retry_at = None;
+                Ok(session)
+            }
+            Err(err) => {
+                state.failed_attempts += 1;
+                let backoff = self
+                    .min_backoff
+                    .saturating_mul(2u32.saturating_pow(state.failed_attempts - 1))
+                    .min(self.max_backoff);
+                state.retry_at = Some(Instant::now() + backoff);
+                Err(err)
+            }
+        }
+    }
+
+    /// Fails while waiting before the next connection attempt
+    fn backing_off(&self) -> Result<(), KindleManagerError> {
+        if let Some(retry_at) = self.state.lock().unwrap().retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                return Err(KindleManagerError::Unreachable(retry_at - now));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets a dead connection, unless another caller already replaced it
+    fn drop_session(&self, session: &Arc<dyn KindleTransport>) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .session
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, session))
+        {
+            state.session = None;
+        }
+    }
+
+    /// Whether a connection is currently open, without checking if it is still alive
+    pub async fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().session.is_some()
+    }
+
+    /// Drops the current connection, the next [`Self::get`] will open a new one right away
+    pub async fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.session = None;
+        state.failed_attempts = 0;
+        state.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn callers_share_one_connection() {
+        let pool = SessionPool::new(Backend::Local {
+            root: std::env::temp_dir().join("kindle_manager_pool"),
+        });
+
+        let (first, second) = tokio::join!(pool.get(), pool.get());
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+        assert!(pool.is_connected().await);
+
+        pool.reset().await;
+        assert!(!pool.is_connected().await);
+        pool.get().await.unwrap();
+        assert!(pool.is_connected().await);
+    }
+}
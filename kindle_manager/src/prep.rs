@@ -0,0 +1,147 @@
+//! What [`crate::KindleManager::prep`] changes on the Kindle, and the record that lets
+//! [`crate::KindleManager::restore`] undo it.
+
+use std::fmt;
+
+/// Where `prep` records the settings it changes. In `/tmp` since restarting the Kindle undoes the
+/// prep anyway.
+pub(crate) const PREP_RECORD: &str = "/tmp/kindle_manager.prep";
+
+pub(crate) const GOVERNOR: &str = "/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor";
+
+/// Governor `prep` switches to, the lowest CPU clock
+pub(crate) const POWERSAVE: &str = "powersave";
+
+/// Prep asks `powerd` to keep the screensaver away, so it can't be stopped
+pub const REQUIRED_SERVICES: [&str; 1] = ["powerd"];
+
+/// State of an upstart job, as `status <service>` prints it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServiceState {
+    Running,
+    Stopped,
+    /// No job by that name on this Kindle
+    Missing,
+}
+
+impl ServiceState {
+    /// Reads the output of `status`, e.g. `x start/running, process 1234` or `x stop/waiting`
+    pub(crate) fn parse(status: &str) -> Option<Self> {
+        let goal = status.split_whitespace().nth(1)?;
+        match goal.split('/').next()? {
+            "start" => Some(ServiceState::Running),
+            "stop" => Some(ServiceState::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// How a step of the prep went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The step changed the Kindle
+    Changed,
+    /// The Kindle already was the way the step wants it, from an earlier prep or on its own
+    Unchanged,
+    /// The service doesn't exist on this Kindle, so there was nothing to stop
+    Missing,
+}
+
+impl StepOutcome {
+    pub fn id(&self) -> &'static str {
+        match self {
+            StepOutcome::Changed => "changed",
+            StepOutcome::Unchanged => "unchanged",
+            StepOutcome::Missing => "missing",
+        }
+    }
+}
+
+impl fmt::Display for StepOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrepStep {
+    /// What the step does, e.g. "Stop lab126_gui"
+    pub description: String,
+    pub outcome: StepOutcome,
+}
+
+/// Every step of a prep in order, with what each one changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrepReport {
+    pub steps: Vec<PrepStep>,
+}
+
+impl PrepReport {
+    pub(crate) fn push(&mut self, description: impl Into<String>, outcome: StepOutcome) {
+        self.steps.push(PrepStep {
+            description: description.into(),
+            outcome,
+        });
+    }
+
+    /// Whether any step changed the Kindle, a prep of a prepped Kindle changes nothing
+    pub fn changed(&self) -> bool {
+        self.steps
+            .iter()
+            .any(|step| step.outcome == StepOutcome::Changed)
+    }
+}
+
+/// Settings changed by `prep`, as they were before, and the services it stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrepRecord {
+    pub governor: String,
+    pub prevent_screen_saver: bool,
+    /// In the order they were stopped
+    pub stopped: Vec<String>,
+}
+
+impl PrepRecord {
+    /// What a Kindle runs with out of the box, with `services` to restart
+    pub(crate) fn defaults(services: &[String]) -> Self {
+        PrepRecord {
+            governor: "ondemand".into(),
+            prevent_screen_saver: false,
+            stopped: services.to_vec(),
+        }
+    }
+
+    /// Reads the `key=value` lines written by its `Display`, defaults fill in anything missing
+    pub(crate) fn parse(saved: &str) -> Self {
+        let mut record = PrepRecord::defaults(&[]);
+        for (key, value) in saved.lines().filter_map(|line| line.split_once('=')) {
+            match (key.trim(), value.trim()) {
+                ("governor", governor) if !governor.is_empty() => {
+                    record.governor = governor.to_string()
+                }
+                ("prevent_screen_saver", prevent) => record.prevent_screen_saver = prevent == "1",
+                ("stopped", stopped) => {
+                    record.stopped = stopped
+                        .split(',')
+                        .filter(|service| !service.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        record
+    }
+}
+
+impl fmt::Display for PrepRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "governor={}", self.governor)?;
+        writeln!(
+            f,
+            "prevent_screen_saver={}",
+            u8::from(self.prevent_screen_saver)
+        )?;
+        writeln!(f, "stopped={}", self.stopped.join(","))
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, str::FromStr};
+
+/// Kindle models with a known [`DeviceProfile`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KindleModel {
+    PaperWhite2,
+    PaperWhite3,
+    Voyage,
+    Oasis,
+    Basic,
+}
+
+impl KindleModel {
+    pub const ALL: [KindleModel; 5] = [
+        KindleModel::PaperWhite2,
+        KindleModel::PaperWhite3,
+        KindleModel::Voyage,
+        KindleModel::Oasis,
+        KindleModel::Basic,
+    ];
+
+    /// Short name used in configuration and command line arguments
+    pub fn id(&self) -> &'static str {
+        match self {
+            KindleModel::PaperWhite2 => "pw2",
+            KindleModel::PaperWhite3 => "pw3",
+            KindleModel::Voyage => "voyage",
+            KindleModel::Oasis => "oasis",
+            KindleModel::Basic => "basic",
+        }
+    }
+
+    /// Identifies the model from the Kindle's serial number (`/proc/usid`)
+    ///
+    /// Older devices keep the device code in the 3rd and 4th characters, newer ones (starting with
+    /// `G`) in the 4th to 6th. Codes taken from KOReader's device detection.
+    pub fn from_serial(serial: &str) -> Option<KindleModel> {
+        let serial = serial.trim();
+        if serial.starts_with('G') {
+            let code = serial.get(3..6)?;
+            match code {
+                "0G1" | "0G2" | "0G4" | "0G5" | "0G6" | "0G7" | "0KB" | "0KC" | "0KD" | "0KE"
+                | "0KF" | "0KG" | "0LK" | "0LL" => Some(KindleModel::PaperWhite3),
+                "0GC" | "0GD" | "0GR" | "0GS" | "0GT" | "0GU" => Some(KindleModel::Oasis),
+                "0DU" | "0K9" | "0KA" => Some(KindleModel::Basic),
+                _ => None,
+            }
+        } else {
+            let code = serial.get(2..4)?;
+            match code {
+                "D4" | "5A" | "D5" | "D6" | "D7" | "D8" | "F2" | "17" | "60" | "F4" | "F9"
+                | "62" | "61" | "5F" => Some(KindleModel::PaperWhite2),
+                "13" | "54" | "2A" | "4F" | "52" | "53" => Some(KindleModel::Voyage),
+                "C6" | "DD" => Some(KindleModel::Basic),
+                _ => None,
+            }
+        }
+    }
+}
+
+impl fmt::Display for KindleModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+impl FromStr for KindleModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KindleModel::ALL
+            .into_iter()
+            .find(|model| model.id().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown Kindle model \"{s}\""))
+    }
+}
+
+/// Screen and hardware details that change between Kindle models
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// Screen width in pixels, in portrait orientation
+    pub width: u32,
+    /// Screen height in pixels, in portrait orientation
+    pub height: u32,
+    pub dpi: u32,
+    /// Number of gray levels the screen can show
+    pub gray_levels: u16,
+    /// sysfs file controlling the frontlight, if there is one
+    pub backlight_path: Option<String>,
+    /// Highest value that still changes the frontlight intensity
+    pub max_intensity: u16,
+    /// Whether `eips` can rotate the framebuffer on this model
+    pub supports_rotation: bool,
+    /// Upstart jobs [`crate::KindleManager::prep`] stops, in order
+    pub prep_services: Vec<String>,
+}
+
+impl DeviceProfile {
+    pub fn for_model(model: KindleModel) -> Self {
+        match model {
+            KindleModel::PaperWhite2 => DeviceProfile {
+                name: "Kindle PaperWhite 2".into(),
+                width: 758,
+                height: 1024,
+                dpi: 212,
+                gray_levels: 16,
+                backlight_path: Some(
+                    "/sys/devices/system/fl_tps6116x/fl_tps6116x0/fl_intensity".into(),
+                ),
+                // Intensity seems to be between 0..=255, higher values don't do anything more
+                max_intensity: 255,
+                supports_rotation: true,
+                prep_services: reader_services(),
+            },
+            KindleModel::PaperWhite3 => DeviceProfile {
+                name: "Kindle PaperWhite 3".into(),
+                width: 1072,
+                height: 1448,
+                dpi: 300,
+                gray_levels: 16,
+                backlight_path: Some("/sys/class/backlight/max77696-bl/brightness".into()),
+                max_intensity: 4095,
+                supports_rotation: true,
+                prep_services: reader_services(),
+            },
+            KindleModel::Voyage => DeviceProfile {
+                name: "Kindle Voyage".into(),
+                width: 1072,
+                height: 1448,
+                dpi: 300,
+                gray_levels: 16,
+                backlight_path: Some("/sys/class/backlight/max77696-bl/brightness".into()),
+                max_intensity: 4095,
+                supports_rotation: true,
+                prep_services: reader_services(),
+            },
+            KindleModel::Oasis => DeviceProfile {
+                name: "Kindle Oasis".into(),
+                width: 1072,
+                height: 1448,
+                dpi: 300,
+                gray_levels: 16,
+                backlight_path: Some("/sys/class/backlight/max77696-bl/brightness".into()),
+                max_intensity: 4095,
+                supports_rotation: true,
+                prep_services: reader_services(),
+            },
+            KindleModel::Basic => DeviceProfile {
+                name: "Kindle Basic".into(),
+                width: 600,
+                height: 800,
+                dpi: 167,
+                gray_levels: 16,
+                backlight_path: None,
+                max_intensity: 0,
+                supports_rotation: false,
+                prep_services: reader_services(),
+            },
+        }
+    }
+
+    /// Resolution as `WIDTHxHEIGHT`
+    pub fn resolution(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+}
+
+/// Services of the reader that showing images doesn't need, the same on every known model.
+/// `framework` can be stopped too on firmwares that have it, `powerd` has to keep running.
+fn reader_services() -> Vec<String> {
+    ["lab126_gui", "otaupd", "phd", "tmd", "x", "todo"]
+        .map(String::from)
+        .to_vec()
+}
+
+impl Default for DeviceProfile {
+    /// The PW2, which is what this project was originally built for
+    fn default() -> Self {
+        DeviceProfile::for_model(KindleModel::PaperWhite2)
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::{ExitStatus, Output},
+};
+
+use async_trait::async_trait;
+use md5::{Digest, Md5};
+use openssh::{Session, Stdio};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{CheckStdout, KindleManagerError};
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("failed to send data: {0}")]
+    Write(std::io::Error),
+
+    #[error("failed to receive data: {0}")]
+    Read(std::io::Error),
+
+    #[error("the Kindle reported an error: {0}")]
+    Remote(String),
+
+    #[error("only {transferred} of {expected} bytes were transferred")]
+    Incomplete { transferred: u64, expected: u64 },
+}
+
+/// How far along a file transfer is, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    pub transferred: u64,
+    pub total: u64,
+}
+
+/// Called every time a chunk of a file is transferred
+pub type ProgressCallback<'a> = &'a (dyn Fn(TransferProgress) + Send + Sync);
+
+/// Everything the [`KindleManager`](crate::KindleManager) needs from a connection to a Kindle.
+///
+/// Paths are always absolute paths on the Kindle, e.g. `/mnt/us/images/cat.png`.
+#[async_trait]
+pub trait KindleTransport: Send + Sync {
+    /// Runs a program on the Kindle, returning its raw output
+    async fn run(&self, program: &str, args: &[&str]) -> Result<Output, KindleManagerError>;
+
+    /// Writes `data` to `remote_path`, overwriting it if it already exists
+    async fn upload(
+        &self,
+        remote_path: &str,
+        data: &[u8],
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<(), KindleManagerError>;
+
+    /// Reads the whole file at `remote_path`
+    async fn download(
+        &self,
+        remote_path: &str,
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<Vec<u8>, KindleManagerError>;
+
+    /// Lists the names of the entries inside `remote_path`
+    async fn list_dir(&self, remote_path: &str) -> Result<Vec<String>, KindleManagerError>;
+
+    /// MD5 of every file inside `remote_path` as lowercase hex, by file name
+    async fn checksums(
+        &self,
+        remote_path: &str,
+    ) -> Result<HashMap<String, String>, KindleManagerError> {
+        let stdout = self
+            .run(
+                "sh",
+                &[
+                    "-c",
+                    "cd \"$0\" && for file in *; do [ -f \"$file\" ] && md5sum \"$file\"; done; true",
+                    remote_path,
+                ],
+            )
+            .await?
+            .check_stdout()?;
+
+        Ok(stdout
+            .lines()
+            .filter_map(|line| line.split_once("  "))
+            .map(|(checksum, name)| (name.to_string(), checksum.to_string()))
+            .collect())
+    }
+
+    /// Checks whether the connection is still alive
+    async fn check(&self) -> Result<(), KindleManagerError> {
+        Ok(())
+    }
+
+    async fn rename(&self, old_path: &str, new_path: &str) -> Result<(), KindleManagerError> {
+        let _ = self
+            .run("mv", &[old_path, new_path])
+            .await?
+            .check_stdout()?;
+        Ok(())
+    }
+
+    async fn remove(&self, remote_path: &str) -> Result<(), KindleManagerError> {
+        let _ = self.run("rm", &[remote_path]).await?.check_stdout()?;
+        Ok(())
+    }
+}
+
+/// Chunk size used when streaming files through the SSH session
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Talks to a real Kindle over a multiplexed SSH connection, files are streamed through it too.
+#[derive(Debug)]
+pub struct SshTransport {
+    session: Session,
+}
+
+impl SshTransport {
+    pub async fn connect(address: &str) -> Result<Self, KindleManagerError> {
+        let session = Session::connect_mux(address, openssh::KnownHosts::Strict).await?;
+        Ok(SshTransport { session })
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Size in bytes of a file on the Kindle
+    async fn remote_size(&self, remote_path: &str) -> Result<u64, KindleManagerError> {
+        let stdout = self
+            .run("sh", &["-c", "wc -c < \"$0\"", remote_path])
+            .await?
+            .check_stdout()?;
+
+        stdout.trim().parse::<u64>().map_err(|err| {
+            KindleManagerError::CommandError(format!("Failed conversion of {stdout}: {err}"))
+        })
+    }
+}
+
+#[async_trait]
+impl KindleTransport for SshTransport {
+    async fn run(&self, program: &str, args: &[&str]) -> Result<Output, KindleManagerError> {
+        Ok(self.session.command(program).args(args).output().await?)
+    }
+
+    async fn check(&self) -> Result<(), KindleManagerError> {
+        Ok(self.session.check().await?)
+    }
+
+    async fn upload(
+        &self,
+        remote_path: &str,
+        data: &[u8],
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<(), KindleManagerError> {
+        let transfer_error = |source| KindleManagerError::Transfer {
+            path: remote_path.to_string(),
+            source,
+        };
+
+        // Write to a temporary file first so an interrupted upload doesn't leave a broken image
+        let mut child = self
+            .session
+            .command("sh")
+            .args([
+                "-c",
+                "cat > \"$0.part\" && mv \"$0.part\" \"$0\"",
+                remote_path,
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .await?;
+
+        let total = data.len() as u64;
+        if let Some(stdin) = child.stdin().as_mut() {
+            let mut transferred = 0;
+            for chunk in data.chunks(CHUNK_SIZE) {
+                stdin
+                    .write_all(chunk)
+                    .await
+                    .map_err(|err| transfer_error(TransferError::Write(err)))?;
+                transferred += chunk.len() as u64;
+                if let Some(progress) = progress {
+                    progress(TransferProgress { transferred, total });
+                }
+            }
+            stdin
+                .shutdown()
+                .await
+                .map_err(|err| transfer_error(TransferError::Write(err)))?;
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr)?;
+            return Err(transfer_error(TransferError::Remote(stderr)));
+        }
+
+        let written = self.remote_size(remote_path).await?;
+        if written != total {
+            return Err(transfer_error(TransferError::Incomplete {
+                transferred: written,
+                expected: total,
+            }));
+        }
+
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        remote_path: &str,
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<Vec<u8>, KindleManagerError> {
+        let transfer_error = |source| KindleManagerError::Transfer {
+            path: remote_path.to_string(),
+            source,
+        };
+
+        let total = self.remote_size(remote_path).await?;
+
+        let mut child = self
+            .session
+            .command("cat")
+            .arg(remote_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .await?;
+
+        let mut data = Vec::with_capacity(total as usize);
+        if let Some(stdout) = child.stdout().as_mut() {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            loop {
+                let read = stdout
+                    .read(&mut buffer)
+                    .await
+                    .map_err(|err| transfer_error(TransferError::Read(err)))?;
+                if read == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buffer[..read]);
+                if let Some(progress) = progress {
+                    progress(TransferProgress {
+                        transferred: data.len() as u64,
+                        total,
+                    });
+                }
+            }
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr)?;
+            return Err(transfer_error(TransferError::Remote(stderr)));
+        }
+        if data.len() as u64 != total {
+            return Err(transfer_error(TransferError::Incomplete {
+                transferred: data.len() as u64,
+                expected: total,
+            }));
+        }
+
+        Ok(data)
+    }
+
+    async fn list_dir(&self, remote_path: &str) -> Result<Vec<String>, KindleManagerError> {
+        let stdout = self.run("ls", &[remote_path]).await?.check_stdout()?;
+
+        let files = stdout
+            .split('\n')
+            .map(|s| s.to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        Ok(files)
+    }
+}
+
+/// Pretends a local directory is the Kindle's filesystem, useful for developing without a device.
+///
+/// Files are stored under `root`, so `/mnt/us/images/cat.png` becomes `<root>/mnt/us/images/cat.png`.
+/// Commands aren't executed, they are only logged, apart from a few which return plausible output.
+/// The upstart jobs, CPU governor and screensaver setting that prep changes are kept under
+/// `<root>/.local_kindle` so they last across sessions, like on a Kindle.
+#[derive(Debug)]
+pub struct LocalTransport {
+    root: PathBuf,
+}
+
+impl LocalTransport {
+    pub fn new(root: PathBuf) -> Self {
+        LocalTransport { root }
+    }
+
+    fn local_path(&self, remote_path: &str) -> PathBuf {
+        self.root.join(remote_path.trim_start_matches('/'))
+    }
+
+    /// File holding a piece of the fake Kindle's state
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.root.join(".local_kindle").join(name)
+    }
+
+    async fn read_state(&self, name: &str, default: &str) -> String {
+        match tokio::fs::read_to_string(self.state_path(name)).await {
+            Ok(value) => value,
+            Err(_) => default.to_string(),
+        }
+    }
+
+    async fn write_state(&self, name: &str, value: &str) -> Result<(), KindleManagerError> {
+        let path = self.state_path(name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, value).await?;
+        Ok(())
+    }
+
+    /// `status`, `start` and `stop` of an upstart job, which all run until stopped
+    async fn service(&self, command: &str, service: &str) -> Result<Output, KindleManagerError> {
+        if !LOCAL_SERVICES.contains(&service) {
+            return Ok(fake_output(
+                false,
+                "",
+                &format!("{command}: Unknown job: {service}\n"),
+            ));
+        }
+        let stopped = self.state_path(&format!("stopped/{service}"));
+        let running = !tokio::fs::try_exists(&stopped).await?;
+        let output = match (command, running) {
+            ("start", false) => {
+                tokio::fs::remove_file(&stopped).await?;
+                fake_output(true, &format!("{service} start/running, process 1\n"), "")
+            }
+            ("stop", true) => {
+                self.write_state(&format!("stopped/{service}"), "").await?;
+                fake_output(true, &format!("{service} stop/waiting\n"), "")
+            }
+            ("start", true) => fake_output(false, "", "start: Job is already running\n"),
+            ("stop", false) => fake_output(false, "", "stop: Unknown instance:\n"),
+            (_, true) => fake_output(true, &format!("{service} start/running, process 1\n"), ""),
+            (_, false) => fake_output(true, &format!("{service} stop/waiting\n"), ""),
+        };
+        Ok(output)
+    }
+}
+
+/// Upstart jobs of the fake Kindle, anything else is an unknown job
+const LOCAL_SERVICES: [&str; 8] = [
+    "lab126_gui",
+    "otaupd",
+    "phd",
+    "tmd",
+    "x",
+    "todo",
+    "framework",
+    "powerd",
+];
+
+fn fake_output(success: bool, stdout: &str, stderr: &str) -> Output {
+    Output {
+        status: ExitStatus::from_raw(if success { 0 } else { 1 << 8 }),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: stderr.as_bytes().to_vec(),
+    }
+}
+
+#[async_trait]
+impl KindleTransport for LocalTransport {
+    async fn run(&self, program: &str, args: &[&str]) -> Result<Output, KindleManagerError> {
+        println!("[local kindle] {} {}", program, args.join(" "));
+
+        let output = match (program, args) {
+            ("gasgauge-info", ["-c"]) => fake_output(true, "100%\n", ""),
+            ("gasgauge-info", ["-l"]) => fake_output(true, "0 mA\n", ""),
+            ("status" | "start" | "stop", [service]) => self.service(program, service).await?,
+            ("cat", [crate::prep::GOVERNOR]) => {
+                fake_output(true, &self.read_state("governor", "ondemand\n").await, "")
+            }
+            ("sh", ["-c", _, governor, crate::prep::GOVERNOR]) => {
+                self.write_state("governor", &format!("{governor}\n"))
+                    .await?;
+                fake_output(true, "", "")
+            }
+            ("lipc-get-prop", ["com.lab126.powerd", "preventScreenSaver"]) => fake_output(
+                true,
+                &self.read_state("preventScreenSaver", "0\n").await,
+                "",
+            ),
+            ("lipc-set-prop", ["com.lab126.powerd", "preventScreenSaver", value]) => {
+                self.write_state("preventScreenSaver", &format!("{value}\n"))
+                    .await?;
+                fake_output(true, "", "")
+            }
+            // Pretend to be a PW2
+            ("cat", ["/proc/usid"]) => fake_output(true, "B0D4000000000000\n", ""),
+            // An 8-bit framebuffer with rows padded to 768 bytes, like a PW2's
+            ("cat", [bits_per_pixel, stride])
+                if [*bits_per_pixel, *stride] == crate::framebuffer::SYSFS_FILES =>
+            {
+                fake_output(true, "8\n768\n", "")
+            }
+            // A blank white screen
+            ("dd", ["if=/dev/fb0", bs, count]) => {
+                let size = |arg: &str, key: &str| -> usize {
+                    arg.strip_prefix(key)
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(0)
+                };
+                Output {
+                    stdout: vec![255; size(bs, "bs=") * size(count, "count=")],
+                    ..fake_output(true, "", "")
+                }
+            }
+            _ => fake_output(true, "", ""),
+        };
+
+        Ok(output)
+    }
+
+    async fn upload(
+        &self,
+        remote_path: &str,
+        data: &[u8],
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<(), KindleManagerError> {
+        let local_path = self.local_path(remote_path);
+        if let Some(parent) = local_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(local_path, data).await?;
+
+        if let Some(progress) = progress {
+            let total = data.len() as u64;
+            progress(TransferProgress {
+                transferred: total,
+                total,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        remote_path: &str,
+        progress: Option<ProgressCallback<'_>>,
+    ) -> Result<Vec<u8>, KindleManagerError> {
+        let data = tokio::fs::read(self.local_path(remote_path)).await?;
+
+        if let Some(progress) = progress {
+            let total = data.len() as u64;
+            progress(TransferProgress {
+                transferred: total,
+                total,
+            });
+        }
+
+        Ok(data)
+    }
+
+    async fn checksums(
+        &self,
+        remote_path: &str,
+    ) -> Result<HashMap<String, String>, KindleManagerError> {
+        let mut checksums = HashMap::new();
+        for name in self.list_dir(remote_path).await? {
+            // Like `[ -f "$file" ]` on the Kindle
+            let path = self.local_path(remote_path).join(&name);
+            if !tokio::fs::metadata(&path).await?.is_file() {
+                continue;
+            }
+            let data = tokio::fs::read(path).await?;
+            let digest = Md5::digest(data);
+            let checksum = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+            checksums.insert(name, checksum);
+        }
+
+        Ok(checksums)
+    }
+
+    async fn list_dir(&self, remote_path: &str) -> Result<Vec<String>, KindleManagerError> {
+        let local_path = self.local_path(remote_path);
+        tokio::fs::create_dir_all(&local_path).await?;
+
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(local_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                files.push(name.to_string());
+            }
+        }
+        // Same order as `ls`
+        files.sort();
+
+        Ok(files)
+    }
+
+    async fn rename(&self, old_path: &str, new_path: &str) -> Result<(), KindleManagerError> {
+        tokio::fs::rename(self.local_path(old_path), self.local_path(new_path)).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, remote_path: &str) -> Result<(), KindleManagerError> {
+        tokio::fs::remove_file(self.local_path(remote_path)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+//! JSON API mounted at `/api/v1`, for scripting against the server without scraping the HTMX
+//! pages. Requests send forms like the pages do, responses are JSON. Failed requests get an HTTP
+//! error status with an [`ApiErrorBody`]. Scripts sign in with an API token, see [`crate::auth`],
+//! and pick the Kindle with the `X-Kindle-Device` header, see [`crate::devices`].
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use kindle_manager::prep::PrepReport;
+use kindle_manager::KindleManagerError;
+use rocket::form::Form;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Request, Response, Route, State};
+
+use crate::auth::{Admin, User};
+use crate::catalog::{Catalog, ImageRecord, SortBy};
+use crate::config::Config;
+use crate::dashboard::Dashboard;
+use crate::devices::{DeviceStatus, Devices, KindleM, PushResult, UnknownDevice};
+use crate::playlists::Playlists;
+use crate::slideshow::{Slideshow, SlideshowStatus};
+use crate::sync::{SyncEntry, SyncResult};
+use crate::{
+    apply_sync, delete, pick_devices, push_to_devices, rename, start_slideshow, sync_plan, upload,
+    valid_filename, ApplySync, PrintText, RefreshForm, ServerError, StartSlideshow, UploadImage,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_images,
+        get_image,
+        upload_image,
+        rename_image,
+        delete_image,
+        set_image,
+        print_text,
+        prep,
+        restore,
+        sync_plan_route,
+        sync_apply,
+        battery,
+        device,
+        screen,
+        list_devices,
+        push_image,
+        slideshow_status,
+        slideshow_start,
+        slideshow_stop,
+        slideshow_skip,
+    ]
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        unprocessable_entity
+    ]
+}
+
+/// Body of every failed request, `error` is a short code scripts can match on
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiErrorBody {
+    pub error: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    body: ApiErrorBody,
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// What a prep or restore did, `changed` is false when the Kindle already was that way
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PrepReportBody {
+    pub changed: bool,
+    pub steps: Vec<PrepStepBody>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PrepStepBody {
+    pub description: String,
+    /// changed, unchanged or missing
+    pub outcome: &'static str,
+}
+
+impl From<PrepReport> for PrepReportBody {
+    fn from(report: PrepReport) -> Self {
+        PrepReportBody {
+            changed: report.changed(),
+            steps: report
+                .steps
+                .into_iter()
+                .map(|step| PrepStepBody {
+                    outcome: step.outcome.id(),
+                    description: step.description,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ApiError {
+    fn new(status: Status, error: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            body: ApiErrorBody {
+                error,
+                message: message.into(),
+            },
+        }
+    }
+}
+
+impl<E: Into<ServerError>> From<E> for ApiError {
+    fn from(err: E) -> Self {
+        let err = err.into();
+        let (status, error) = match &err {
+            ServerError::KindleError(err) => match err {
+                KindleManagerError::FileExists(_) => (Status::Conflict, "file_exists"),
+                KindleManagerError::FileMissing(_) => (Status::NotFound, "file_missing"),
+                KindleManagerError::OutOfRange(_) => (Status::BadRequest, "out_of_range"),
+                KindleManagerError::Unsupported(_) => (Status::NotImplemented, "unsupported"),
+                KindleManagerError::Unreachable(_) => (Status::ServiceUnavailable, "unreachable"),
+                KindleManagerError::ImageError(_) => (Status::UnprocessableEntity, "image_error"),
+                KindleManagerError::StdioError(_) => (Status::InternalServerError, "io_error"),
+                _ => (Status::BadGateway, "kindle_error"),
+            },
+            ServerError::IOError(_) => (Status::InternalServerError, "io_error"),
+            ServerError::NotFound(_) => (Status::NotFound, "not_found"),
+            ServerError::Forbidden(_) => (Status::Forbidden, "forbidden"),
+            ServerError::InvalidInput(_) => (Status::UnprocessableEntity, "invalid_form"),
+            ServerError::BadRequest(_) => (Status::BadRequest, "bad_request"),
+            ServerError::CatalogError(_) => (Status::InternalServerError, "catalog_error"),
+            ServerError::Other(_) => (Status::InternalServerError, "other"),
+        };
+        ApiError::new(status, error, err.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        eprintln!("> API request failed with {}", self.status);
+        eprintln!("{}", self.body.message);
+        Response::build_from(Json(self.body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+#[catch(400)]
+fn bad_request() -> ApiError {
+    ApiError::new(
+        Status::BadRequest,
+        "bad_request",
+        "The request is malformed",
+    )
+}
+
+#[catch(401)]
+fn unauthorized() -> ApiError {
+    ApiError::new(
+        Status::Unauthorized,
+        "unauthorized",
+        "Send an API token as `Authorization: Bearer <token>`",
+    )
+}
+
+#[catch(403)]
+fn forbidden() -> ApiError {
+    ApiError::new(
+        Status::Forbidden,
+        "forbidden",
+        "Only admin accounts can make changes",
+    )
+}
+
+#[catch(404)]
+fn not_found(request: &Request<'_>) -> ApiError {
+    if let UnknownDevice(Some(device)) = request.local_cache(UnknownDevice::default) {
+        return ApiError::new(
+            Status::NotFound,
+            "unknown_device",
+            format!("No device named {device}"),
+        );
+    }
+    ApiError::new(
+        Status::NotFound,
+        "not_found",
+        format!("No route for {}", request.uri()),
+    )
+}
+
+#[catch(422)]
+fn unprocessable_entity() -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity,
+        "invalid_form",
+        "The form is missing fields or has invalid values",
+    )
+}
+
+/// Every image on the server or the Kindle, images only on the Kindle have no metadata
+#[get("/images?<sort>&<tag>")]
+async fn list_images(
+    _user: User,
+    sort: Option<&str>,
+    tag: Option<&str>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> ApiResult<Vec<ImageRecord>> {
+    let session = km.pool.get().await?;
+    let kindle_images: HashSet<String> =
+        HashSet::from_iter(km.manager.list_files(&*session).await?);
+    catalog.update_on_kindle(&km.name, &kindle_images)?;
+
+    let tag = tag.filter(|tag| !tag.is_empty());
+    let mut records = catalog.list(SortBy::from(sort), tag)?;
+    if tag.is_none() {
+        let known = catalog.names()?;
+        let mut unknown: Vec<&String> = kindle_images.difference(&known).collect();
+        unknown.sort();
+        records.extend(unknown.into_iter().map(|name| ImageRecord {
+            devices: vec![km.name.clone()],
+            ..ImageRecord::new(name)
+        }));
+    }
+
+    Ok(Json(records))
+}
+
+#[get("/images/<name>")]
+fn get_image(_user: User, name: &str, catalog: &State<Arc<Catalog>>) -> ApiResult<ImageRecord> {
+    let record = catalog
+        .get(name)?
+        .ok_or_else(|| ServerError::NotFound(format!("image {name}")))?;
+    Ok(Json(record))
+}
+
+/// Same form as the upload on the main page
+#[post("/images", data = "<form>")]
+async fn upload_image(
+    _admin: Admin,
+    mut form: Form<UploadImage<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> Result<(Status, Json<ImageRecord>), ApiError> {
+    let session = km.pool.get().await?;
+    let name = upload(&mut form, config, km, catalog, &*session).await?;
+    let record = catalog
+        .get(&name)?
+        .unwrap_or_else(|| ImageRecord::new(&name));
+    Ok((Status::Created, Json(record)))
+}
+
+// Rename Form, `name` doesn't include the extension
+#[derive(Debug, FromForm)]
+struct RenameImage<'v> {
+    #[field(validate = len(1..=200))]
+    #[field(validate = valid_filename())]
+    name: &'v str,
+}
+
+#[patch("/images/<name>", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+async fn rename_image(
+    _admin: Admin,
+    name: &str,
+    form: Form<RenameImage<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
+    devices: &State<Devices>,
+    km: &KindleM,
+) -> ApiResult<ImageRecord> {
+    let new_name = format!("{}.png", form.name);
+    rename(config, km, devices, catalog, playlists, name, &new_name).await?;
+    let record = catalog
+        .get(&new_name)?
+        .unwrap_or_else(|| ImageRecord::new(&new_name));
+    Ok(Json(record))
+}
+
+#[delete("/images/<name>")]
+async fn delete_image(
+    _admin: Admin,
+    name: &str,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
+    config: &State<Config>,
+    km: &KindleM,
+) -> Result<Status, ApiError> {
+    let session = km.pool.get().await?;
+    match delete(config, km, catalog, playlists, &*session, name).await? {
+        true => Ok(Status::NoContent),
+        false => Err(ServerError::NotFound(format!("image {name}")).into()),
+    }
+}
+
+/// Shows the image on the Kindle, with a full refresh unless the query picks another, so a bare
+/// `POST` keeps working
+#[post("/images/<name>/set?<refresh..>")]
+async fn set_image(
+    _admin: Admin,
+    name: &str,
+    refresh: RefreshForm<'_>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> Result<Status, ApiError> {
+    let refresh = refresh.settings()?.options();
+    let session = km.pool.get().await?;
+    km.manager.set_image_with(&*session, name, &refresh).await?;
+    catalog.mark_displayed(&km.name, name)?;
+    Ok(Status::NoContent)
+}
+
+/// Prints text over what the Kindle shows
+#[post("/print", data = "<form>")]
+async fn print_text(
+    _admin: Admin,
+    form: Form<PrintText<'_>>,
+    km: &KindleM,
+) -> Result<Status, ApiError> {
+    let options = form.options()?;
+    let session = km.pool.get().await?;
+    km.manager
+        .print_text(&*session, form.text, &options)
+        .await?;
+    Ok(Status::NoContent)
+}
+
+/// Keeps the Kindle awake to show images, only changing what isn't prepped yet, see
+/// [`kindle_manager::KindleManager::prep_with`]
+#[post("/prep")]
+async fn prep(_admin: Admin, km: &KindleM) -> ApiResult<PrepReportBody> {
+    Ok(Json(km.prep().await?.into()))
+}
+
+/// Undoes the prep, stopping the slideshow and dashboard if they run on the Kindle
+#[post("/restore")]
+async fn restore(
+    _admin: Admin,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+    dashboard: &State<Arc<Dashboard>>,
+    km: &KindleM,
+) -> ApiResult<PrepReportBody> {
+    let report = crate::restore(km, devices, slideshow, dashboard).await?;
+    Ok(Json(report.into()))
+}
+
+/// What syncing would change, without changing anything
+#[get("/sync")]
+async fn sync_plan_route(
+    _user: User,
+    catalog: &State<Arc<Catalog>>,
+    config: &State<Config>,
+    km: &KindleM,
+) -> ApiResult<Vec<SyncEntry>> {
+    let session = km.pool.get().await?;
+    let (plan, _, _) = sync_plan(config, km, catalog, &*session).await?;
+    Ok(Json(plan))
+}
+
+#[post("/sync", data = "<form>")]
+async fn sync_apply(
+    _admin: Admin,
+    form: Form<ApplySync<'_>>,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
+    config: &State<Config>,
+    km: &KindleM,
+) -> ApiResult<Vec<SyncResult>> {
+    let session = km.pool.get().await?;
+    let results = apply_sync(config, km, catalog, playlists, &*session, &form).await?;
+    Ok(Json(results))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Battery {
+    /// Percentage
+    charge: u8,
+    /// Current drawn, as reported by `gasgauge-info`
+    load: String,
+}
+
+#[get("/battery")]
+async fn battery(_user: User, km: &KindleM) -> ApiResult<Battery> {
+    let session = km.pool.get().await?;
+    Ok(Json(Battery {
+        charge: km.manager.battery_charge(&*session).await?,
+        load: km.manager.battery_load(&*session).await?.trim().to_string(),
+    }))
+}
+
+/// Whether the Kindle can be reached and what the server knows about it
+#[get("/device")]
+async fn device(_user: User, config: &State<Config>, km: &KindleM) -> Json<DeviceStatus> {
+    Json(km.status(&config.converted_dir).await)
+}
+
+/// What the screen shows, as a PNG
+#[get("/screen")]
+async fn screen(_user: User, km: &KindleM) -> Result<(ContentType, Vec<u8>), ApiError> {
+    Ok((ContentType::PNG, km.screenshot().await?))
+}
+
+/// Status of every device, the first one is the default
+#[get("/devices")]
+async fn list_devices(
+    _user: User,
+    config: &State<Config>,
+    devices: &State<Devices>,
+) -> Json<Vec<DeviceStatus>> {
+    let mut statuses = Vec::new();
+    for km in devices.all() {
+        statuses.push(km.status(&config.converted_dir).await);
+    }
+    Json(statuses)
+}
+
+// Push Form, `devices` defaults to every device
+#[derive(Debug, FromForm)]
+struct PushImage<'v> {
+    devices: Vec<&'v str>,
+    set: bool,
+}
+
+/// Pushes the image to several devices at once and optionally sets it, a device failing doesn't
+/// stop the others
+#[post("/images/<name>/push", data = "<form>")]
+async fn push_image(
+    _admin: Admin,
+    name: &str,
+    form: Form<PushImage<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    devices: &State<Devices>,
+) -> ApiResult<Vec<PushResult>> {
+    let targets = match form.devices.is_empty() {
+        true => devices.all().collect(),
+        false => pick_devices(devices, &form.devices)?,
+    };
+    let results = push_to_devices(config, catalog, &targets, name, form.set).await?;
+    Ok(Json(results))
+}
+
+/// What the slideshow is doing, it always runs on the default device
+#[get("/slideshow")]
+fn slideshow_status(_user: User, slideshow: &State<Arc<Slideshow>>) -> Json<SlideshowStatus> {
+    Json(slideshow.state().status())
+}
+
+/// Same form as the slideshow panel, stops the dashboard
+#[post("/slideshow/start", data = "<form>")]
+fn slideshow_start(
+    _admin: Admin,
+    form: Form<StartSlideshow<'_>>,
+    slideshow: &State<Arc<Slideshow>>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> ApiResult<SlideshowStatus> {
+    start_slideshow(&form, slideshow, dashboard)?;
+    Ok(Json(slideshow.state().status()))
+}
+
+#[post("/slideshow/stop")]
+fn slideshow_stop(_admin: Admin, slideshow: &State<Arc<Slideshow>>) -> Json<SlideshowStatus> {
+    slideshow.stop();
+    Json(slideshow.state().status())
+}
+
+/// Shows the next image right away
+#[post("/slideshow/skip")]
+fn slideshow_skip(_admin: Admin, slideshow: &State<Arc<Slideshow>>) -> Json<SlideshowStatus> {
+    slideshow.skip();
+    Json(slideshow.state().status())
+}
@@ -0,0 +1,512 @@
+//! Accounts allowed to use the server. People sign in with a password and get a session cookie,
+//! scripts send an API token as `Authorization: Bearer <token>`. Viewers can only look around,
+//! changing anything needs an admin, see the [`User`] and [`Admin`] request guards.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Redirect, Responder};
+use rocket::serde::Serialize;
+use rocket::time::Duration;
+use rocket::{Request, Response};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::templates::{errors, oob};
+use crate::ServerError;
+
+/// Private cookie holding the signed in account as `<name>:<session version>:<signed in at>`
+const SESSION_COOKIE: &str = "session";
+
+/// How long a sign in lasts
+const SESSION_LENGTH: Duration = Duration::days(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, FromFormField)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Role {
+    /// Can change images, the Kindle, the slideshow and accounts
+    Admin,
+    /// Can only look
+    Viewer,
+}
+
+impl Role {
+    pub const ALL: [Role; 2] = [Role::Admin, Role::Viewer];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "Admin",
+            Role::Viewer => "Viewer",
+        }
+    }
+
+    fn from_id(id: &str) -> Self {
+        match id {
+            "admin" => Role::Admin,
+            _ => Role::Viewer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Account {
+    pub name: String,
+    pub role: Role,
+    /// Changes with the password and role, signing out the sessions started before
+    #[serde(skip)]
+    session_version: i64,
+}
+
+/// API token without its secret, which is only shown once when created
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiToken {
+    pub id: String,
+    /// Account the token acts as
+    pub user: String,
+    pub label: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    pub last_used: Option<i64>,
+}
+
+/// First version of the schema, never changed, changes go in [`MIGRATIONS`]
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    password_hash TEXT NOT NULL,
+    role TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tokens (
+    id TEXT PRIMARY KEY,
+    user TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+    secret_hash TEXT NOT NULL,
+    label TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used INTEGER
+);
+";
+
+/// Changes to the schema, applied in order. `PRAGMA user_version` counts the ones already applied.
+const MIGRATIONS: &[&str] = &[
+    // Bumped when the password or role changes, so sessions started before are signed out
+    "ALTER TABLE users ADD COLUMN session_version INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// Accounts and API tokens, stored in SQLite apart from the catalog so it can be shared without
+/// the password hashes
+#[derive(Debug)]
+pub struct Accounts {
+    connection: Mutex<Connection>,
+}
+
+impl Accounts {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.execute_batch(&format!("PRAGMA user_version = {};", applied + 1))?;
+        }
+
+        Ok(Accounts {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Creates the first admin, unless an account was created in the meantime. Returns whether it
+    /// was created.
+    pub fn create_first_admin(&self, name: &str, password: &str) -> Result<bool, ServerError> {
+        let password_hash = hash(password)?;
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let count: i64 =
+            transaction.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+        if count > 0 {
+            return Ok(false);
+        }
+        transaction.execute(
+            "INSERT INTO users (name, password_hash, role) VALUES (?1, ?2, ?3)",
+            params![name, password_hash, Role::Admin.id()],
+        )?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    /// Before the first admin signs up
+    pub fn is_empty(&self) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let count: i64 =
+            connection.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+
+    pub fn get(&self, name: &str) -> rusqlite::Result<Option<Account>> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT name, role, session_version FROM users WHERE name = ?1",
+                [name],
+                account_from_row,
+            )
+            .optional()
+    }
+
+    pub fn list(&self) -> rusqlite::Result<Vec<Account>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT name, role, session_version FROM users ORDER BY name")?;
+        let accounts = statement.query_map([], account_from_row)?;
+        accounts.collect()
+    }
+
+    /// Creates the account, or changes its password and role if it already exists, which signs it
+    /// out everywhere. The last admin can't become a viewer.
+    pub fn save(&self, name: &str, password: &str, role: Role) -> Result<(), ServerError> {
+        let password_hash = hash(password)?;
+        let connection = self.connection.lock().unwrap();
+        if role != Role::Admin && is_last_admin(&connection, name)? {
+            return Err(ServerError::Forbidden(
+                "the last admin can't become a viewer".into(),
+            ));
+        }
+        connection.execute(
+            "INSERT INTO users (name, password_hash, role) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET
+                password_hash = ?2, role = ?3, session_version = session_version + 1",
+            params![name, password_hash, role.id()],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the account and its tokens, the last admin can't be removed
+    pub fn remove(&self, name: &str) -> Result<bool, ServerError> {
+        let connection = self.connection.lock().unwrap();
+        if is_last_admin(&connection, name)? {
+            return Err(ServerError::Forbidden(
+                "the last admin can't be removed".into(),
+            ));
+        }
+        let removed = connection.execute("DELETE FROM users WHERE name = ?1", [name])?;
+        Ok(removed > 0)
+    }
+
+    /// The account if the password is right
+    pub fn verify_password(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<Account>, ServerError> {
+        let stored: Option<(String, Account)> = {
+            let connection = self.connection.lock().unwrap();
+            connection
+                .query_row(
+                    "SELECT password_hash, name, role, session_version FROM users WHERE name = ?1",
+                    [name],
+                    |row| Ok((row.get("password_hash")?, account_from_row(row)?)),
+                )
+                .optional()?
+        };
+
+        Ok(match stored {
+            Some((password_hash, account)) if verify(password, &password_hash)? => Some(account),
+            _ => None,
+        })
+    }
+
+    pub fn tokens(&self) -> rusqlite::Result<Vec<ApiToken>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, user, label, created_at, last_used FROM tokens ORDER BY user, created_at",
+        )?;
+        let tokens = statement.query_map([], |row| {
+            Ok(ApiToken {
+                id: row.get("id")?,
+                user: row.get("user")?,
+                label: row.get("label")?,
+                created_at: row.get("created_at")?,
+                last_used: row.get("last_used")?,
+            })
+        })?;
+        tokens.collect()
+    }
+
+    /// Creates a token acting as `user`, returns it whole since only its hash is kept
+    pub fn create_token(&self, user: &str, label: &str) -> Result<String, ServerError> {
+        let id = random_hex(4);
+        let secret = random_hex(16);
+        let secret_hash = hash(&secret)?;
+
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO tokens (id, user, secret_hash, label, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, user, secret_hash, label, now()],
+        )?;
+        Ok(format!("{id}.{secret}"))
+    }
+
+    pub fn revoke_token(&self, id: &str) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection.execute("DELETE FROM tokens WHERE id = ?1", [id])?;
+        Ok(removed > 0)
+    }
+
+    /// The account a token acts as, if the token is valid
+    pub fn verify_token(&self, token: &str) -> Result<Option<Account>, ServerError> {
+        let Some((id, secret)) = token.split_once('.') else {
+            return Ok(None);
+        };
+        let stored: Option<(String, Account)> = {
+            let connection = self.connection.lock().unwrap();
+            connection
+                .query_row(
+                    "SELECT tokens.secret_hash, users.name, users.role, users.session_version
+                     FROM tokens JOIN users ON users.name = tokens.user WHERE tokens.id = ?1",
+                    [id],
+                    |row| Ok((row.get("secret_hash")?, account_from_row(row)?)),
+                )
+                .optional()?
+        };
+
+        let Some((secret_hash, account)) = stored else {
+            return Ok(None);
+        };
+        if !verify(secret, &secret_hash)? {
+            return Ok(None);
+        }
+        self.connection.lock().unwrap().execute(
+            "UPDATE tokens SET last_used = ?2 WHERE id = ?1",
+            params![id, now()],
+        )?;
+        Ok(Some(account))
+    }
+
+    /// The account of a session cookie, if it's still signed in
+    fn verify_session(&self, session: &str) -> Result<Option<Account>, ServerError> {
+        let mut parts = session.rsplitn(3, ':');
+        let (Some(signed_in_at), Some(version), Some(name)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Ok(None);
+        };
+        let (Ok(signed_in_at), Ok(version)) = (signed_in_at.parse::<i64>(), version.parse::<i64>())
+        else {
+            return Ok(None);
+        };
+        if now() - signed_in_at > SESSION_LENGTH.whole_seconds() {
+            return Ok(None);
+        }
+        Ok(self
+            .get(name)?
+            .filter(|account| account.session_version == version))
+    }
+}
+
+fn is_last_admin(connection: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let (is_admin, other_admins): (bool, i64) = connection.query_row(
+        "SELECT
+            EXISTS(SELECT 1 FROM users WHERE name = ?1 AND role = 'admin'),
+            (SELECT COUNT(*) FROM users WHERE role = 'admin' AND name != ?1)",
+        [name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(is_admin && other_admins == 0)
+}
+
+fn account_from_row(row: &Row<'_>) -> rusqlite::Result<Account> {
+    let role: String = row.get("role")?;
+    Ok(Account {
+        name: row.get("name")?,
+        role: Role::from_id(&role),
+        session_version: row.get("session_version")?,
+    })
+}
+
+fn hash(secret: &str) -> Result<String, ServerError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| ServerError::Other(format!("failed to hash a password: {err}")))
+}
+
+fn verify(secret: &str, hash: &str) -> Result<bool, ServerError> {
+    let hash = PasswordHash::new(hash)
+        .map_err(|err| ServerError::Other(format!("stored password hash is invalid: {err}")))?;
+    Ok(Argon2::default()
+        .verify_password(secret.as_bytes(), &hash)
+        .is_ok())
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Value of the session cookie of an account that signed in at `signed_in_at`
+fn session(account: &Account, signed_in_at: i64) -> String {
+    format!(
+        "{}:{}:{signed_in_at}",
+        account.name, account.session_version
+    )
+}
+
+pub fn sign_in(cookies: &CookieJar<'_>, account: &Account) {
+    cookies.add_private(
+        Cookie::build((SESSION_COOKIE, session(account, now())))
+            .same_site(SameSite::Strict)
+            .max_age(SESSION_LENGTH),
+    );
+}
+
+pub fn sign_out(cookies: &CookieJar<'_>) {
+    cookies.remove_private(SESSION_COOKIE);
+}
+
+/// Any signed in account, from an API token or the session cookie
+#[derive(Debug)]
+pub struct User(pub Account);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(accounts) = request.rocket().state::<Accounts>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        let account = match bearer {
+            Some(token) => accounts.verify_token(token.trim()),
+            // Sessions of accounts removed or changed since signing in are signed out
+            None => match request.cookies().get_private(SESSION_COOKIE) {
+                Some(cookie) => accounts.verify_session(cookie.value()),
+                None => Ok(None),
+            },
+        };
+
+        match account {
+            Ok(Some(account)) => Outcome::Success(User(account)),
+            Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+            Err(err) => {
+                eprintln!("> Failed to check the account of a request");
+                eprintln!("{err}");
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+/// Signed in account with the admin role, needed by every route that changes something
+#[derive(Debug)]
+pub struct Admin(pub Account);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request.guard::<User>().await {
+            Outcome::Success(User(account)) if account.role == Role::Admin => {
+                Outcome::Success(Admin(account))
+            }
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+            Outcome::Error(error) => Outcome::Error(error),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
+
+/// Answer to requests without the account they need. Pages go to the login page, HTMX requests
+/// are redirected there too or get an error banner when the account isn't allowed. Scripts only
+/// get the status, a redirect would look like a success to them.
+#[derive(Debug)]
+pub struct AuthFailure(pub Status);
+
+impl<'r> Responder<'r, 'static> for AuthFailure {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let htmx = request.headers().contains("HX-Request");
+        let browser = request
+            .accept()
+            .is_some_and(|accept| accept.preferred().media_type().is_html());
+        let forbidden = self.0 == Status::Forbidden;
+        match (forbidden, htmx, browser) {
+            (true, true, _) => (
+                Status::Ok,
+                oob::error_banner("Not allowed", "Only admins can make changes."),
+            )
+                .respond_to(request),
+            (true, false, true) => (Status::Forbidden, errors::e403()).respond_to(request),
+            (false, true, _) => Response::build()
+                .status(Status::Ok)
+                .raw_header("HX-Redirect", "/login")
+                .ok(),
+            (false, false, true) => Redirect::to("/login").respond_to(request),
+            (_, false, false) => Response::build().status(self.0).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_end_when_the_account_changes() {
+        let accounts = Accounts::open(Path::new(":memory:")).unwrap();
+        accounts.save("admin", "password", Role::Admin).unwrap();
+        accounts.save("viewer", "password", Role::Viewer).unwrap();
+        let signed_in = |name: &str, signed_in_at: i64| {
+            let account = accounts.get(name).unwrap().unwrap();
+            session(&account, signed_in_at)
+        };
+        let is_valid = |session: &str| accounts.verify_session(session).unwrap().is_some();
+
+        let session = signed_in("viewer", now());
+        assert!(is_valid(&session));
+        assert!(!is_valid("viewer"));
+        assert!(!is_valid(&signed_in(
+            "viewer",
+            now() - SESSION_LENGTH.whole_seconds() - 1
+        )));
+
+        accounts
+            .save("viewer", "new password", Role::Viewer)
+            .unwrap();
+        assert!(!is_valid(&session));
+
+        let session = signed_in("viewer", now());
+        accounts.remove("viewer").unwrap();
+        assert!(!is_valid(&session));
+    }
+}
@@ -0,0 +1,439 @@
+//! Just enough of iCalendar (RFC 5545) to list the events of a local `.ics` file on the dashboard.
+//!
+//! Only the start, summary and recurrence of each `VEVENT` are read. Daily and weekly `RRULE`s are
+//! repeated over the agenda, other recurring events show their first occurrence and `EXDATE`s are
+//! ignored. Times with a `TZID` are taken as local time since there's no time zone database to
+//! convert them with.
+
+use chrono::{
+    DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc, Weekday,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Local time, midnight for all-day events
+    pub start: NaiveDateTime,
+    pub all_day: bool,
+    pub summary: String,
+    /// Missing for events happening once, and for rules that aren't supported
+    pub recurrence: Option<Recurrence>,
+}
+
+/// The supported part of an `RRULE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recurrence {
+    pub weekly: bool,
+    /// Days or weeks between occurrences
+    pub interval: u32,
+    /// Most occurrences, counting the first one
+    pub count: Option<u32>,
+    /// Last time an occurrence can start, included
+    pub until: Option<NaiveDateTime>,
+    /// Days of the week the event happens on, the day of the start for weekly events when empty
+    pub by_day: Vec<Weekday>,
+}
+
+/// Events of the calendar, in the order of the file. Events without a valid start are skipped.
+pub fn parse(ics: &str) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut current: Option<VEvent> = None;
+
+    for line in unfold(ics) {
+        let Some((property, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = property.split(';').next().unwrap_or(property);
+        match (name.to_ascii_uppercase().as_str(), &mut current) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                current = Some(VEvent::default());
+            }
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VEVENT") => {
+                let Some(event) = current.take() else {
+                    continue;
+                };
+                if let Some((start, all_day)) = event.start {
+                    events.push(Event {
+                        start,
+                        all_day,
+                        summary: event.summary,
+                        recurrence: event.rule.as_deref().and_then(parse_rule),
+                    });
+                }
+            }
+            ("DTSTART", Some(event)) => event.start = parse_start(value),
+            ("SUMMARY", Some(event)) => event.summary = unescape(value),
+            ("RRULE", Some(event)) => event.rule = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    events
+}
+
+/// Properties of the `VEVENT` being read
+#[derive(Default)]
+struct VEvent {
+    start: Option<(NaiveDateTime, bool)>,
+    summary: String,
+    rule: Option<String>,
+}
+
+/// Occurrences of events starting in the `days` days from `from`, sorted by start
+pub fn agenda(events: &[Event], from: NaiveDate, days: u32) -> Vec<Event> {
+    let start = from.and_time(NaiveTime::MIN);
+    let end = start + TimeDelta::days(days as i64);
+    let mut agenda: Vec<Event> = events
+        .iter()
+        .flat_map(|event| {
+            occurrences(event, end)
+                .into_iter()
+                .filter(|occurrence| *occurrence >= start)
+                .map(|occurrence| Event {
+                    start: occurrence,
+                    ..event.clone()
+                })
+        })
+        .collect();
+    agenda.sort_by_key(|event| event.start);
+    agenda
+}
+
+/// Starts of the event before `end`
+fn occurrences(event: &Event, end: NaiveDateTime) -> Vec<NaiveDateTime> {
+    let Some(rule) = &event.recurrence else {
+        return match event.start < end {
+            true => vec![event.start],
+            false => Vec::new(),
+        };
+    };
+
+    let interval = rule.interval.max(1) as i64;
+    let time = event.start.time();
+    let first = event.start.date();
+    // Weekly events repeat each of their days, starting from the Monday of the first week
+    let (period, days) = match (rule.weekly, rule.by_day.is_empty()) {
+        (false, _) => (TimeDelta::try_days(interval), vec![first]),
+        (true, true) => (TimeDelta::try_weeks(interval), vec![first]),
+        (true, false) => {
+            let monday = first - TimeDelta::days(first.weekday().num_days_from_monday() as i64);
+            let mut days: Vec<NaiveDate> = rule
+                .by_day
+                .iter()
+                .map(|day| monday + TimeDelta::days(day.num_days_from_monday() as i64))
+                .collect();
+            days.sort();
+            days.dedup();
+            (TimeDelta::try_weeks(interval), days)
+        }
+    };
+    let Some(period) = period else {
+        return Vec::new();
+    };
+
+    let mut occurrences = Vec::new();
+    let mut count = 0;
+    for i in 0.. {
+        // Intervals so long they overflow the calendar have no later occurrences
+        let Some(period_start) = period.checked_mul(i) else {
+            break;
+        };
+        for day in &days {
+            let Some(date) = day.checked_add_signed(period_start) else {
+                return occurrences;
+            };
+            let occurrence = date.and_time(time);
+            // Daily events can be limited to some days of the week
+            let skipped_day = !rule.weekly
+                && !rule.by_day.is_empty()
+                && !rule.by_day.contains(&occurrence.weekday());
+            if occurrence < event.start || skipped_day {
+                continue;
+            }
+            let counted_out = rule.count.is_some_and(|most| count >= most);
+            let ended = rule.until.is_some_and(|until| occurrence > until);
+            if occurrence >= end || counted_out || ended {
+                return occurrences;
+            }
+            occurrences.push(occurrence);
+            count += 1;
+        }
+    }
+    occurrences
+}
+
+/// Joins folded lines, which continue on the next line after a space or a tab
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Daily and weekly rules, others aren't supported
+fn parse_rule(value: &str) -> Option<Recurrence> {
+    let mut recurrence = Recurrence {
+        weekly: false,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+    };
+    let mut frequency = None;
+    for part in value.split(';') {
+        let (name, value) = part.split_once('=')?;
+        match name.to_ascii_uppercase().as_str() {
+            "FREQ" => frequency = Some(value.to_ascii_uppercase()),
+            "INTERVAL" => recurrence.interval = value.parse().ok()?,
+            "COUNT" => recurrence.count = Some(value.parse().ok()?),
+            "UNTIL" => {
+                // A date ends the event on that day
+                let (until, all_day) = parse_start(value)?;
+                recurrence.until = Some(match all_day {
+                    true => until.date().and_time(NaiveTime::from_hms_opt(23, 59, 59)?),
+                    false => until,
+                });
+            }
+            "BYDAY" => {
+                for day in value.split(',') {
+                    // Days with a position like 1MO only make sense for monthly rules
+                    recurrence.by_day.push(weekday(day)?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match frequency.as_deref() {
+        Some("DAILY") => Some(recurrence),
+        Some("WEEKLY") => Some(Recurrence {
+            weekly: true,
+            ..recurrence
+        }),
+        _ => None,
+    }
+}
+
+fn weekday(code: &str) -> Option<Weekday> {
+    Some(match code.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// `DTSTART` as local time, and whether it's a whole day, which is a date without a time
+fn parse_start(value: &str) -> Option<(NaiveDateTime, bool)> {
+    let value = value.trim();
+    if !value.contains('T') {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+        return Some((date.and_time(NaiveTime::MIN), true));
+    }
+
+    match value.strip_suffix('Z') {
+        Some(utc) => {
+            let start = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+            let start = DateTime::<Utc>::from_naive_utc_and_offset(start, Utc);
+            Some((start.with_timezone(&Local).naive_local(), false))
+        }
+        None => Some((
+            NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?,
+            false,
+        )),
+    }
+}
+
+/// Undoes the escaping of text values
+fn unescape(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        // Line breaks don't fit in the agenda's single line per event
+        match chars.next() {
+            Some('n' | 'N') => text.push(' '),
+            Some(escaped) => text.push(escaped),
+            None => {}
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str, time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn day(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn calendar(events: &[&str]) -> String {
+        let events: Vec<String> = events
+            .iter()
+            .map(|event| format!("BEGIN:VEVENT\r\n{event}\r\nEND:VEVENT\r\n"))
+            .collect();
+        format!("BEGIN:VCALENDAR\r\n{}END:VCALENDAR\r\n", events.concat())
+    }
+
+    /// Starts of the occurrences in the agenda
+    fn starts(ics: &str, from: &str, days: u32) -> Vec<NaiveDateTime> {
+        agenda(&parse(ics), day(from), days)
+            .into_iter()
+            .map(|event| event.start)
+            .collect()
+    }
+
+    #[test]
+    fn folded_lines_are_joined() {
+        let ics = calendar(&[
+            "DTSTART:20260310T090000\r\nSUMMARY:Dentist \r\n appointment\\, then\r\n\tlunch",
+        ]);
+        let events = parse(&ics);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].summary, "Dentist appointment, thenlunch");
+    }
+
+    #[test]
+    fn dates_are_all_day() {
+        let events = parse(&calendar(&[
+            "DTSTART;VALUE=DATE:20260310\r\nSUMMARY:Holiday",
+        ]));
+        assert_eq!(events[0].start, at("2026-03-10", "00:00"));
+        assert!(events[0].all_day);
+    }
+
+    #[test]
+    fn times_with_a_time_zone_are_local() {
+        let events = parse(&calendar(&[
+            "DTSTART;TZID=Europe/Paris:20260310T090000\r\nSUMMARY:Local",
+            "DTSTART:20260310T090000Z\r\nSUMMARY:UTC",
+        ]));
+        assert_eq!(events[0].start, at("2026-03-10", "09:00"));
+        assert!(!events[0].all_day);
+
+        let utc = DateTime::<Utc>::from_naive_utc_and_offset(at("2026-03-10", "09:00"), Utc);
+        assert_eq!(events[1].start, utc.with_timezone(&Local).naive_local());
+    }
+
+    #[test]
+    fn events_without_a_start_are_skipped() {
+        let events = parse(&calendar(&[
+            "SUMMARY:Someday",
+            "DTSTART:soon\r\nSUMMARY:Soon",
+        ]));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn agenda_only_has_events_in_the_window() {
+        let ics = calendar(&[
+            "DTSTART:20260309T235900\r\nSUMMARY:Before",
+            "DTSTART:20260312T090000\r\nSUMMARY:Later",
+            "DTSTART:20260310T000000\r\nSUMMARY:First",
+            "DTSTART:20260317T000000\r\nSUMMARY:After",
+        ]);
+        let summaries: Vec<String> = agenda(&parse(&ics), day("2026-03-10"), 7)
+            .into_iter()
+            .map(|event| event.summary)
+            .collect();
+        assert_eq!(summaries, ["First", "Later"]);
+    }
+
+    #[test]
+    fn daily_events_repeat_over_the_agenda() {
+        let ics =
+            calendar(&["DTSTART:20260101T080000\r\nRRULE:FREQ=DAILY;INTERVAL=2\r\nSUMMARY:Run"]);
+        assert_eq!(
+            starts(&ics, "2026-03-10", 5),
+            [
+                at("2026-03-10", "08:00"),
+                at("2026-03-12", "08:00"),
+                at("2026-03-14", "08:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn daily_events_can_skip_days_of_the_week() {
+        // 2026-03-13 is a Friday
+        let ics = calendar(&[
+            "DTSTART:20260302T080000\r\nRRULE:FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR\r\nSUMMARY:Work",
+        ]);
+        assert_eq!(
+            starts(&ics, "2026-03-13", 4),
+            [at("2026-03-13", "08:00"), at("2026-03-16", "08:00")]
+        );
+    }
+
+    #[test]
+    fn weekly_events_repeat_on_their_days() {
+        // 2026-01-05 is a Monday
+        let ics = calendar(&[
+            "DTSTART:20260105T180000\r\nRRULE:FREQ=WEEKLY\r\nSUMMARY:Choir",
+            "DTSTART:20260107T070000\r\nRRULE:FREQ=WEEKLY;INTERVAL=2;BYDAY=WE,FR\r\nSUMMARY:Swim",
+        ]);
+        assert_eq!(
+            starts(&ics, "2026-03-09", 14),
+            [
+                at("2026-03-09", "18:00"),
+                at("2026-03-16", "18:00"),
+                at("2026-03-18", "07:00"),
+                at("2026-03-20", "07:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_stop_after_count_or_until() {
+        let ics = calendar(&[
+            "DTSTART:20260309T090000\r\nRRULE:FREQ=DAILY;COUNT=3\r\nSUMMARY:Course",
+            "DTSTART:20260309T100000\r\nRRULE:FREQ=WEEKLY;UNTIL=20260316\r\nSUMMARY:Class",
+        ]);
+        assert_eq!(
+            starts(&ics, "2026-03-10", 14),
+            [
+                at("2026-03-10", "09:00"),
+                at("2026-03-11", "09:00"),
+                at("2026-03-16", "10:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_stop_when_the_interval_overflows() {
+        let ics = calendar(&[
+            "DTSTART:20260310T090000\r\nRRULE:FREQ=DAILY;INTERVAL=100000000\r\nSUMMARY:Rarely",
+            "DTSTART:20260310T100000\r\nRRULE:FREQ=WEEKLY;INTERVAL=4294967295;BYDAY=TU,WE\r\nSUMMARY:Never again",
+        ]);
+        assert_eq!(
+            starts(&ics, "2026-03-01", 60),
+            [
+                at("2026-03-10", "09:00"),
+                at("2026-03-10", "10:00"),
+                at("2026-03-11", "10:00")
+            ]
+        );
+    }
+
+    #[test]
+    fn other_rules_show_the_first_occurrence() {
+        let ics = calendar(&["DTSTART:20260310T090000\r\nRRULE:FREQ=MONTHLY\r\nSUMMARY:Rent"]);
+        assert!(parse(&ics)[0].recurrence.is_none());
+        assert_eq!(starts(&ics, "2026-03-01", 60), [at("2026-03-10", "09:00")]);
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use kindle_manager::image_converter::{self, Background, ConvertOptions, Palette};
+use md5::{Digest, Md5};
+use rocket::serde::{json, Deserialize, Serialize};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+/// Settings an image was converted with, kept so it can be converted again later
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ConversionSettings {
+    pub horizontal: bool,
+    pub background: String,
+    pub fit: String,
+    /// Relative to the stored original, so already rotated for horizontal images
+    pub focal_point: Option<(f32, f32)>,
+    pub dither: String,
+    pub brightness: i32,
+    pub contrast: i32,
+    pub gamma: f32,
+    pub sharpen: f32,
+}
+
+/// Used for images converted before settings were kept, and for the `conversion` setting's defaults
+impl Default for ConversionSettings {
+    fn default() -> Self {
+        ConversionSettings {
+            horizontal: false,
+            background: "white".into(),
+            fit: "fit".into(),
+            focal_point: None,
+            dither: "floyd_steinberg".into(),
+            brightness: 0,
+            contrast: 0,
+            gamma: 1.0,
+            sharpen: 0.0,
+        }
+    }
+}
+
+impl ConversionSettings {
+    /// Focal point after turning the stored original to `horizontal`, see
+    /// [`image_converter::reorient_original`]
+    pub fn reoriented_focal_point(&self, horizontal: bool) -> Option<(f32, f32)> {
+        self.focal_point
+            .map(|(x, y)| match (self.horizontal, horizontal) {
+                (false, true) => (1.0 - y, x),
+                (true, false) => (y, 1.0 - x),
+                _ => (x, y),
+            })
+    }
+
+    pub fn options(&self, palette: Option<Palette>) -> ConvertOptions {
+        ConvertOptions {
+            background: self.background.parse().unwrap_or(Background::White),
+            fit: self.fit.parse().unwrap_or_default(),
+            focal_point: self.focal_point,
+            palette,
+            dither: self.dither.parse().unwrap_or_default(),
+            brightness: self.brightness,
+            contrast: self.contrast,
+            gamma: self.gamma.clamp(0.1, 10.0),
+            sharpen: self.sharpen.clamp(0.0, 10.0),
+        }
+    }
+}
+
+/// Everything known about a converted image on the server
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ImageRecord {
+    /// Name of the converted image, the same on the server and on the Kindle
+    pub name: String,
+    /// Name of the file as it was uploaded
+    pub original_name: String,
+    /// Seconds since the Unix epoch
+    pub uploaded_at: i64,
+    /// Missing for images that were already on the server or pulled from the Kindle
+    pub settings: Option<ConversionSettings>,
+    /// MD5 of the converted image
+    pub checksum: String,
+    /// Size of the original image
+    pub width: u32,
+    pub height: u32,
+    pub tags: Vec<String>,
+    /// Last time it was shown on any device
+    pub last_displayed: Option<i64>,
+    /// Devices that have the image, by name
+    pub devices: Vec<String>,
+}
+
+/// How [`Catalog::list`] orders images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+    #[default]
+    Name,
+    /// Newest first
+    Uploaded,
+    /// Most recently shown first, never shown ones last
+    Displayed,
+}
+
+impl SortBy {
+    pub const ALL: [SortBy; 3] = [SortBy::Name, SortBy::Uploaded, SortBy::Displayed];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Uploaded => "uploaded",
+            SortBy::Displayed => "displayed",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortBy::Name => "Name",
+            SortBy::Uploaded => "Newest",
+            SortBy::Displayed => "Last shown",
+        }
+    }
+
+    fn order_by(&self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Uploaded => "uploaded_at DESC, name",
+            SortBy::Displayed => "last_displayed IS NULL, last_displayed DESC, name",
+        }
+    }
+}
+
+/// Parses the `sort` query parameter, unknown values sort by name
+impl From<Option<&str>> for SortBy {
+    fn from(id: Option<&str>) -> Self {
+        SortBy::ALL
+            .into_iter()
+            .find(|sort| Some(sort.id()) == id)
+            .unwrap_or_default()
+    }
+}
+
+/// First version of the schema, as the first catalogs were created. New databases start from it
+/// and go through every migration too, so they end up like migrated ones. It's never changed,
+/// changes go in [`MIGRATIONS`].
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS images (
+    name TEXT PRIMARY KEY,
+    original_name TEXT NOT NULL,
+    uploaded_at INTEGER NOT NULL,
+    settings TEXT,
+    checksum TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    last_displayed INTEGER,
+    on_kindle INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS tags (
+    image TEXT NOT NULL REFERENCES images(name) ON DELETE CASCADE ON UPDATE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (image, tag)
+);
+";
+
+/// Changes to the schema, applied in order. `PRAGMA user_version` counts the ones already applied.
+const MIGRATIONS: &[&str] = &[
+    // Checksum the server and the Kindle both had after the last transfer, tells which side
+    // changed an image since
+    "ALTER TABLE images ADD COLUMN synced_checksum TEXT;",
+    // State of each image on each device, the single Kindle from before is named "kindle". Rows
+    // outlive their image, so devices still holding a deleted image know to delete it when synced.
+    "CREATE TABLE device_images (
+        device TEXT NOT NULL,
+        image TEXT NOT NULL,
+        on_kindle INTEGER NOT NULL DEFAULT 0,
+        synced_checksum TEXT,
+        last_displayed INTEGER,
+        PRIMARY KEY (device, image)
+    );
+    INSERT INTO device_images (device, image, on_kindle, synced_checksum, last_displayed)
+        SELECT 'kindle', name, on_kindle, synced_checksum, last_displayed FROM images
+        WHERE on_kindle OR synced_checksum IS NOT NULL;
+    ALTER TABLE images DROP COLUMN on_kindle;
+    ALTER TABLE images DROP COLUMN synced_checksum;",
+];
+
+/// Metadata of every converted image, stored in SQLite
+#[derive(Debug)]
+pub struct Catalog {
+    connection: Mutex<Connection>,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.execute_batch(&format!("PRAGMA user_version = {};", applied + 1))?;
+        }
+
+        Ok(Catalog {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Adds every image in `converted` that isn't in the catalog yet, for images converted before
+    /// the catalog existed. Their originals in `originals` are used for the dimensions if found.
+    pub fn import_untracked(&self, converted: &Path, originals: &Path) -> io::Result<usize> {
+        let known = self.names().map_err(io::Error::other)?;
+
+        let mut imported = 0;
+        for entry in fs::read_dir(converted)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if known.contains(&name) || !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let (width, height) = image_converter::dimensions(&originals.join(&name))
+                .or_else(|_| image_converter::dimensions(&entry.path()))
+                .unwrap_or((0, 0));
+            let record = ImageRecord {
+                uploaded_at: entry.metadata()?.modified().map(unix_time).unwrap_or(0),
+                checksum: checksum(&entry.path())?,
+                width,
+                height,
+                ..ImageRecord::new(&name)
+            };
+            self.insert(&record).map_err(io::Error::other)?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// Adds the image, replacing any previous image with the same name. What devices know about it
+    /// is kept, so they can tell they have an older version.
+    pub fn insert(&self, record: &ImageRecord) -> rusqlite::Result<()> {
+        let settings = record
+            .settings
+            .as_ref()
+            .map(|settings| json::to_string(settings).expect("Settings should always serialize"));
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO images
+                (name, original_name, uploaded_at, settings, checksum, width, height, last_displayed)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT(name) DO UPDATE SET
+                    original_name = ?2, uploaded_at = ?3, settings = ?4, checksum = ?5,
+                    width = ?6, height = ?7, last_displayed = ?8",
+            params![
+                record.name,
+                record.original_name,
+                record.uploaded_at,
+                settings,
+                record.checksum,
+                record.width,
+                record.height,
+                record.last_displayed,
+            ],
+        )?;
+        transaction.execute("DELETE FROM tags WHERE image = ?1", [&record.name])?;
+        for tag in &record.tags {
+            transaction.execute(
+                "INSERT OR IGNORE INTO tags (image, tag) VALUES (?1, ?2)",
+                [&record.name, tag],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    pub fn get(&self, name: &str) -> rusqlite::Result<Option<ImageRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let record = connection
+            .query_row("SELECT * FROM images WHERE name = ?1", [name], from_row)
+            .optional()?;
+        record
+            .map(|record| with_details(&connection, record))
+            .transpose()
+    }
+
+    /// Every image, optionally only the ones with `tag`
+    pub fn list(&self, sort: SortBy, tag: Option<&str>) -> rusqlite::Result<Vec<ImageRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let query = format!(
+            "SELECT * FROM images
+                WHERE ?1 IS NULL OR name IN (SELECT image FROM tags WHERE tag = ?1)
+                ORDER BY {}",
+            sort.order_by()
+        );
+        let mut statement = connection.prepare(&query)?;
+        let records = statement
+            .query_map([tag], from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        records
+            .into_iter()
+            .map(|record| with_details(&connection, record))
+            .collect()
+    }
+
+    pub fn names(&self) -> rusqlite::Result<HashSet<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name FROM images")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()?;
+        Ok(names)
+    }
+
+    /// Every tag in use, sorted
+    pub fn tags(&self) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT DISTINCT tag FROM tags ORDER BY tag")?;
+        let tags = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tags)
+    }
+
+    /// Renames the image on the server and on every device but `not_renamed`, whose files still
+    /// have the old name
+    pub fn rename(&self, name: &str, new_name: &str, not_renamed: &[&str]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "UPDATE images SET name = ?2 WHERE name = ?1",
+            [name, new_name],
+        )?;
+        let devices = transaction
+            .prepare("SELECT device FROM device_images WHERE image = ?1")?
+            .query_map([name], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        for device in devices
+            .iter()
+            .filter(|device| !not_renamed.contains(&device.as_str()))
+        {
+            // Replaces what the device remembers of an earlier image with the new name
+            transaction.execute(
+                "UPDATE OR REPLACE device_images SET image = ?3 WHERE device = ?1 AND image = ?2",
+                [device, name, new_name],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Removes the image from the server, devices still remember having it
+    pub fn remove(&self, name: &str) -> rusqlite::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM images WHERE name = ?1", [name])?;
+        Ok(())
+    }
+
+    pub fn set_tags(&self, name: &str, tags: &[String]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM tags WHERE image = ?1", [name])?;
+        for tag in tags {
+            transaction.execute(
+                "INSERT OR IGNORE INTO tags (image, tag) VALUES (?1, ?2)",
+                [name, tag],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Stores what the image was converted with again
+    pub fn set_conversion(
+        &self,
+        name: &str,
+        settings: &ConversionSettings,
+        checksum: &str,
+    ) -> rusqlite::Result<()> {
+        let settings = json::to_string(settings).expect("Settings should always serialize");
+        self.connection.lock().unwrap().execute(
+            "UPDATE images SET settings = ?2, checksum = ?3 WHERE name = ?1",
+            [name, &settings, checksum],
+        )?;
+        Ok(())
+    }
+
+    /// Updates the checksum of an image whose converted file changed
+    pub fn set_checksum(&self, name: &str, checksum: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE images SET checksum = ?2 WHERE name = ?1",
+            [name, checksum],
+        )?;
+        Ok(())
+    }
+
+    /// Image most recently set on the device from the server, likely the one on screen
+    pub fn last_displayed(&self, device: &str) -> rusqlite::Result<Option<String>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT image FROM device_images WHERE device = ?1 AND last_displayed IS NOT NULL
+                    ORDER BY last_displayed DESC LIMIT 1",
+                [device],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Remembers the image was just shown on the device
+    pub fn mark_displayed(&self, device: &str, name: &str) -> rusqlite::Result<()> {
+        let now = unix_time(SystemTime::now());
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "UPDATE images SET last_displayed = ?2 WHERE name = ?1",
+            params![name, now],
+        )?;
+        transaction.execute(
+            "INSERT INTO device_images (device, image, on_kindle, last_displayed)
+                VALUES (?1, ?2, 1, ?3)
+                ON CONFLICT(device, image) DO UPDATE SET on_kindle = 1, last_displayed = ?3",
+            params![device, name, now],
+        )?;
+        transaction.commit()
+    }
+
+    /// Remembers the image was just pushed to or removed from the device by the server, so the
+    /// device now has the same version as the server or none
+    pub fn set_on_kindle(&self, device: &str, name: &str, on_kindle: bool) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        if !on_kindle {
+            connection.execute(
+                "DELETE FROM device_images WHERE device = ?1 AND image = ?2",
+                [device, name],
+            )?;
+            return Ok(());
+        }
+        connection.execute(
+            "INSERT INTO device_images (device, image, on_kindle, synced_checksum)
+                SELECT ?1, name, 1, checksum FROM images WHERE name = ?2
+                ON CONFLICT(device, image) DO UPDATE SET
+                    on_kindle = 1, synced_checksum = excluded.synced_checksum",
+            [device, name],
+        )?;
+        Ok(())
+    }
+
+    /// Checksum of every image when it was last the same on the server and the device
+    pub fn synced_checksums(&self, device: &str) -> rusqlite::Result<HashMap<String, String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT image, synced_checksum FROM device_images
+                WHERE device = ?1 AND synced_checksum IS NOT NULL",
+        )?;
+        let synced = statement
+            .query_map([device], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+        Ok(synced)
+    }
+
+    /// Updates which images are on the device from a listing of its files
+    pub fn update_on_kindle(
+        &self,
+        device: &str,
+        kindle_images: &HashSet<String>,
+    ) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "UPDATE device_images SET on_kindle = 0 WHERE device = ?1",
+            [device],
+        )?;
+        for name in kindle_images {
+            transaction.execute(
+                "INSERT INTO device_images (device, image, on_kindle)
+                    SELECT ?1, name, 1 FROM images WHERE name = ?2
+                    ON CONFLICT(device, image) DO UPDATE SET on_kindle = 1",
+                [device, name],
+            )?;
+        }
+        transaction.commit()
+    }
+}
+
+impl ImageRecord {
+    /// Record for an image nothing is known about yet, uploaded now
+    pub fn new(name: &str) -> Self {
+        ImageRecord {
+            name: name.to_string(),
+            original_name: name.to_string(),
+            uploaded_at: unix_time(SystemTime::now()),
+            settings: None,
+            checksum: String::new(),
+            width: 0,
+            height: 0,
+            tags: Vec::new(),
+            last_displayed: None,
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// MD5 of a file as lowercase hex, like `md5sum` prints it
+pub fn checksum(path: &Path) -> io::Result<String> {
+    let digest = Md5::digest(fs::read(path)?);
+    Ok(digest.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn from_row(row: &Row<'_>) -> rusqlite::Result<ImageRecord> {
+    let settings: Option<String> = row.get("settings")?;
+    Ok(ImageRecord {
+        name: row.get("name")?,
+        original_name: row.get("original_name")?,
+        uploaded_at: row.get("uploaded_at")?,
+        settings: settings.and_then(|settings| json::from_str(&settings).ok()),
+        checksum: row.get("checksum")?,
+        width: row.get("width")?,
+        height: row.get("height")?,
+        tags: Vec::new(),
+        last_displayed: row.get("last_displayed")?,
+        devices: Vec::new(),
+    })
+}
+
+/// Fills in the tags and devices of a record
+fn with_details(connection: &Connection, mut record: ImageRecord) -> rusqlite::Result<ImageRecord> {
+    let mut statement = connection.prepare("SELECT tag FROM tags WHERE image = ?1 ORDER BY tag")?;
+    record.tags = statement
+        .query_map([&record.name], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut statement = connection.prepare(
+        "SELECT device FROM device_images WHERE image = ?1 AND on_kindle ORDER BY device",
+    )?;
+    record.devices = statement
+        .query_map([&record.name], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_catalogs_go_through_every_migration() {
+        let catalog = Catalog::open(Path::new(":memory:")).unwrap();
+        let connection = catalog.connection.lock().unwrap();
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let columns: Vec<String> = connection
+            .prepare("SELECT name FROM pragma_table_info('images')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert!(!columns.contains(&"on_kindle".to_string()));
+        assert!(!columns.contains(&"synced_checksum".to_string()));
+    }
+}
@@ -0,0 +1,385 @@
+//! Server settings, read from `Rocket.toml` and `ROCKET_*` environment variables through Rocket's
+//! figment, so `ROCKET_KINDLE_ADDRESS=192.168.1.10` overrides `kindle_address`. Every setting has a
+//! default and they're all checked at startup, see [`Config::validate`].
+//!
+//! Several Kindles are listed as `[[default.devices]]` tables, without any the `kindle_*` settings
+//! describe a single Kindle named "kindle".
+//!
+//! The dashboard's widgets are listed as `[[default.dashboard.widgets]]` tables, picked by `kind`.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::format::{Item, StrftimeItems};
+use kindle_manager::fbink::{RefreshMode, Waveform};
+use kindle_manager::image_converter::{Background, Dither, Fit, Palette};
+use kindle_manager::prep::REQUIRED_SERVICES;
+use kindle_manager::KindleModel;
+use rocket::serde::Deserialize;
+
+use crate::catalog::ConversionSettings;
+use crate::slideshow::RefreshSettings;
+use crate::ServerError;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    /// SSH address of the Kindle, or a host from `~/.ssh/config`
+    pub kindle_address: String,
+    /// Directory on the Kindle holding the images
+    pub kindle_location: String,
+    /// Local directory used as the Kindle's filesystem instead of connecting through SSH
+    pub local_kindle: Option<PathBuf>,
+    /// One of the known models (pw2, pw3, voyage, oasis, basic) or "auto"
+    pub kindle_model: String,
+    /// Services stopped by prep, the model's when missing
+    pub kindle_prep_services: Option<Vec<String>>,
+    /// Image whose gray levels replace the Kindle's palette
+    pub palette: Option<PathBuf>,
+    /// Uploaded originals, kept to convert them again
+    pub images_dir: PathBuf,
+    /// Converted images, the same as the ones on the Kindle
+    pub converted_dir: PathBuf,
+    pub slideshow_state: PathBuf,
+    pub dashboard_state: PathBuf,
+    pub playlists: PathBuf,
+    pub catalog: PathBuf,
+    pub accounts: PathBuf,
+    /// Admin created at startup while there are no accounts, along with `admin_password`
+    pub admin_name: String,
+    /// Without it, the admin can only be created from the server itself at `/login`
+    pub admin_password: Option<String>,
+    /// Settings the upload form starts with, also used by uploads that leave some out
+    pub conversion: ConversionSettings,
+    /// Longest image name, without the extension
+    pub max_filename_length: usize,
+    /// Kindles managed by the server, the first one is the default
+    pub devices: Vec<DeviceConfig>,
+    pub dashboard: DashboardConfig,
+}
+
+/// A Kindle in the `devices` list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceConfig {
+    /// Name shown in the device switcher and used by the API
+    pub name: String,
+    #[serde(default = "default_address")]
+    pub address: String,
+    #[serde(default = "default_location")]
+    pub location: String,
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Local directory used as this Kindle's filesystem
+    #[serde(default)]
+    pub local: Option<PathBuf>,
+    /// Services stopped by prep, the model's when missing
+    #[serde(default)]
+    pub prep_services: Option<Vec<String>>,
+}
+
+/// What the dashboard shows and how often it's redrawn
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DashboardConfig {
+    /// TrueType or OpenType font every widget is drawn with, checked when the dashboard starts
+    pub font: PathBuf,
+    /// Seconds between redraws, aligned on the clock so a minute refresh shows the right time
+    pub refresh: u64,
+    /// Draws the dashboard for a Kindle held sideways
+    pub horizontal: bool,
+    /// How the screen is refreshed on redraws, a partial refresh avoids flashing every minute
+    pub screen_refresh: RefreshSettings,
+    /// Widgets from top to bottom
+    pub widgets: Vec<Widget>,
+}
+
+impl Default for DashboardConfig {
+    fn default() -> Self {
+        DashboardConfig {
+            font: "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf".into(),
+            refresh: 60,
+            horizontal: false,
+            screen_refresh: RefreshSettings::default(),
+            widgets: vec![
+                Widget::Clock {
+                    time_format: default_time_format(),
+                    date_format: default_date_format(),
+                },
+                Widget::Battery,
+                Widget::Notes,
+                Widget::Todo,
+            ],
+        }
+    }
+}
+
+/// A part of the dashboard, see [`crate::dashboard`] for how each one is drawn
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "kind", rename_all = "snake_case")]
+pub enum Widget {
+    /// Current time and date, as chrono formats
+    Clock {
+        #[serde(default = "default_time_format")]
+        time_format: String,
+        #[serde(default = "default_date_format")]
+        date_format: String,
+    },
+    /// Battery charge of the Kindle
+    Battery,
+    /// Free text, edited on the dashboard panel
+    Notes,
+    /// To-do list, edited on the dashboard panel
+    Todo,
+    /// Upcoming events of a local ICS file
+    Calendar {
+        file: PathBuf,
+        /// Days shown, starting today
+        #[serde(default = "default_calendar_days")]
+        days: u32,
+    },
+}
+
+fn default_time_format() -> String {
+    "%H:%M".into()
+}
+
+fn default_date_format() -> String {
+    "%A %-d %B".into()
+}
+
+fn default_calendar_days() -> u32 {
+    7
+}
+
+fn default_address() -> String {
+    "kindle".into()
+}
+
+fn default_location() -> String {
+    "/mnt/us/images".into()
+}
+
+fn default_model() -> String {
+    "auto".into()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            kindle_address: "kindle".into(),
+            kindle_location: "/mnt/us/images".into(),
+            local_kindle: None,
+            kindle_model: "auto".into(),
+            kindle_prep_services: None,
+            palette: None,
+            images_dir: "images".into(),
+            converted_dir: "converted".into(),
+            slideshow_state: "slideshow.json".into(),
+            dashboard_state: "dashboard.json".into(),
+            playlists: "playlists.json".into(),
+            catalog: "catalog.db".into(),
+            accounts: "accounts.db".into(),
+            admin_name: "admin".into(),
+            admin_password: None,
+            conversion: ConversionSettings::default(),
+            max_filename_length: 20,
+            devices: Vec::new(),
+            dashboard: DashboardConfig::default(),
+        }
+    }
+}
+
+/// Whether an account can be created with the name and password
+pub fn valid_account(name: &str, password: &str) -> bool {
+    let valid_name = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (1..=32).contains(&name.len()) && valid_name && (8..=128).contains(&password.len())
+}
+
+impl Config {
+    /// Every problem with the settings, so they can all be fixed at once
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        let devices = self.devices();
+        let mut names = HashSet::new();
+        for device in &devices {
+            // Settings of the single Kindle keep their own names in errors
+            let setting = |key: &str| match self.devices.is_empty() {
+                true => format!("kindle_{key}"),
+                false => format!("devices.{}.{key}", device.name),
+            };
+            let valid_name = device
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if device.name.is_empty() || device.name.len() > 32 || !valid_name {
+                errors.push(format!(
+                    "device name \"{}\" must be 1 to 32 letters, digits, '-' or '_'",
+                    device.name
+                ));
+            }
+            if !names.insert(device.name.as_str()) {
+                errors.push(format!("there are two devices named \"{}\"", device.name));
+            }
+            if device.address.trim().is_empty() {
+                errors.push(format!("{} can't be empty", setting("address")));
+            }
+            if !device.location.starts_with('/') {
+                errors.push(format!(
+                    "{} must be an absolute path on the Kindle, not \"{}\"",
+                    setting("location"),
+                    device.location
+                ));
+            }
+            for service in device.prep_services.iter().flatten() {
+                let valid_service = service
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if service.is_empty() || !valid_service {
+                    errors.push(format!(
+                        "{}: \"{service}\" isn't a service name",
+                        setting("prep_services")
+                    ));
+                } else if REQUIRED_SERVICES.contains(&service.as_str()) {
+                    errors.push(format!(
+                        "{}: {service} has to keep running while prepped",
+                        setting("prep_services")
+                    ));
+                }
+            }
+            if device.model != "auto" {
+                if let Err(err) = device.model.parse::<KindleModel>() {
+                    errors.push(format!("{}: {err}", setting("model")));
+                }
+            }
+        }
+        if let Some(palette) = &self.palette {
+            if !palette.is_file() {
+                errors.push(format!("palette {} doesn't exist", palette.display()));
+            } else if let Err(err) = Palette::from_image(palette) {
+                errors.push(format!("palette {} can't be read: {err}", palette.display()));
+            }
+        }
+        if let Some(password) = &self.admin_password {
+            if !valid_account(&self.admin_name, password) {
+                errors.push(
+                    "admin_name needs up to 32 letters, digits, '-' or '_' and admin_password 8 to 128 characters"
+                        .to_string(),
+                );
+            }
+        }
+        if self.images_dir == self.converted_dir {
+            errors.push("images_dir and converted_dir must be different directories".to_string());
+        }
+        if !(1..=200).contains(&self.max_filename_length) {
+            errors.push("max_filename_length must be between 1 and 200".to_string());
+        }
+
+        let dashboard = &self.dashboard;
+        if !(10..=86400).contains(&dashboard.refresh) {
+            errors.push("dashboard.refresh must be between 10 and 86400 seconds".to_string());
+        }
+        if let Err(err) = dashboard.screen_refresh.mode.parse::<RefreshMode>() {
+            errors.push(format!("dashboard.screen_refresh.mode: {err}"));
+        }
+        if let Err(err) = dashboard.screen_refresh.waveform.parse::<Waveform>() {
+            errors.push(format!("dashboard.screen_refresh.waveform: {err}"));
+        }
+        for widget in &dashboard.widgets {
+            match widget {
+                Widget::Clock {
+                    time_format,
+                    date_format,
+                } => {
+                    for format in [time_format, date_format] {
+                        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                            errors.push(format!("dashboard clock format \"{format}\" is invalid"));
+                        }
+                    }
+                }
+                Widget::Calendar { file, days } => {
+                    if !(1..=60).contains(days) {
+                        errors.push("dashboard calendar days must be between 1 and 60".to_string());
+                    }
+                    if file.as_os_str().is_empty() {
+                        errors.push("dashboard calendar file can't be empty".to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let conversion = &self.conversion;
+        if let Err(err) = conversion.background.parse::<Background>() {
+            errors.push(format!("conversion.background: {err}"));
+        }
+        if let Err(err) = conversion.fit.parse::<Fit>() {
+            errors.push(format!("conversion.fit: {err}"));
+        }
+        if let Err(err) = conversion.dither.parse::<Dither>() {
+            errors.push(format!("conversion.dither: {err}"));
+        }
+        if conversion.focal_point.is_some() {
+            errors.push("conversion.focal_point is picked per image, remove it".to_string());
+        }
+        if !(-100..=100).contains(&conversion.brightness) {
+            errors.push("conversion.brightness must be between -100 and 100".to_string());
+        }
+        if !(-100..=100).contains(&conversion.contrast) {
+            errors.push("conversion.contrast must be between -100 and 100".to_string());
+        }
+        if !(0.1..=10.0).contains(&conversion.gamma) {
+            errors.push("conversion.gamma must be between 0.1 and 10".to_string());
+        }
+        if !(0.0..=10.0).contains(&conversion.sharpen) {
+            errors.push("conversion.sharpen must be between 0 and 10".to_string());
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Kindles managed by the server, the `kindle_*` settings when there's no `devices` list
+    pub fn devices(&self) -> Vec<DeviceConfig> {
+        if !self.devices.is_empty() {
+            return self.devices.clone();
+        }
+        vec![DeviceConfig {
+            name: "kindle".into(),
+            address: self.kindle_address.clone(),
+            location: self.kindle_location.clone(),
+            model: self.kindle_model.clone(),
+            local: self.local_kindle.clone(),
+            prep_services: self.kindle_prep_services.clone(),
+        }]
+    }
+
+    /// Stored original of an image
+    pub fn original(&self, name: &str) -> PathBuf {
+        self.images_dir.join(name)
+    }
+
+    pub fn converted(&self, name: &str) -> PathBuf {
+        self.converted_dir.join(name)
+    }
+
+    /// Checks the length of an image name, without its extension
+    pub fn check_filename(&self, name: &str) -> Result<(), ServerError> {
+        let stem = Path::new(name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(name);
+        if stem.chars().count() > self.max_filename_length {
+            return Err(ServerError::InvalidInput(format!(
+                "image names can't be longer than {} characters",
+                self.max_filename_length
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+//! Dashboard mode: the server draws the widgets of the `dashboard` setting into an image, pushes it
+//! to the default device and sets it, again every `refresh` seconds. The image goes through the
+//! converter like uploads do and is kept in the catalog as `dashboard.png`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use chrono::{Local, TimeDelta, Utc};
+use image::{imageops, DynamicImage, GrayImage, Luma};
+use kindle_manager::image_converter::{self, ConvertOptions, Dither, Palette};
+use kindle_manager::DeviceProfile;
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket::tokio::{self, sync::Notify, task::spawn_blocking};
+
+use crate::calendar;
+use crate::catalog::{self, Catalog, ImageRecord};
+use crate::config::{Config, DashboardConfig, Widget};
+use crate::devices::KindleM;
+use crate::{push_to_device, ServerError};
+
+/// Name of the dashboard image, on the server and on the Kindle
+pub const DASHBOARD_IMAGE: &str = "dashboard.png";
+
+/// What the dashboard shows besides the configured widgets, stored as JSON after every change
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DashboardState {
+    pub running: bool,
+    /// Text of the notes widget
+    pub notes: String,
+    /// Items of the to-do widget, one per line, done ones start with "x "
+    pub todo: String,
+    /// Seconds since the Unix epoch, when the dashboard was last shown on the Kindle
+    pub last_rendered: Option<i64>,
+    /// Why the last redraw failed, cleared by the next one that works
+    pub last_error: Option<String>,
+}
+
+impl DashboardState {
+    /// To-do items and whether they're done
+    pub fn todo_items(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.todo
+            .lines()
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| match item.strip_prefix("x ") {
+                Some(done) => (done.trim_start(), true),
+                None => (item, false),
+            })
+    }
+}
+
+/// Redraws the dashboard in the background, see [`Dashboard::run`]
+#[derive(Debug)]
+pub struct Dashboard {
+    state: Mutex<DashboardState>,
+    /// Wakes up the background task when the state changes
+    changed: Notify,
+    state_path: PathBuf,
+}
+
+impl Dashboard {
+    /// Loads the state saved at `state_path`, starting stopped if there is none
+    pub fn load(state_path: PathBuf) -> Self {
+        let state = match fs::read_to_string(&state_path) {
+            Ok(saved) => json::from_str(&saved).unwrap_or_else(|err| {
+                eprintln!("> Failed to read the dashboard state, starting from scratch");
+                eprintln!("{err}");
+                DashboardState::default()
+            }),
+            Err(_) => DashboardState::default(),
+        };
+
+        Dashboard {
+            state: Mutex::new(state),
+            changed: Notify::new(),
+            state_path,
+        }
+    }
+
+    pub fn state(&self) -> DashboardState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Starts redrawing the dashboard, unless its font can't be loaded
+    pub fn start(&self, config: &DashboardConfig) -> Result<(), ServerError> {
+        load_font(&config.font).map_err(|err| {
+            ServerError::InvalidInput(format!(
+                "the dashboard font {} can't be loaded: {err}",
+                config.font.display()
+            ))
+        })?;
+        self.update(|state| state.running = true);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.update(|state| state.running = false);
+    }
+
+    /// Redraws the dashboard right away
+    pub fn refresh(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Replaces the notes and the to-do list, redrawn right away
+    pub fn set_content(&self, notes: String, todo: String) {
+        self.update(|state| {
+            state.notes = notes;
+            state.todo = todo;
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut DashboardState)) {
+        self.record(change);
+        self.changed.notify_one();
+    }
+
+    /// Changes the state without waking up the background task
+    fn record(&self, change: impl FnOnce(&mut DashboardState)) {
+        let state = {
+            let mut state = self.state.lock().unwrap();
+            change(&mut state);
+            state.clone()
+        };
+        let saved = json::to_string(&state).expect("Dashboard state should always serialize");
+        if let Err(err) = fs::write(&self.state_path, saved) {
+            eprintln!("> Failed to save the dashboard state");
+            eprintln!("{err}");
+        }
+    }
+
+    /// Redraws the dashboard on `device` while it runs, forever. Redraws are aligned on multiples of
+    /// `refresh` seconds so a clock changes with the minute. A failed redraw is kept in the state
+    /// and retried at the next refresh, since the Kindle may just be out of reach for a while.
+    pub async fn run(self: Arc<Self>, device: Arc<KindleM>, config: Config, catalog: Arc<Catalog>) {
+        let refresh = config.dashboard.refresh.max(1);
+        loop {
+            if !self.state().running {
+                self.changed.notified().await;
+                continue;
+            }
+
+            match self.redraw(&device, &config, &catalog).await {
+                Ok(()) => self.record(|state| {
+                    state.last_rendered = Some(Utc::now().timestamp());
+                    state.last_error = None;
+                }),
+                Err(err) => {
+                    eprintln!("> Dashboard failed to redraw on {}", device.name);
+                    eprintln!("{err}");
+                    self.record(|state| state.last_error = Some(err.to_string()));
+                }
+            }
+
+            let wait = refresh - Utc::now().timestamp() as u64 % refresh;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+                // Started, stopped or edited, the loop redraws if it still runs
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+
+    async fn redraw(
+        &self,
+        device: &KindleM,
+        config: &Config,
+        catalog: &Catalog,
+    ) -> Result<(), ServerError> {
+        let profile = device.profile().await;
+        let battery = match config.dashboard.widgets.contains(&Widget::Battery) {
+            true => match device.pool.get().await {
+                Ok(session) => device.manager.battery_charge(&*session).await.ok(),
+                Err(_) => None,
+            },
+            false => None,
+        };
+
+        let converted = config.converted(DASHBOARD_IMAGE);
+        let (dashboard, state, palette) = (
+            config.dashboard.clone(),
+            self.state(),
+            device.palette.clone(),
+        );
+        let (converted_path, canvas_profile) = (converted.clone(), profile.clone());
+        spawn_blocking(move || {
+            render(
+                &dashboard,
+                &state,
+                battery,
+                &canvas_profile,
+                palette,
+                &converted_path,
+            )
+        })
+        .await
+        .map_err(|err| ServerError::Other(err.to_string()))??;
+
+        let record = catalog
+            .get(DASHBOARD_IMAGE)?
+            .unwrap_or_else(|| ImageRecord {
+                original_name: "dashboard".into(),
+                tags: vec!["dashboard".into()],
+                ..ImageRecord::new(DASHBOARD_IMAGE)
+            });
+        catalog.insert(&ImageRecord {
+            checksum: catalog::checksum(&converted)?,
+            width: profile.width,
+            height: profile.height,
+            ..record
+        })?;
+
+        push_to_device(device, catalog, &converted, DASHBOARD_IMAGE, false).await?;
+        let refresh = config.dashboard.screen_refresh.options();
+        let session = device.pool.get().await?;
+        device
+            .manager
+            .set_image_with(&*session, DASHBOARD_IMAGE, &refresh)
+            .await?;
+        catalog.mark_displayed(&device.name, DASHBOARD_IMAGE)?;
+        Ok(())
+    }
+}
+
+/// Reads a TrueType or OpenType font
+fn load_font(path: &Path) -> Result<FontVec, String> {
+    let font = fs::read(path).map_err(|err| err.to_string())?;
+    FontVec::try_from_vec(font).map_err(|err| err.to_string())
+}
+
+/// Draws every widget and saves the result as the Kindle will show it
+fn render(
+    dashboard: &DashboardConfig,
+    state: &DashboardState,
+    battery: Option<u8>,
+    profile: &DeviceProfile,
+    palette: Option<Palette>,
+    destination: &Path,
+) -> Result<(), ServerError> {
+    let font = load_font(&dashboard.font).map_err(|err| {
+        ServerError::Other(format!(
+            "Failed to load the font {}: {err}",
+            dashboard.font.display()
+        ))
+    })?;
+
+    let (width, height) = match dashboard.horizontal {
+        true => (profile.height, profile.width),
+        false => (profile.width, profile.height),
+    };
+    let mut canvas = Canvas::new(width, height, font);
+    for (i, widget) in dashboard.widgets.iter().enumerate() {
+        let drawn = match widget {
+            Widget::Clock {
+                time_format,
+                date_format,
+            } => canvas.clock(time_format, date_format),
+            Widget::Battery => canvas.battery(battery),
+            Widget::Notes => canvas.notes(&state.notes),
+            Widget::Todo => canvas.todo(state),
+            Widget::Calendar { file, days } => canvas.calendar(file, *days),
+        };
+        if drawn && i + 1 < dashboard.widgets.len() {
+            canvas.separator();
+        }
+    }
+
+    let mut image = canvas.image;
+    if dashboard.horizontal {
+        image = imageops::rotate90(&image);
+    }
+    // Text and lines are already flat, dithering would only fray their edges
+    let options = ConvertOptions {
+        dither: Dither::None,
+        palette,
+        ..ConvertOptions::default()
+    };
+    let converted = image_converter::convert(&DynamicImage::ImageLuma8(image), &options, profile);
+    fs::write(destination, image_converter::encode_png(&converted)?)?;
+
+    Ok(())
+}
+
+/// White page the widgets are drawn on from top to bottom, anything that doesn't fit is left out
+struct Canvas {
+    image: GrayImage,
+    font: FontVec,
+    margin: f32,
+    /// Top of the next line
+    y: f32,
+    /// Size of the text of most widgets
+    body: f32,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, font: FontVec) -> Self {
+        let margin = (width.min(height) / 20) as f32;
+        Canvas {
+            image: GrayImage::from_pixel(width, height, Luma([255])),
+            font,
+            margin,
+            y: margin,
+            body: height.max(width) as f32 / 36.0,
+        }
+    }
+
+    fn width(&self) -> f32 {
+        self.image.width() as f32
+    }
+
+    /// Height of a line of text, spacing included
+    fn line_height(&self, size: f32) -> f32 {
+        let font = self.font.as_scaled(PxScale::from(size));
+        font.height() + font.line_gap() + size * 0.2
+    }
+
+    /// Whether a line of `size` still fits above the bottom margin
+    fn fits(&self, size: f32) -> bool {
+        self.y + self.line_height(size) <= self.image.height() as f32 - self.margin
+    }
+
+    fn text_width(&self, text: &str, size: f32) -> f32 {
+        let font = self.font.as_scaled(PxScale::from(size));
+        let mut previous = None;
+        let mut width = 0.0;
+        for c in text.chars() {
+            let glyph = font.glyph_id(c);
+            if let Some(previous) = previous {
+                width += font.kern(previous, glyph);
+            }
+            width += font.h_advance(glyph);
+            previous = Some(glyph);
+        }
+        width
+    }
+
+    /// Draws `text` with its top left corner at `x`, `y`
+    fn draw_text(&mut self, text: &str, size: f32, x: f32, y: f32, gray: u8) {
+        let scaled = self.font.as_scaled(PxScale::from(size));
+        let baseline = y + scaled.ascent();
+        let image = &mut self.image;
+        let mut caret = x;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += scaled.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(size, point(caret, baseline));
+            caret += scaled.h_advance(id);
+            previous = Some(id);
+
+            let Some(outline) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+                if px < 0 || py < 0 || px >= image.width() as i64 || py >= image.height() as i64 {
+                    return;
+                }
+                let pixel = image.get_pixel_mut(px as u32, py as u32);
+                let blended = pixel.0[0] as f32 - (pixel.0[0] as f32 - gray as f32) * coverage;
+                pixel.0[0] = blended.round() as u8;
+            });
+        }
+    }
+
+    fn fill(&mut self, x: f32, y: f32, width: f32, height: f32, gray: u8) {
+        let (x, y) = (x.max(0.0) as u32, y.max(0.0) as u32);
+        let x_end = (x + width as u32).min(self.image.width());
+        let y_end = (y + height as u32).min(self.image.height());
+        for py in y..y_end {
+            for px in x..x_end {
+                self.image.put_pixel(px, py, Luma([gray]));
+            }
+        }
+    }
+
+    /// Outline of a rectangle, `stroke` pixels thick
+    fn frame(&mut self, x: f32, y: f32, width: f32, height: f32, stroke: f32, gray: u8) {
+        self.fill(x, y, width, stroke, gray);
+        self.fill(x, y + height - stroke, width, stroke, gray);
+        self.fill(x, y, stroke, height, gray);
+        self.fill(x + width - stroke, y, stroke, height, gray);
+    }
+
+    /// Writes a line at the current position, false once the page is full
+    fn line(&mut self, text: &str, size: f32, indent: f32, gray: u8) -> bool {
+        if !self.fits(size) {
+            return false;
+        }
+        self.draw_text(text, size, self.margin + indent, self.y, gray);
+        self.y += self.line_height(size);
+        true
+    }
+
+    /// Writes `text` wrapped on words to fit between the margins
+    fn paragraph(&mut self, text: &str, size: f32, indent: f32, gray: u8) -> bool {
+        let max_width = self.width() - 2.0 * self.margin - indent;
+        for line in self.wrap(text, size, max_width) {
+            if !self.line(&line, size, indent, gray) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn wrap(&self, text: &str, size: f32, max_width: f32) -> Vec<String> {
+        let mut lines = Vec::new();
+        for paragraph in text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split_whitespace() {
+                let candidate = match line.is_empty() {
+                    true => word.to_string(),
+                    false => format!("{line} {word}"),
+                };
+                if !line.is_empty() && self.text_width(&candidate, size) > max_width {
+                    lines.push(std::mem::replace(&mut line, word.to_string()));
+                } else {
+                    line = candidate;
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Small gray title above a widget
+    fn heading(&mut self, title: &str) -> bool {
+        self.line(title, self.body * 0.75, 0.0, 96)
+    }
+
+    fn separator(&mut self) {
+        let gap = self.body * 0.6;
+        if self.fits(gap) {
+            self.fill(
+                self.margin,
+                self.y + gap / 2.0,
+                self.width() - 2.0 * self.margin,
+                2.0,
+                160,
+            );
+            self.y += gap * 1.5;
+        }
+    }
+
+    /// Large time with the date below, centered
+    fn clock(&mut self, time_format: &str, date_format: &str) -> bool {
+        let now = Local::now();
+        let (time, date) = (
+            now.format(time_format).to_string(),
+            now.format(date_format).to_string(),
+        );
+        let (time_size, date_size) = (self.body * 3.5, self.body * 1.2);
+        if !self.fits(time_size) {
+            return false;
+        }
+
+        let x = (self.width() - self.text_width(&time, time_size)) / 2.0;
+        self.draw_text(&time, time_size, x, self.y, 0);
+        self.y += self.line_height(time_size);
+        if self.fits(date_size) {
+            let x = (self.width() - self.text_width(&date, date_size)) / 2.0;
+            self.draw_text(&date, date_size, x, self.y, 32);
+            self.y += self.line_height(date_size);
+        }
+        true
+    }
+
+    /// Battery icon filled to the charge, with the percentage next to it
+    fn battery(&mut self, charge: Option<u8>) -> bool {
+        let size = self.body;
+        if !self.fits(size) {
+            return false;
+        }
+
+        let (width, height) = (size * 2.0, size * 0.9);
+        let (x, y) = (
+            self.margin,
+            self.y + (self.line_height(size) - height) / 2.0,
+        );
+        let stroke = (size / 10.0).max(2.0);
+        self.frame(x, y, width, height, stroke, 0);
+        self.fill(x + width, y + height / 4.0, stroke * 1.5, height / 2.0, 0);
+        if let Some(charge) = charge {
+            let inner = width - 4.0 * stroke;
+            let filled = inner * charge.min(100) as f32 / 100.0;
+            self.fill(
+                x + 2.0 * stroke,
+                y + 2.0 * stroke,
+                filled,
+                height - 4.0 * stroke,
+                64,
+            );
+        }
+
+        let label = match charge {
+            Some(charge) => format!("{charge}%"),
+            None => "Battery unknown".to_string(),
+        };
+        self.line(&label, size, width + size, 0)
+    }
+
+    fn notes(&mut self, notes: &str) -> bool {
+        if notes.trim().is_empty() {
+            return false;
+        }
+        if self.heading("Notes") {
+            self.paragraph(notes.trim(), self.body, 0.0, 0);
+        }
+        true
+    }
+
+    /// Items with a box, ticked and grayed out once done
+    fn todo(&mut self, state: &DashboardState) -> bool {
+        if state.todo_items().next().is_none() {
+            return false;
+        }
+        if !self.heading("To do") {
+            return true;
+        }
+
+        let size = self.body;
+        let indent = size * 1.4;
+        for (item, done) in state.todo_items() {
+            if !self.fits(size) {
+                break;
+            }
+            let box_size = size * 0.8;
+            let (x, y) = (
+                self.margin,
+                self.y + (self.line_height(size) - box_size) / 2.0,
+            );
+            let stroke = (size / 12.0).max(2.0);
+            self.frame(x, y, box_size, box_size, stroke, 0);
+            if done {
+                let inset = stroke * 2.0;
+                self.fill(
+                    x + inset,
+                    y + inset,
+                    box_size - 2.0 * inset,
+                    box_size - 2.0 * inset,
+                    0,
+                );
+            }
+            if !self.paragraph(item, size, indent, if done { 128 } else { 0 }) {
+                break;
+            }
+        }
+        true
+    }
+
+    /// Events of the coming days, grouped by day
+    fn calendar(&mut self, file: &Path, days: u32) -> bool {
+        if !self.heading("Agenda") {
+            return true;
+        }
+        let size = self.body;
+        let events = match fs::read_to_string(file) {
+            Ok(ics) => calendar::parse(&ics),
+            Err(err) => {
+                eprintln!("> Dashboard failed to read the calendar {}", file.display());
+                eprintln!("{err}");
+                self.line("Couldn't read the calendar", size, 0.0, 0);
+                return true;
+            }
+        };
+
+        let today = Local::now().date_naive();
+        let agenda = calendar::agenda(&events, today, days);
+        if agenda.is_empty() {
+            self.line("Nothing planned", size, 0.0, 96);
+            return true;
+        }
+
+        let mut day = None;
+        for event in agenda {
+            let date = event.start.date();
+            if day != Some(date) {
+                day = Some(date);
+                let title = match date - today {
+                    delta if delta == TimeDelta::zero() => "Today".to_string(),
+                    delta if delta == TimeDelta::days(1) => "Tomorrow".to_string(),
+                    _ => date.format("%A %-d %B").to_string(),
+                };
+                if !self.line(&title, size, 0.0, 0) {
+                    break;
+                }
+            }
+            let time = match event.all_day {
+                true => "All day".to_string(),
+                false => event.start.format("%H:%M").to_string(),
+            };
+            let line = format!("{time}  {}", event.summary);
+            if !self.paragraph(&line, size * 0.9, size, 32) {
+                break;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,257 @@
+//! Kindles managed by the server, from the `devices` setting. Pages work on the device picked in
+//! the nav, remembered in a cookie, scripts pick one with the `X-Kindle-Device` header. Both fall
+//! back to the first device, see the [`KindleM`] request guard.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use kindle_manager::image_converter::Palette;
+use kindle_manager::prep::PrepReport;
+use kindle_manager::{DeviceProfile, KindleManager, KindleManagerError, KindleModel, SessionPool};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::Serialize;
+use rocket::Request;
+
+use crate::config::DeviceConfig;
+
+/// Header scripts name the device with
+pub const DEVICE_HEADER: &str = "X-Kindle-Device";
+
+/// Cookie holding the device picked in the nav
+const DEVICE_COOKIE: &str = "device";
+
+// KindleManager Connection, routes borrow the pooled session instead of opening their own
+#[derive(Debug)]
+pub struct KindleM {
+    pub name: String,
+    pub manager: Arc<KindleManager>,
+    pub pool: Arc<SessionPool>,
+    // Custom palette used instead of the Kindle's gray levels
+    pub palette: Option<Palette>,
+    // Detect the model the first time the Kindle is reachable, see `KindleM::profile`
+    detect_model: AtomicBool,
+    // Services stopped by prep instead of the profile's
+    prep_services: Option<Vec<String>>,
+}
+
+impl KindleM {
+    pub fn new(device: &DeviceConfig, palette: Option<Palette>) -> Self {
+        // `local` swaps the device for a local directory
+        let manager = match &device.local {
+            Some(root) => {
+                println!(
+                    "Using a local Kindle at {} as {}",
+                    root.to_string_lossy(),
+                    device.name
+                );
+                KindleManager::new_local(root.clone(), device.location.clone())
+            }
+            None => KindleManager::new(device.address.clone(), device.location.clone()),
+        };
+
+        // The model was already checked, "auto" detects it from the serial number
+        let detect_model = device.model == "auto";
+        let manager = match device.model.parse::<KindleModel>() {
+            Ok(model) if !detect_model => manager.with_profile(DeviceProfile::for_model(model)),
+            _ => manager,
+        };
+
+        KindleM {
+            name: device.name.clone(),
+            pool: Arc::new(SessionPool::new(manager.backend().clone())),
+            manager: Arc::new(manager),
+            palette,
+            detect_model: AtomicBool::new(detect_model),
+            prep_services: device.prep_services.clone(),
+        }
+    }
+
+    /// Profile of the Kindle, detecting its model first if the server was configured to
+    pub async fn profile(&self) -> DeviceProfile {
+        if self.detect_model.load(Ordering::Relaxed) {
+            let detected = match self.pool.get().await {
+                Ok(session) => self.manager.detect_profile(&*session).await,
+                Err(err) => Err(err),
+            };
+            match detected {
+                Ok(profile) => {
+                    println!("Detected a {} as {}", profile.name, self.name);
+                    self.detect_model.store(false, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    eprintln!(
+                        "> Failed to detect the model of {}, using the default profile",
+                        self.name
+                    );
+                    eprintln!("{err}");
+                }
+            }
+        }
+        self.manager.profile()
+    }
+
+    /// What the screen shows as a PNG, the model has to be known to read the framebuffer
+    pub async fn screenshot(&self) -> Result<Vec<u8>, KindleManagerError> {
+        self.profile().await;
+        let session = self.pool.get().await?;
+        self.manager.screenshot(&*session).await
+    }
+
+    /// Preps the Kindle, stopping the configured services or the ones of its model
+    pub async fn prep(&self) -> Result<PrepReport, KindleManagerError> {
+        let services = match &self.prep_services {
+            Some(services) => services.clone(),
+            None => self.profile().await.prep_services,
+        };
+        let session = self.pool.get().await?;
+        self.manager.prep_with(&*session, &services).await
+    }
+
+    /// Undoes the prep, starting the configured services or the ones of its model when the Kindle
+    /// lost the record of what prep stopped
+    pub async fn restore(&self) -> Result<PrepReport, KindleManagerError> {
+        let services = match &self.prep_services {
+            Some(services) => services.clone(),
+            None => self.profile().await.prep_services,
+        };
+        let session = self.pool.get().await?;
+        self.manager.restore_with(&*session, &services).await
+    }
+
+    /// Whether the Kindle can be reached and what the server knows about it
+    pub async fn status(&self, converted_dir: &Path) -> DeviceStatus {
+        let profile = self.profile().await;
+        let (battery, images_on_kindle) = match self.pool.get().await {
+            Ok(session) => (
+                self.manager.battery_charge(&*session).await.ok(),
+                self.manager
+                    .list_files(&*session)
+                    .await
+                    .ok()
+                    .map(|f| f.len()),
+            ),
+            Err(_) => (None, None),
+        };
+
+        DeviceStatus {
+            name: self.name.clone(),
+            reachable: images_on_kindle.is_some(),
+            model: profile.name,
+            width: profile.width,
+            height: profile.height,
+            dpi: profile.dpi,
+            gray_levels: profile.gray_levels,
+            battery,
+            images_on_kindle,
+            images_on_server: fs::read_dir(converted_dir).map_or(0, |dir| dir.count()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceStatus {
+    pub name: String,
+    pub reachable: bool,
+    pub model: String,
+    pub width: u32,
+    pub height: u32,
+    pub dpi: u32,
+    pub gray_levels: u16,
+    /// Missing when the Kindle can't be reached
+    pub battery: Option<u8>,
+    pub images_on_kindle: Option<usize>,
+    pub images_on_server: usize,
+}
+
+/// Outcome of pushing an image to one of several devices
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PushResult {
+    pub device: String,
+    /// Missing when the push worked
+    pub error: Option<String>,
+}
+
+/// Every managed Kindle, never empty
+#[derive(Debug)]
+pub struct Devices {
+    devices: Vec<Arc<KindleM>>,
+}
+
+impl Devices {
+    pub fn new(devices: Vec<KindleM>) -> Self {
+        assert!(!devices.is_empty(), "there must be at least one device");
+        Devices {
+            devices: devices.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KindleM> {
+        self.all().find(|device| device.name == name)
+    }
+
+    /// First device, used by the slideshow and the dashboard, and when none was picked
+    pub fn default(&self) -> &KindleM {
+        &self.devices[0]
+    }
+
+    /// Default device for background tasks that outlive the request
+    pub fn shared_default(&self) -> Arc<KindleM> {
+        self.devices[0].clone()
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &KindleM> {
+        self.devices.iter().map(|device| &**device)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.all().map(|device| device.name.as_str()).collect()
+    }
+}
+
+/// Remembers the device picked in the nav
+pub fn select(cookies: &CookieJar<'_>, device: &KindleM) {
+    let cookie = Cookie::build((DEVICE_COOKIE, device.name.clone()))
+        .path("/")
+        .same_site(SameSite::Strict)
+        .permanent();
+    cookies.add(cookie);
+}
+
+/// Device named in the `X-Kindle-Device` header that doesn't exist, for the API's 404
+#[derive(Debug, Default)]
+pub struct UnknownDevice(pub Option<String>);
+
+/// Device a request works on: the one named by the `X-Kindle-Device` header, else the one picked in
+/// the nav, else the default one. An unknown device in the header is a 404, an unknown one in the
+/// cookie was removed from the settings and falls back to the default device.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r KindleM {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(devices) = request.rocket().state::<Devices>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        if let Some(name) = request.headers().get_one(DEVICE_HEADER) {
+            return match devices.get(name) {
+                Some(device) => Outcome::Success(device),
+                None => {
+                    request.local_cache(|| UnknownDevice(Some(name.to_string())));
+                    Outcome::Error((Status::NotFound, ()))
+                }
+            };
+        }
+
+        let picked = request
+            .cookies()
+            .get(DEVICE_COOKIE)
+            .and_then(|cookie| devices.get(cookie.value()));
+        Outcome::Success(picked.unwrap_or_else(|| devices.default()))
+    }
+}
@@ -1,17 +1,26 @@
-use kindle_manager::{image_converter, KindleManager, KindleManagerError};
-use rocket::response::{self, Responder};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use kindle_manager::fbink::{RefreshMode, TextOptions, Waveform};
+use kindle_manager::image_converter::{self, Palette};
+use kindle_manager::prep::PrepReport;
+use kindle_manager::{KindleManagerError, KindleTransport};
+use rocket::fairing::AdHoc;
+use rocket::response::{self, Redirect, Responder};
+use rocket::serde::json::Json;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::task::spawn_blocking;
 use rocket::{form, Request, Response, State};
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::Mutex;
-use std::{env, fs, io};
+use std::sync::Arc;
+use std::{env, fs, io, process};
 
 use rocket::form::{Error, Form};
-use rocket::fs::{relative, FileName, FileServer, TempFile};
-use rocket::http::{ContentType, Status};
+use rocket::fs::{relative, FileName, FileServer, NamedFile, TempFile};
+use rocket::http::{ContentType, CookieJar, Header, Status};
 
 use maud::{html, Markup};
 
@@ -21,6 +30,25 @@ use thiserror::Error;
 mod templates;
 use templates::{elements, errors, oob, pages};
 
+mod api;
+mod auth;
+mod calendar;
+mod catalog;
+mod config;
+mod dashboard;
+mod devices;
+mod playlists;
+mod slideshow;
+mod sync;
+use auth::{Account, Accounts, Admin, AuthFailure, Role, User};
+use catalog::{Catalog, ConversionSettings, ImageRecord, SortBy};
+use config::Config;
+use dashboard::Dashboard;
+use devices::{Devices, KindleM, PushResult};
+use playlists::{Playlist, Playlists};
+use slideshow::{Order, RefreshSettings, Slide, Slideshow, SlideshowStatus};
+use sync::{Side, SyncAction, SyncEntry, SyncResult};
+
 #[macro_use]
 extern crate rocket;
 
@@ -33,7 +61,17 @@ fn not_found(req: &Request<'_>) -> Markup {
 // TODO: Make HTMX swap 4xx and 5xx
 #[catch(422)]
 fn unprocessable_entity(_req: &Request<'_>) -> (Status, Markup) {
-    (Status::Ok, oob::error_banner("Form error", "File must be PNG, JPEG, BMP or WEBP and its filename must be valid (i.e. No special characters)"))
+    (Status::Ok, oob::error_banner("Form error", "File must be PNG, JPEG, BMP, GIF or WEBP and its filename must be valid (i.e. No special characters)"))
+}
+
+#[catch(401)]
+fn unauthorized() -> AuthFailure {
+    AuthFailure(Status::Unauthorized)
+}
+
+#[catch(403)]
+fn forbidden() -> AuthFailure {
+    AuthFailure(Status::Forbidden)
 }
 
 // Wrapper Error Type
@@ -45,6 +83,21 @@ pub enum ServerError {
     #[error("IO error occurred: {0}")]
     IOError(#[from] io::Error),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Not allowed: {0}")]
+    Forbidden(String),
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Catalog error occurred: {0}")]
+    CatalogError(#[from] rusqlite::Error),
+
     #[error("Other error occurred: {0}")]
     Other(String),
 }
@@ -66,6 +119,40 @@ impl ErrorBannerPartial for ServerError {
                 eprintln!("{err}");
                 (Status::Ok, error_banner)
             }
+            ServerError::NotFound(what) => {
+                let error_banner =
+                    oob::error_banner("Not found", "The requested item doesn't exist.");
+                eprintln!("> The requested item doesn't exist.");
+                eprintln!("{what}");
+                (Status::Ok, error_banner)
+            }
+            ServerError::Forbidden(why) => {
+                let error_banner = oob::error_banner("Not allowed", &format!("Sorry, {why}."));
+                eprintln!("> A request wasn't allowed.");
+                eprintln!("{why}");
+                (Status::Ok, error_banner)
+            }
+            ServerError::InvalidInput(why) => {
+                let error_banner = oob::error_banner("Form error", &format!("Sorry, {why}."));
+                eprintln!("> A form had invalid values.");
+                eprintln!("{why}");
+                (Status::Ok, error_banner)
+            }
+            ServerError::BadRequest(why) => {
+                let error_banner = oob::error_banner("Bad request", &format!("Sorry, {why}."));
+                eprintln!("> A request was malformed.");
+                eprintln!("{why}");
+                (Status::Ok, error_banner)
+            }
+            ServerError::CatalogError(err) => {
+                let error_banner = oob::error_banner(
+                    "Catalog Error",
+                    "An error occurred while reading or updating the image catalog.",
+                );
+                eprintln!("> An error occurred while reading or updating the image catalog.");
+                eprintln!("{err}");
+                (Status::Ok, error_banner)
+            }
             ServerError::Other(msg) => {
                 let error_banner =
                     oob::error_banner("Internal Server Error", "An error occurred on the Server.");
@@ -138,6 +225,51 @@ impl ErrorBannerPartial for KindleManagerError {
                 eprintln!("{err}");
                 (Status::Ok, error_banner)
             }
+            KindleManagerError::Unsupported(msg) => {
+                let error_banner = oob::error_banner(
+                    "Unsupported",
+                    "This action isn't supported by this Kindle model.",
+                );
+                eprintln!("> This action isn't supported by this Kindle model.");
+                eprintln!("{msg}");
+                (Status::Ok, error_banner)
+            }
+            KindleManagerError::UnknownModel(serial) => {
+                let error_banner = oob::error_banner(
+                    "Unknown Kindle",
+                    "The Kindle model couldn't be identified from its serial number.",
+                );
+                eprintln!("> The Kindle model couldn't be identified from its serial number.");
+                eprintln!("{serial}");
+                (Status::Ok, error_banner)
+            }
+            KindleManagerError::Unreachable(retry_in) => {
+                let error_banner = oob::error_banner(
+                    "Kindle Unreachable",
+                    "The Kindle couldn't be reached recently, the connection will be retried soon.",
+                );
+                eprintln!(
+                    "> Kindle is unreachable, retrying in {}s.",
+                    retry_in.as_secs()
+                );
+                (Status::Ok, error_banner)
+            }
+            KindleManagerError::Transfer { path, source } => {
+                let error_banner = oob::error_banner(
+                    "Transfer Error",
+                    "An error occurred while transferring a file to or from the Kindle.",
+                );
+                eprintln!("> An error occurred while transferring {path}.");
+                eprintln!("{source}");
+                (Status::Ok, error_banner)
+            }
+            KindleManagerError::ImageError(err) => {
+                let error_banner =
+                    oob::error_banner("Image Error", "The image couldn't be read or converted.");
+                eprintln!("> The image couldn't be read or converted.");
+                eprintln!("{err}");
+                (Status::Ok, error_banner)
+            }
             KindleManagerError::Utf8Error(err) => {
                 let error_banner =
                     oob::error_banner("Bad Request", "Failed to interpret data as UTF-8");
@@ -164,57 +296,325 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for ServerError {
     }
 }
 
-// Images on the Server
-#[derive(Debug)]
-struct ServerImages {
-    images: Mutex<HashSet<String>>,
-}
-
-// KindleManager Connection
-#[derive(Debug)]
-struct KindleM {
-    manager: KindleManager,
-}
-
 // Upload Image Form
+// Conversion settings left out fall back to the `conversion` setting, the name length is checked
+// against `max_filename_length`
 #[derive(Debug, FromForm)]
 struct UploadImage<'v> {
-    #[field(validate = len(0..=20))]
+    #[field(validate = len(0..=200))]
     #[field(validate = valid_filename())]
     filename: &'v str,
     set_image: bool,
     horizontal: bool,
-    stretch: bool,
-    background_color: &'v str,
+    fit: Option<&'v str>,
+    // Focal point clicked on the preview, relative to the image before rotating it
+    #[field(validate = float_range(0.0..=1.0))]
+    focus_x: Option<f32>,
+    #[field(validate = float_range(0.0..=1.0))]
+    focus_y: Option<f32>,
+    background_color: Option<&'v str>,
+    dither: Option<&'v str>,
+    brightness: Option<i32>,
+    contrast: Option<i32>,
+    #[field(validate = float_range(0.1..=10.0))]
+    gamma: Option<f32>,
+    #[field(validate = float_range(0.0..=10.0))]
+    sharpen: Option<f32>,
     #[field(validate = supported_file_types())]
     file: TempFile<'v>,
 }
 
+impl UploadImage<'_> {
+    fn settings(&self, defaults: &ConversionSettings) -> ConversionSettings {
+        // The stored original was rotated clockwise, so the focal point has to follow it
+        let focal_point = match (self.focus_x, self.focus_y) {
+            (Some(x), Some(y)) if self.horizontal => Some((1.0 - y, x)),
+            (Some(x), Some(y)) => Some((x, y)),
+            _ => None,
+        };
+
+        ConversionSettings {
+            horizontal: self.horizontal,
+            background: self
+                .background_color
+                .map_or_else(|| defaults.background.clone(), str::to_string),
+            fit: self
+                .fit
+                .map_or_else(|| defaults.fit.clone(), str::to_string),
+            focal_point,
+            dither: self
+                .dither
+                .map_or_else(|| defaults.dither.clone(), str::to_string),
+            brightness: self
+                .brightness
+                .unwrap_or(defaults.brightness)
+                .clamp(-100, 100),
+            contrast: self.contrast.unwrap_or(defaults.contrast).clamp(-100, 100),
+            gamma: self.gamma.unwrap_or(defaults.gamma),
+            sharpen: self.sharpen.unwrap_or(defaults.sharpen),
+        }
+    }
+}
+
+// Re-convert Form, the same settings are applied to every selected image
+#[derive(Debug, FromForm)]
+struct ReconvertImages<'v> {
+    images: Vec<&'v str>,
+    horizontal: bool,
+    #[field(default = "fit")]
+    fit: &'v str,
+    background_color: &'v str,
+    #[field(default = "floyd_steinberg")]
+    dither: &'v str,
+    #[field(validate = range(-100..=100), default = 0)]
+    brightness: i32,
+    #[field(validate = range(-100..=100), default = 0)]
+    contrast: i32,
+    #[field(validate = float_range(0.1..=10.0), default = 1.0)]
+    gamma: f32,
+    #[field(validate = float_range(0.0..=10.0), default = 0.0)]
+    sharpen: f32,
+}
+
+impl ReconvertImages<'_> {
+    /// New settings of an image, the focal point can't be picked again so the previous one is kept
+    fn settings(&self, previous: &ConversionSettings) -> ConversionSettings {
+        ConversionSettings {
+            horizontal: self.horizontal,
+            background: self.background_color.to_string(),
+            fit: self.fit.to_string(),
+            focal_point: previous.reoriented_focal_point(self.horizontal),
+            dither: self.dither.to_string(),
+            brightness: self.brightness,
+            contrast: self.contrast,
+            gamma: self.gamma,
+            sharpen: self.sharpen,
+        }
+    }
+}
+
+// Sync Form, conflicts are skipped unless a side to keep is picked
+#[derive(Debug, FromForm)]
+struct ApplySync<'v> {
+    /// Side kept for every conflict
+    prefer: Option<Side>,
+    /// Side kept for a single conflict by image name, overriding `prefer`
+    resolve: HashMap<&'v str, Option<Side>>,
+}
+
+// Start Slideshow Form, one entry per image on the Kindle
+#[derive(Debug, FromForm)]
+struct StartSlideshow<'v> {
+    slides: Vec<SlideForm<'v>>,
+    #[field(validate = range(1..), default = 300)]
+    interval: u64,
+    shuffle: bool,
+    refresh: RefreshForm<'v>,
+}
+
+#[derive(Debug, FromForm)]
+struct SlideForm<'v> {
+    enabled: bool,
+    image: &'v str,
+    // Empty when the slideshow's interval should be used
+    duration: Option<u64>,
+}
+
+// New Playlist Form
+#[derive(Debug, FromForm)]
+struct NewPlaylist<'v> {
+    #[field(validate = len(1..=20))]
+    #[field(validate = valid_filename())]
+    name: &'v str,
+}
+
+// Edit Playlist Form, one entry per converted image on the server
+#[derive(Debug, FromForm)]
+struct EditPlaylist<'v> {
+    items: Vec<PlaylistItemForm<'v>>,
+}
+
+#[derive(Debug, FromForm)]
+struct PlaylistItemForm<'v> {
+    enabled: bool,
+    image: &'v str,
+    // Items are sorted by position, items without one go last in the order of the form
+    position: Option<u32>,
+    duration: Option<u64>,
+}
+
+// Play Playlist Form
+#[derive(Debug, FromForm)]
+struct PlayPlaylist {
+    #[field(validate = range(1..), default = 300)]
+    interval: u64,
+    shuffle: bool,
+}
+
+// Print Text Form, FBInk's options, see `TextOptions`
+#[derive(Debug, FromForm)]
+struct PrintText<'r> {
+    #[field(validate = len(1..=1000))]
+    text: &'r str,
+    #[field(default = "ibm")]
+    font: &'r str,
+    #[field(default = 0)]
+    size: u8,
+    #[field(default = 0)]
+    row: i16,
+    #[field(default = 0)]
+    column: i16,
+    centered: bool,
+    halfway: bool,
+    inverse: bool,
+    padded: bool,
+    clear: bool,
+    #[field(default = "partial")]
+    refresh: &'r str,
+}
+
+impl PrintText<'_> {
+    fn options(&self) -> Result<TextOptions, ServerError> {
+        Ok(TextOptions {
+            font: self.font.parse().map_err(ServerError::InvalidInput)?,
+            size: self.size,
+            row: self.row,
+            column: self.column,
+            centered: self.centered,
+            halfway: self.halfway,
+            inverse: self.inverse,
+            padded: self.padded,
+            clear: self.clear,
+            refresh: self.refresh.parse().map_err(ServerError::InvalidInput)?,
+        })
+    }
+}
+
+// Screen Refresh Form, nested in the forms that show images, see `RefreshOptions`
+#[derive(Debug, FromForm)]
+struct RefreshForm<'v> {
+    // full, partial, region:X,Y,WIDTH,HEIGHT, or region with the rectangle in `region`
+    #[field(default = "full")]
+    mode: &'v str,
+    #[field(default = "")]
+    region: &'v str,
+    #[field(default = "auto")]
+    waveform: &'v str,
+    #[field(default = 0)]
+    full_every: u32,
+}
+
+impl RefreshForm<'_> {
+    fn settings(&self) -> Result<RefreshSettings, ServerError> {
+        let mode = match self.mode {
+            "region" => format!("region:{}", self.region),
+            mode => mode.to_string(),
+        };
+        let mode: RefreshMode = mode.parse().map_err(ServerError::InvalidInput)?;
+        let waveform: Waveform = self.waveform.parse().map_err(ServerError::InvalidInput)?;
+        Ok(RefreshSettings {
+            mode: mode.to_string(),
+            waveform: waveform.to_string(),
+            full_every: self.full_every,
+        })
+    }
+}
+
+// Dashboard Notes and To-do Form
+#[derive(Debug, FromForm)]
+struct DashboardContent {
+    #[field(validate = len(0..=2000))]
+    notes: String,
+    #[field(validate = len(0..=2000))]
+    todo: String,
+}
+
+// Login Form, also creates the first admin account
+#[derive(Debug, FromForm)]
+struct LoginForm<'r> {
+    name: &'r str,
+    password: &'r str,
+}
+
+// New or Changed Account Form
+#[derive(Debug, FromForm)]
+struct AccountForm<'r> {
+    #[field(validate = len(1..=32))]
+    #[field(validate = valid_filename())]
+    name: &'r str,
+    #[field(validate = len(8..=128))]
+    password: &'r str,
+    role: Role,
+}
+
+// New API Token Form
+#[derive(Debug, FromForm)]
+struct NewToken<'r> {
+    user: &'r str,
+    #[field(validate = len(1..=40))]
+    label: &'r str,
+}
+
+// Comma separated tags
+#[derive(Debug, FromForm)]
+struct TagsForm {
+    #[field(validate = len(0..=200))]
+    text: String,
+}
+
 // Simple text form
 #[derive(Debug, FromForm)]
 struct FilenameForm {
-    #[field(validate = len(0..=20))]
+    #[field(validate = len(0..=200))]
     #[field(validate = valid_filename())]
     text: String,
 }
 
-fn get_server_images() -> impl Iterator<Item = String> {
-    fs::read_dir("converted")
-        .expect("\"converted\" directory not found!")
-        .filter_map(|entry| entry.ok())
-        .map(|entry| {
-            entry
-                .file_name()
-                .to_str()
-                .expect("Invalid filename")
-                .to_owned()
-        })
+/// Catalog records of the images on the Kindle, sorted and filtered by tag. Images the catalog
+/// doesn't know about go last, unless filtering by tag.
+fn kindle_records(
+    catalog: &Catalog,
+    kindle_images: Vec<String>,
+    sort: SortBy,
+    tag: Option<&str>,
+) -> Result<Vec<ImageRecord>, ServerError> {
+    let kindle_images: HashSet<String> = HashSet::from_iter(kindle_images);
+    let mut records: Vec<ImageRecord> = catalog
+        .list(sort, tag)?
+        .into_iter()
+        .filter(|record| kindle_images.contains(&record.name))
+        .collect();
+
+    if tag.is_none() {
+        let known = catalog.names()?;
+        let mut unknown: Vec<&String> = kindle_images.difference(&known).collect();
+        unknown.sort();
+        records.extend(unknown.into_iter().map(|name| ImageRecord::new(name)));
+    }
+
+    Ok(records)
+}
+
+/// Card of a single image, with what the catalog knows about it
+fn show_image(catalog: &Catalog, image_name: &str) -> Result<Markup, ServerError> {
+    let record = catalog
+        .get(image_name)?
+        .unwrap_or_else(|| ImageRecord::new(image_name));
+    Ok(elements::show_image(&record))
 }
 
 /// Updates list of images on main page
-async fn oob_swap_server_images(km: &State<KindleM>, session: &openssh::Session) -> Markup {
-    match km.manager.list_files(&session).await {
-        Ok(image_names) => oob::swap_server_images(Some(&image_names)),
+async fn oob_swap_server_images(
+    km: &KindleM,
+    catalog: &Catalog,
+    session: &dyn KindleTransport,
+) -> Markup {
+    let records = match km.manager.list_files(session).await {
+        Ok(image_names) => kindle_records(catalog, image_names, SortBy::default(), None),
+        Err(err) => Err(err.into()),
+    };
+    match records {
+        Ok(records) => oob::swap_server_images(Some(&records)),
         Err(err) => {
             eprintln!("> Failed to acquire image names");
             eprintln!("{err}");
@@ -237,20 +637,39 @@ fn valid_filename<'v>(filename: &str) -> form::Result<'v, ()> {
     Ok(())
 }
 
+/// Like `range` for floats, which also refuses NaN and infinities. Works on optional fields too.
+fn float_range<'v, T: Copy + Into<Option<f32>>>(
+    value: &T,
+    range: RangeInclusive<f32>,
+) -> form::Result<'v, ()> {
+    match (*value).into() {
+        Some(value) if !range.contains(&value) => Err(form::Error::validation(format!(
+            "must be between {} and {}",
+            range.start(),
+            range.end()
+        )))?,
+        _ => Ok(()),
+    }
+}
+
 fn supported_file_types<'v>(file: &TempFile<'_>) -> form::Result<'v, ()> {
     if let Some(file_ct) = file.content_type() {
         if file_ct == &ContentType::PNG
             || file_ct == &ContentType::JPEG
             || file_ct == &ContentType::WEBP
             || file_ct == &ContentType::BMP
+            || file_ct == &ContentType::GIF
         {
             return Ok(());
         }
     }
 
     let msg = match file.content_type().and_then(|c| c.extension()) {
-        Some(a) => format!("invalid file type: .{}, must be PNG, JPEG, BMP or WEBP", a),
-        None => format!("file type must be PNG, JPEG, BMP or WEBP"),
+        Some(a) => format!(
+            "invalid file type: .{}, must be PNG, JPEG, BMP, GIF or WEBP",
+            a
+        ),
+        None => "file type must be PNG, JPEG, BMP, GIF or WEBP".to_string(),
     };
 
     Err(Error::validation(msg))?
@@ -258,17 +677,33 @@ fn supported_file_types<'v>(file: &TempFile<'_>) -> form::Result<'v, ()> {
 
 // ------- Routes ---------- //
 #[get("/")]
-async fn view_index(km: &State<KindleM>) -> Result<Markup, ServerError> {
-    let session = km.manager.new_session().await;
+async fn view_index(
+    user: User,
+    config: &State<Config>,
+    km: &KindleM,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let tags = catalog.tags()?;
+    let session = km.pool.get().await;
     match session {
-        Ok(session) => match km.manager.list_files(&session).await {
-            Ok(filenames) => Ok(pages::main(Some(&filenames))),
+        Ok(session) => match km.manager.list_files(&*session).await {
+            Ok(filenames) => {
+                catalog
+                    .update_on_kindle(&km.name, &HashSet::from_iter(filenames.iter().cloned()))?;
+                let records = kindle_records(catalog, filenames, SortBy::default(), None)?;
+                Ok(pages::main(
+                    &user.0,
+                    &config.conversion,
+                    Some(&records),
+                    &tags,
+                ))
+            }
             Err(err) => {
                 eprintln!("> Failed to acquire filenames");
                 eprintln!("{err}");
                 let (_, error_banner) = err.to_error_banner();
                 Ok(html! {
-                    (pages::main(None))
+                    (pages::main(&user.0, &config.conversion, None, &tags))
                     (error_banner)
                 })
             }
@@ -278,69 +713,112 @@ async fn view_index(km: &State<KindleM>) -> Result<Markup, ServerError> {
             eprintln!("{err}");
             let (_, error_banner) = err.to_error_banner();
             Ok(html! {
-                (pages::main(None))
+                (pages::main(&user.0, &config.conversion, None, &tags))
                 (error_banner)
             })
         }
     }
 }
 
+/// Images on the Kindle, sorted and filtered with the controls above them
+#[get("/library?<sort>&<tag>")]
+async fn library(
+    _user: User,
+    sort: Option<&str>,
+    tag: Option<&str>,
+    km: &KindleM,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let tag = tag.filter(|tag| !tag.is_empty());
+    let session = km.pool.get().await?;
+    let filenames = km.manager.list_files(&*session).await?;
+    let records = kindle_records(catalog, filenames, SortBy::from(sort), tag)?;
+    Ok(elements::server_images(Some(&records)))
+}
+
+#[get("/forms/tags/<image_name>")]
+async fn form_tags(
+    _user: User,
+    image_name: &str,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let tags = match catalog.get(image_name)? {
+        Some(record) => record.tags,
+        None => Vec::new(),
+    };
+    Ok(elements::show_edit_image_tags(image_name, &tags))
+}
+
+#[patch("/tags/<image_name>", data = "<tags>")]
+async fn set_tags(
+    _admin: Admin,
+    image_name: &str,
+    tags: Form<TagsForm>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let mut tags: Vec<String> = tags
+        .text
+        .split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+
+    if catalog.get(image_name)?.is_none() {
+        catalog.insert(&ImageRecord {
+            checksum: catalog::checksum(&config.converted(image_name))?,
+            ..ImageRecord::new(image_name)
+        })?;
+    }
+    catalog.set_tags(image_name, &tags)?;
+    Ok(elements::show_image_tags(image_name, &tags))
+}
+
 #[get("/forms/rename/<image_name>")]
-async fn form_rename(image_name: &str) -> Markup {
+async fn form_rename(_user: User, image_name: &str) -> Markup {
     elements::show_edit_image_name(image_name)
 }
 
 #[patch("/images/<image_name>", data = "<new_name>")]
+#[allow(clippy::too_many_arguments)]
 async fn rename_image(
-    km: &State<KindleM>,
+    _admin: Admin,
+    config: &State<Config>,
+    km: &KindleM,
+    devices: &State<Devices>,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
     image_name: &str,
     new_name: Form<FilenameForm>,
 ) -> (Status, Markup) {
     let new_name = format!("{}.png", new_name.text);
     let image_name = format!("{}.png", image_name);
 
-    async fn rename(
-        km: &State<KindleM>,
-        image_name: &str,
-        new_name: &str,
-    ) -> Result<(Status, Markup), ServerError> {
-        if new_name == image_name {
-            println!("No change in image name, not renaming.");
-            return Ok((Status::Ok, elements::show_image(&image_name)));
-        }
-
-        println!("Image name is {image_name}, renaming to {new_name}");
-
-        let session = km.manager.new_session().await?;
-        km.manager
-            .rename_file(&session, &format!("{image_name}"), &format!("{new_name}"))
-            .await?;
-
-        // As long as the renaming operation was successful on the Kindle, we can continue
-        if let Err(err) = fs::rename(
-            format!("converted/{image_name}"),
-            format!("converted/{new_name}"),
-        ) {
-            eprintln!("Failed conversion on the converted/ folder, continuing as normal");
-            eprintln!("{err}")
-        }
-
-        if let Err(err) = fs::rename(format!("images/{image_name}"), format!("images/{new_name}")) {
-            eprintln!("Failed conversion on the images/ folder, continuing as normal");
-            eprintln!("{err}")
-        }
-
-        Ok((Status::Ok, elements::show_image(&new_name)))
-    }
-
-    match rename(&km, &image_name, &new_name).await {
-        Ok((status, body)) => (status, body),
+    let renamed = match rename(
+        config,
+        km,
+        devices,
+        catalog,
+        playlists,
+        &image_name,
+        &new_name,
+    )
+    .await
+    {
+        Ok(()) => show_image(catalog, &new_name),
+        Err(err) => Err(err),
+    };
+    match renamed {
+        Ok(body) => (Status::Ok, body),
         Err(err) => {
             let (_, error_banner) = err.to_error_banner();
+            let record = ImageRecord::new(&image_name);
             (
                 Status::Ok,
                 html! {
-                    (elements::show_image(&image_name))
+                    (elements::show_image(&record))
                     (error_banner)
                 },
             )
@@ -348,22 +826,101 @@ async fn rename_image(
     }
 }
 
+/// Renames the image on the Kindle, on the other devices holding it, then on the server
+async fn rename(
+    config: &Config,
+    km: &KindleM,
+    devices: &Devices,
+    catalog: &Catalog,
+    playlists: &Playlists,
+    image_name: &str,
+    new_name: &str,
+) -> Result<(), ServerError> {
+    if new_name == image_name {
+        println!("No change in image name, not renaming.");
+        return Ok(());
+    }
+
+    config.check_filename(new_name)?;
+    println!("Image name is {image_name}, renaming to {new_name}");
+
+    let session = km.pool.get().await?;
+    km.manager
+        .rename_file(&*session, image_name, new_name)
+        .await?;
+
+    // A device that can't follow keeps the old name, its next sync deletes it and pushes the new one
+    let holders = catalog
+        .get(image_name)?
+        .map(|record| record.devices)
+        .unwrap_or_default();
+    let mut not_renamed = Vec::new();
+    for other in holders
+        .iter()
+        .filter_map(|device| devices.get(device))
+        .filter(|other| other.name != km.name)
+    {
+        let renamed = match other.pool.get().await {
+            Ok(session) => {
+                other
+                    .manager
+                    .rename_file(&*session, image_name, new_name)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = renamed {
+            eprintln!("> Failed to rename {image_name} on {}", other.name);
+            eprintln!("{err}");
+            not_renamed.push(other.name.as_str());
+        }
+    }
+
+    // As long as the renaming operation was successful on the Kindle, we can continue
+    if let Err(err) = fs::rename(config.converted(image_name), config.converted(new_name)) {
+        eprintln!("Failed conversion on the converted/ folder, continuing as normal");
+        eprintln!("{err}")
+    }
+
+    if let Err(err) = fs::rename(config.original(image_name), config.original(new_name)) {
+        eprintln!("Failed conversion on the images/ folder, continuing as normal");
+        eprintln!("{err}")
+    }
+    catalog.rename(image_name, new_name, &not_renamed)?;
+    playlists.replace_image(image_name, Some(new_name));
+
+    Ok(())
+}
+
 #[post("/", data = "<form>")]
 async fn submit_image_form(
+    _admin: Admin,
     mut form: Form<UploadImage<'_>>,
-    server_images: &State<ServerImages>,
-    km: &State<KindleM>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
 ) -> Result<Markup, ServerError> {
     // Establish connection to Kindle
-    let session = km.manager.new_session().await?;
+    let session = km.pool.get().await?;
+    upload(&mut form, config, km, catalog, &*session).await?;
+    Ok(oob_swap_server_images(km, catalog, &*session).await)
+}
 
+/// Stores, converts and pushes the uploaded image, returning its name
+async fn upload(
+    form: &mut UploadImage<'_>,
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    session: &dyn KindleTransport,
+) -> Result<String, ServerError> {
     // Save file to server
     let og_file_extension = form
         .file
         .content_type()
-        .expect("Failed to get content type")
+        .ok_or_else(|| ServerError::BadRequest("the uploaded file has no content type".into()))?
         .extension()
-        .expect("Failed to get extension")
+        .ok_or_else(|| ServerError::InvalidInput("the uploaded file's type isn't supported".into()))?
         .to_string();
 
     // Filename should already be checked by form validation, but this guarantees that
@@ -371,83 +928,67 @@ async fn submit_image_form(
     // TODO: Check for repeated filenames
     let user_filename = FileName::new(form.filename)
         .as_str()
-        .unwrap_or_else(|| {
-            form.file
-                .name()
-                .expect("Invalid filename and failed to get filename from upload")
-        })
+        .or_else(|| form.file.name())
+        .ok_or_else(|| ServerError::InvalidInput("the image needs a valid name".into()))?
         .to_string();
+    config.check_filename(&user_filename)?;
 
+    let original_name = match form.file.name() {
+        Some(name) => format!("{name}.{og_file_extension}"),
+        None => format!("{user_filename}.{og_file_extension}"),
+    };
     let mut full_filename = format!("{}.{}", user_filename, og_file_extension);
     form.file
-        .persist_to(format!("images/{}", full_filename))
+        .persist_to(config.original(&full_filename))
         .await?;
 
     // Convert image to png in the server if it's not a PNG already
-    // Also reduce it's size if needed
-    let output = Command::new("magick")
-        .arg(format!("images/{}", full_filename))
-        .args(["-resize", "1516x2048>"])
-        .arg(format!("images/{}.png", user_filename))
-        .output()
-        .expect("Failed to convert image to PNG");
-    if !output.status.success() {
-        let stdout = String::from_utf8(output.stdout).unwrap();
-        let stderr = String::from_utf8(output.stderr).unwrap();
-
-        eprintln!("out: {stdout}");
-        eprintln!("err: {stderr}");
-    }
-    if form.file.content_type() != Some(&ContentType::PNG) {
-        fs::remove_file(format!("images/{}", full_filename)).expect(&format!(
-            "Failed to delete original file \"{}\"",
-            full_filename
-        ));
-    }
+    // Also reduce it's size if needed, keeping twice the Kindle's resolution
+    let profile = km.profile().await;
+    let original = config.original(&full_filename);
+    let (width, height) = image_converter::dimensions(&original)?;
     full_filename = format!("{}.png", user_filename);
-
+    let stored = config.original(&full_filename);
     if form.horizontal {
         println!("Submitted image is being rotated by 90 degrees");
-        let output = Command::new("magick")
-            .arg(format!("images/{}.png", user_filename))
-            .args(["-rotate", "90"])
-            .arg(format!("images/{}.png", user_filename))
-            .output()
-            .expect("Failed to convert image to PNG");
-        if !output.status.success() {
-            let stdout = String::from_utf8(output.stdout).unwrap();
-            let stderr = String::from_utf8(output.stderr).unwrap();
-
-            eprintln!("out: {stdout}");
-            eprintln!("err: {stderr}");
-        }
-    }
-
-    // Get Background Color
-    println!("---------------------- {}", form.background_color);
-    let bg_color = match form.background_color {
-        "white" => "white",
-        "light_gray" => "gray60",
-        "dark_gray" => "gray20",
-        "black" => "black",
-        _ => "white",
-    };
-    println!("------------- matched: {}", bg_color);
+    }
+    let (max_width, max_height, rotate) = (profile.width * 2, profile.height * 2, form.horizontal);
+    let (original_path, stored_path) = (original.clone(), stored.clone());
+    spawn_blocking(move || {
+        image_converter::store_original(&original_path, &stored_path, max_width, max_height, rotate)
+    })
+    .await
+    .map_err(|err| ServerError::Other(err.to_string()))??;
+    if original != stored {
+        fs::remove_file(&original)?;
+    }
 
-    // TODO: Allow changing background fill - Enum for background
     // Convert image to Kindle-appropriate format
-    match image_converter::convert_image(
-        &bg_color,
-        form.stretch,
-        &PathBuf::from(format!("images/{full_filename}")),
-        &PathBuf::from(format!("converted/{full_filename}")),
-    ) {
+    let settings = form.settings(&config.conversion);
+    let options = settings.options(km.palette.clone());
+    let converted = config.converted(&full_filename);
+    let converted_path = converted.clone();
+    let conversion = spawn_blocking(move || {
+        image_converter::convert_image(&options, &profile, &stored, &converted_path)
+    })
+    .await
+    .map_err(|err| ServerError::Other(err.to_string()))?;
+    match conversion {
         Ok(_) => {
-            server_images
-                .images
-                .lock()
-                .unwrap()
-                .insert(format!("{}", full_filename));
+            // Tags are kept when an image is uploaded again with the same name
+            let tags = catalog
+                .get(&full_filename)?
+                .map(|record| record.tags)
+                .unwrap_or_default();
+            catalog.insert(&ImageRecord {
+                original_name,
+                settings: Some(settings),
+                checksum: catalog::checksum(&converted)?,
+                width,
+                height,
+                tags,
+                ..ImageRecord::new(&full_filename)
+            })?;
         }
         Err(error) => {
             println!(
@@ -460,216 +1001,1554 @@ async fn submit_image_form(
 
     // Push file to Kindle and set it
     km.manager
-        .push_file(
-            &session,
-            &PathBuf::from(format!("converted/{}", full_filename)),
-            &full_filename,
-        )
+        .push_file(session, &converted, &full_filename)
         .await?;
+    catalog.set_on_kindle(&km.name, &full_filename, true)?;
     if form.set_image {
-        km.manager.set_image(&session, &full_filename).await?;
+        km.manager.set_image(session, &full_filename).await?;
+        catalog.mark_displayed(&km.name, &full_filename)?;
     }
 
-    Ok(oob_swap_server_images(km, &session).await)
+    Ok(full_filename)
 }
 
-#[post("/set", data = "<image_name>")]
-async fn set_image(
-    image_name: Form<FilenameForm>,
-    km: &State<KindleM>,
-) -> Result<Status, ServerError> {
-    let session = km.manager.new_session().await?;
-    km.manager.set_image(&session, &image_name.text).await?;
-    return Ok(Status::Ok);
+/// Re-convert panel, with the settings of `image` if given, otherwise for every image with `tag`
+#[get("/forms/reconvert?<image>&<tag>")]
+fn form_reconvert(
+    _user: User,
+    image: Option<&str>,
+    tag: Option<&str>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let tag = tag.filter(|tag| !tag.is_empty());
+    // Only images whose original is still on the server can be converted again
+    let records: Vec<ImageRecord> = catalog
+        .list(SortBy::Name, tag)?
+        .into_iter()
+        .filter(|record| config.original(&record.name).is_file())
+        .collect();
+    let settings = image
+        .and_then(|image| records.iter().find(|record| record.name == image))
+        .and_then(|record| record.settings.clone())
+        .unwrap_or_else(|| config.conversion.clone());
+    Ok(elements::reconvert(&records, image, &settings))
 }
 
-#[post("/sync")]
-async fn sync(
-    server_images: &State<ServerImages>,
-    km: &State<KindleM>,
+/// Converts the selected images again from their originals, replacing them on the Kindle and
+/// setting the image on screen again if it was one of them
+#[post("/reconvert", data = "<form>")]
+async fn reconvert_images(
+    _admin: Admin,
+    form: Form<ReconvertImages<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
 ) -> Result<Markup, ServerError> {
-    let session = km.manager.new_session().await?;
-    match km.manager.list_files(&session).await {
-        Ok(image_names) => {
-            let kindle_images: HashSet<String> = HashSet::from_iter(image_names);
-
-            // Check for images on the server that aren't on the kindle
-            let images = server_images.images.lock().unwrap().clone();
-            for s_image in images {
-                if !kindle_images.contains(&s_image) {
-                    if let Err(err) = km
-                        .manager
-                        .push_file(
-                            &session,
-                            Path::new(&format!("converted/{}", s_image)),
-                            &s_image,
-                        )
-                        .await
-                    {
-                        eprintln!("> Failed to pull file!");
-                        eprintln!("{err}")
-                    }
-                    println!("Missing {} in the kindle", s_image);
-                }
-            }
-
-            // Check for images on the Kindle that aren't on the server
-            for k_image in &kindle_images {
-                if !server_images.images.lock().unwrap().contains(k_image) {
-                    if let Err(err) = km
-                        .manager
-                        .pull_file(
-                            &session,
-                            &k_image,
-                            Path::new(&format!("converted/{k_image}")),
-                        )
-                        .await
-                    {
-                        eprintln!("> Failed to push file!");
-                        eprintln!("{err}")
-                    }
-                    println!("Missing {} in the server", k_image);
-                }
+    let session = km.pool.get().await?;
+    let kindle_images: HashSet<String> =
+        HashSet::from_iter(km.manager.list_files(&*session).await?);
+    let current = catalog.last_displayed(&km.name)?;
+
+    let mut failed = 0;
+    for &image in &form.images {
+        let on_kindle = kindle_images.contains(image);
+        let on_screen = on_kindle && current.as_deref() == Some(image);
+        let reconverted = reconvert(config, km, catalog, &form, image).await;
+        let replaced = match reconverted {
+            Ok(converted) if on_kindle => {
+                replace_on_kindle(km, catalog, &*session, &converted, image, on_screen).await
             }
-            // Check kindle again for updated images
-            Ok(oob_swap_server_images(km, &session).await)
-        }
-        Err(err) => {
-            eprintln!(
-                "> Failed to acquire list of images on the Kindle, cancelling the Sync operation"
-            );
+            other => other.map(|_| ()),
+        };
+        if let Err(err) = replaced {
+            eprintln!("> Failed to re-convert {image}");
             eprintln!("{err}");
-            let (_, error_banner) = err.to_error_banner();
-            Ok(html! {
-                (oob::swap_server_images(None))
-                (error_banner)
-            })
+            failed += 1;
         }
     }
-}
 
-#[delete("/<filename>")]
-async fn delete_image(
-    filename: &str,
-    server_images: &State<ServerImages>,
-    km: &State<KindleM>,
-) -> Result<Markup, io::Error> {
-    let session = km
-        .manager
-        .new_session()
-        .await
-        .expect("Failed to create SSH session");
-    match fs::remove_file(format!("converted/{}", filename)) {
-        Ok(_) => {
-            server_images.images.lock().unwrap().remove(filename);
-        }
-        Err(error) => {
-            println!("Problem removing {}: {:?}", filename, error);
+    Ok(html! {
+        #reconvert {}
+        (oob_swap_server_images(km, catalog, &*session).await)
+        @if failed > 0 {
+            (oob::error_banner("Re-convert", &format!("{failed} images couldn't be converted again.")))
         }
-    }
+    })
+}
 
-    match fs::remove_file(format!("images/{}", filename)) {
-        Ok(_) => (),
-        Err(error) => {
-            println!("Problem removing {}: {:?}", filename, error);
-        }
+async fn reconvert(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    form: &ReconvertImages<'_>,
+    image: &str,
+) -> Result<PathBuf, ServerError> {
+    let record = catalog
+        .get(image)?
+        .ok_or_else(|| ServerError::NotFound(format!("image {image}")))?;
+    let stored = config.original(image);
+    if !stored.is_file() {
+        return Err(ServerError::NotFound(format!("original of {image}")));
     }
 
-    if let Err(err) = km.manager.delete_file(&session, filename).await {
-        eprintln!("> Failed to delete file!");
-        eprintln!("{err}");
-    }
+    let previous = record.settings.unwrap_or_default();
+    let settings = form.settings(&previous);
+    let options = settings.options(km.palette.clone());
+    let profile = km.profile().await;
+    let converted = config.converted(image);
+    let converted_path = converted.clone();
+    let (turn, horizontal) = (
+        previous.horizontal != settings.horizontal,
+        settings.horizontal,
+    );
+    spawn_blocking(move || {
+        if turn {
+            image_converter::reorient_original(&stored, horizontal)?;
+        }
+        let conversion =
+            image_converter::convert_image(&options, &profile, &stored, &converted_path);
+        // Keeps the original matching the settings still in the catalog
+        if conversion.is_err() && turn {
+            image_converter::reorient_original(&stored, !horizontal)?;
+        }
+        conversion
+    })
+    .await
+    .map_err(|err| ServerError::Other(err.to_string()))??;
 
-    Ok(oob_swap_server_images(km, &session).await)
+    catalog.set_conversion(image, &settings, &catalog::checksum(&converted)?)?;
+    Ok(converted)
 }
 
-// Route /stats
-#[get("/battery")]
-async fn stats_battery(km: &State<KindleM>) -> Markup {
-    let session = km
-        .manager
-        .new_session()
-        .await
-        .expect("Failed to create SSH session");
-    match km.manager.battery_charge(&session).await {
-        Ok(battery) => html! { "Battery: " (battery) "%" },
-        Err(err) => {
-            eprintln!("> Failed to get battery info");
-            eprintln!("{err}");
-            html! { "Battery: ??" }
-        }
+async fn replace_on_kindle(
+    km: &KindleM,
+    catalog: &Catalog,
+    session: &dyn KindleTransport,
+    converted: &Path,
+    image: &str,
+    on_screen: bool,
+) -> Result<(), ServerError> {
+    km.manager.replace_file(session, converted, image).await?;
+    catalog.set_on_kindle(&km.name, image, true)?;
+    if on_screen {
+        km.manager.set_image(session, image).await?;
+        catalog.mark_displayed(&km.name, image)?;
     }
+    Ok(())
 }
 
-#[get("/files")]
-async fn stats_files(km: &State<KindleM>) -> Markup {
-    let session = km
-        .manager
-        .new_session()
-        .await
-        .expect("Failed to create SSH session");
-    let count_kindle = match km.manager.list_files(&session).await {
-        Ok(images) => format!("{}", images.len()),
-        Err(err) => {
-            eprintln!("> Failed to get number of files on the Kindle");
-            eprintln!("{err}");
-            "??".into()
-        }
-    };
-    // let count_kindle = km::get_filenames().len();
-    let count_server = fs::read_dir("converted").unwrap().count();
-    html! { ."text-white/70" { "Kindle/Server files: " (count_kindle)"/"(count_server) }}
+/// Converts the uploaded image without pushing it, so the result can be checked first
+#[post("/preview", data = "<form>")]
+async fn preview_image(
+    _user: User,
+    form: Form<UploadImage<'_>>,
+    config: &State<Config>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let mut data = Vec::new();
+    form.file.open().await?.read_to_end(&mut data).await?;
+
+    let profile = km.profile().await;
+    let options = form
+        .settings(&config.conversion)
+        .options(km.palette.clone());
+    let horizontal = form.horizontal;
+    let png =
+        spawn_blocking(move || image_converter::preview(&data, horizontal, &options, &profile))
+            .await
+            .map_err(|err| ServerError::Other(err.to_string()))??;
+
+    Ok(elements::kindle_preview(
+        &BASE64_STANDARD.encode(png),
+        horizontal,
+    ))
 }
 
-// ------ Rocket Setup --------- //
+#[post("/set", data = "<image_name>")]
+async fn set_image(
+    _admin: Admin,
+    image_name: Form<FilenameForm>,
+    km: &KindleM,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Status, ServerError> {
+    let session = km.pool.get().await?;
+    km.manager.set_image(&*session, &image_name.text).await?;
+    catalog.mark_displayed(&km.name, &image_name.text)?;
+    Ok(Status::Ok)
+}
 
-fn setup_rocket() -> std::io::Result<()> {
-    // Create necessary dirs
-    println!(
-        "{}",
-        env::current_dir()
-            .expect("could not get curr dir")
-            .to_string_lossy()
-    );
-    fs::create_dir_all("./images/tmp")?;
-    fs::create_dir_all("./converted")?;
+#[get("/print")]
+fn form_print_text(_user: User, km: &KindleM) -> Markup {
+    elements::print_text(&km.name, &TextOptions::default(), "", false)
+}
 
-    Ok(())
+/// Prints a message over the image on the Kindle, the form keeps its options for the next one
+#[post("/print", data = "<form>")]
+async fn print_text(
+    _admin: Admin,
+    form: Form<PrintText<'_>>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let options = form.options()?;
+    let session = km.pool.get().await?;
+    km.manager
+        .print_text(&*session, form.text, &options)
+        .await?;
+    Ok(elements::print_text(&km.name, &options, form.text, true))
 }
 
-#[launch]
+// Route /sync
+/// Dry-run of the sync, showing what would change before applying it
+#[get("/")]
+async fn sync_panel(
+    _user: User,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let session = km.pool.get().await?;
+    let (plan, _, _) = sync_plan(config, km, catalog, &*session).await?;
+    Ok(elements::sync_plan(&plan))
+}
+
+#[post("/", data = "<form>")]
+async fn sync_apply(
+    _admin: Admin,
+    form: Form<ApplySync<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let session = km.pool.get().await?;
+    let results = apply_sync(config, km, catalog, playlists, &*session, &form).await?;
+    Ok(html! {
+        (elements::sync_results(&results))
+        (oob_swap_server_images(km, catalog, &*session).await)
+    })
+}
+
+/// Checksums of every converted image on the server, by name
+fn server_checksums(converted_dir: &Path) -> io::Result<HashMap<String, String>> {
+    let mut checksums = HashMap::new();
+    for entry in fs::read_dir(converted_dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if entry.file_type()?.is_file() {
+            checksums.insert(name, catalog::checksum(&entry.path())?);
+        }
+    }
+    Ok(checksums)
+}
+
+/// Plan making the server and the Kindle match, along with the checksums on each side
+async fn sync_plan(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    session: &dyn KindleTransport,
+) -> Result<
+    (
+        Vec<SyncEntry>,
+        HashMap<String, String>,
+        HashMap<String, String>,
+    ),
+    ServerError,
+> {
+    let converted_dir = config.converted_dir.clone();
+    let server = spawn_blocking(move || server_checksums(&converted_dir))
+        .await
+        .map_err(|err| ServerError::Other(err.to_string()))??;
+    let kindle = km.manager.checksums(session).await?;
+    let synced = catalog.synced_checksums(&km.name)?;
+
+    // The converted images are shared by every device, only the ones made for this one are pushed
+    let mut plan = Vec::new();
+    for entry in sync::plan(&server, &kindle, &synced) {
+        if entry.action == SyncAction::Push && !pushable(config, km, catalog, &entry).await? {
+            continue;
+        }
+        plan.push(entry);
+    }
+    Ok((plan, server, kindle))
+}
+
+/// Whether a pushed image was converted for the device and isn't only another device's image
+async fn pushable(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    entry: &SyncEntry,
+) -> Result<bool, ServerError> {
+    let elsewhere = catalog.get(&entry.name)?.is_some_and(|record| {
+        entry.kindle.is_none() && !record.devices.is_empty() && !record.devices.contains(&km.name)
+    });
+    Ok(!elsewhere && fits_device(km, &config.converted(&entry.name)).await?)
+}
+
+/// Applies the sync plan, conflicts are skipped unless the form picks a side to keep
+async fn apply_sync(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    playlists: &Playlists,
+    session: &dyn KindleTransport,
+    form: &ApplySync<'_>,
+) -> Result<Vec<SyncResult>, ServerError> {
+    let (plan, server, kindle) = sync_plan(config, km, catalog, session).await?;
+
+    // Images that are already the same on both sides count as synced from now on
+    for (name, checksum) in &server {
+        if kindle.get(name) == Some(checksum) {
+            mark_synced(config, catalog, &km.name, name, checksum)?;
+        }
+    }
+
+    let mut results = Vec::new();
+    for entry in plan {
+        let resolution = form.resolve.get(entry.name.as_str()).copied().flatten();
+        let action = match (entry.action, resolution.or(form.prefer)) {
+            (SyncAction::Conflict, Some(side)) => entry.resolve(side),
+            (action, _) => action,
+        };
+        let error = match action {
+            SyncAction::Conflict => Some("Changed on both sides, pick the version to keep".into()),
+            action => apply_sync_entry(config, km, catalog, playlists, session, &entry, action)
+                .await
+                .err()
+                .map(|err| {
+                    eprintln!("> Failed to sync {}", entry.name);
+                    eprintln!("{err}");
+                    err.to_string()
+                }),
+        };
+        results.push(SyncResult {
+            name: entry.name,
+            action,
+            error,
+        });
+    }
+
+    Ok(results)
+}
+
+async fn apply_sync_entry(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    playlists: &Playlists,
+    session: &dyn KindleTransport,
+    entry: &SyncEntry,
+    action: SyncAction,
+) -> Result<(), ServerError> {
+    let name = entry.name.as_str();
+    let converted = config.converted(name);
+    match action {
+        SyncAction::Push => {
+            ensure_fits(km, &converted, name).await?;
+            km.manager.replace_file(session, &converted, name).await?;
+            mark_synced(
+                config,
+                catalog,
+                &km.name,
+                name,
+                entry.server.as_deref().unwrap_or_default(),
+            )?;
+        }
+        SyncAction::Pull => {
+            km.manager.pull_file(session, name, &converted).await?;
+            mark_synced(
+                config,
+                catalog,
+                &km.name,
+                name,
+                entry.kindle.as_deref().unwrap_or_default(),
+            )?;
+        }
+        SyncAction::DeleteFromKindle => {
+            km.manager.delete_file(session, name).await?;
+            catalog.remove(name)?;
+            catalog.set_on_kindle(&km.name, name, false)?;
+            playlists.replace_image(name, None);
+        }
+        SyncAction::DeleteFromServer => {
+            catalog.set_on_kindle(&km.name, name, false)?;
+            // The server copy is shared, it stays while another device still holds the image
+            let held = catalog
+                .get(name)?
+                .is_some_and(|record| !record.devices.is_empty());
+            if !held {
+                fs::remove_file(&converted)?;
+                // The original may already be gone
+                let _ = fs::remove_file(config.original(name));
+                catalog.remove(name)?;
+                playlists.replace_image(name, None);
+            }
+        }
+        SyncAction::Conflict => {}
+    }
+    Ok(())
+}
+
+/// Records that the server and the device both have the image with `checksum`
+fn mark_synced(
+    config: &Config,
+    catalog: &Catalog,
+    device: &str,
+    name: &str,
+    checksum: &str,
+) -> Result<(), ServerError> {
+    match catalog.get(name)? {
+        Some(record) if record.checksum == checksum => {}
+        Some(_) => catalog.set_checksum(name, checksum)?,
+        None => {
+            let (width, height) =
+                image_converter::dimensions(&config.converted(name)).unwrap_or((0, 0));
+            catalog.insert(&ImageRecord {
+                checksum: checksum.to_string(),
+                width,
+                height,
+                ..ImageRecord::new(name)
+            })?;
+        }
+    }
+    catalog.set_on_kindle(device, name, true)?;
+    Ok(())
+}
+
+#[delete("/<filename>")]
+async fn delete_image(
+    _admin: Admin,
+    filename: &str,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    playlists: &State<Playlists>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let session = km.pool.get().await?;
+    delete(config, km, catalog, playlists, &*session, filename).await?;
+    Ok(oob_swap_server_images(km, catalog, &*session).await)
+}
+
+/// Deletes the image from the Kindle, and from the server unless another device still holds it.
+/// Returns whether either had it.
+async fn delete(
+    config: &Config,
+    km: &KindleM,
+    catalog: &Catalog,
+    playlists: &Playlists,
+    session: &dyn KindleTransport,
+    filename: &str,
+) -> Result<bool, ServerError> {
+    let mut found = false;
+    match km.manager.delete_file(session, filename).await {
+        Ok(()) => {
+            found = true;
+            catalog.set_on_kindle(&km.name, filename, false)?;
+        }
+        Err(err) => {
+            eprintln!("> Failed to delete file!");
+            eprintln!("{err}");
+        }
+    }
+
+    // The server copy is shared, it stays while another device still holds the image
+    let held = catalog
+        .get(filename)?
+        .is_some_and(|record| record.devices.iter().any(|device| *device != km.name));
+    if held {
+        return Ok(true);
+    }
+
+    match fs::remove_file(config.converted(filename)) {
+        Ok(_) => {
+            found = true;
+            catalog.remove(filename)?;
+            playlists.replace_image(filename, None);
+        }
+        Err(error) => {
+            println!("Problem removing {}: {:?}", filename, error);
+        }
+    }
+
+    match fs::remove_file(config.original(filename)) {
+        Ok(_) => (),
+        Err(error) => {
+            println!("Problem removing {}: {:?}", filename, error);
+        }
+    }
+
+    Ok(found)
+}
+
+// Route /slideshow
+#[get("/status")]
+fn slideshow_status(_user: User, slideshow: &State<Arc<Slideshow>>) -> Json<SlideshowStatus> {
+    Json(slideshow.state().status())
+}
+
+#[get("/")]
+async fn slideshow_panel(
+    _user: User,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+) -> Markup {
+    show_slideshow(devices.default(), slideshow).await
+}
+
+/// The slideshow always runs on the default device
+async fn show_slideshow(km: &KindleM, slideshow: &Slideshow) -> Markup {
+    let images = match km.pool.get().await {
+        Ok(session) => km.manager.list_files(&*session).await,
+        Err(err) => Err(err),
+    };
+    match images {
+        Ok(images) => elements::slideshow(&km.name, &slideshow.state(), Some(&images)),
+        Err(err) => {
+            eprintln!("> Failed to acquire image names for the slideshow");
+            eprintln!("{err}");
+            let (_, error_banner) = err.to_error_banner();
+            html! {
+                (elements::slideshow(&km.name, &slideshow.state(), None))
+                (error_banner)
+            }
+        }
+    }
+}
+
+#[post("/start", data = "<form>")]
+async fn slideshow_start(
+    _admin: Admin,
+    form: Form<StartSlideshow<'_>>,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Markup {
+    match start_slideshow(&form, slideshow, dashboard) {
+        Ok(()) => html! {
+            (show_slideshow(devices.default(), slideshow).await)
+            (elements::refresh_dashboard())
+        },
+        Err(err) => {
+            let (_, error_banner) = err.to_error_banner();
+            html! {
+                (show_slideshow(devices.default(), slideshow).await)
+                (error_banner)
+            }
+        }
+    }
+}
+
+/// Starts the slideshow of the picked images, stopping the dashboard since both show on the
+/// default device
+fn start_slideshow(
+    form: &StartSlideshow<'_>,
+    slideshow: &Slideshow,
+    dashboard: &Dashboard,
+) -> Result<(), ServerError> {
+    let slides: Vec<Slide> = form
+        .slides
+        .iter()
+        .filter(|slide| slide.enabled)
+        .map(|slide| Slide {
+            image: slide.image.to_string(),
+            duration: slide.duration.filter(|&duration| duration > 0),
+        })
+        .collect();
+    if slides.is_empty() {
+        return Err(ServerError::InvalidInput(
+            "pick at least one image to start the slideshow".into(),
+        ));
+    }
+    let refresh = form.refresh.settings()?;
+
+    let order = if form.shuffle {
+        Order::Shuffle
+    } else {
+        Order::Sequential
+    };
+    println!("Starting a slideshow of {} images", slides.len());
+    dashboard.stop();
+    slideshow.start(slides, form.interval, order, refresh);
+    Ok(())
+}
+
+#[post("/stop")]
+async fn slideshow_stop(
+    _admin: Admin,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+) -> Markup {
+    slideshow.stop();
+    show_slideshow(devices.default(), slideshow).await
+}
+
+#[post("/skip")]
+async fn slideshow_skip(
+    _admin: Admin,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+) -> Markup {
+    slideshow.skip();
+    show_slideshow(devices.default(), slideshow).await
+}
+
+// Route /dashboard
+/// The dashboard always runs on the default device
+fn show_dashboard(config: &Config, devices: &Devices, dashboard: &Dashboard) -> Markup {
+    elements::dashboard(
+        &devices.default().name,
+        &dashboard.state(),
+        &config.dashboard,
+    )
+}
+
+#[get("/")]
+fn dashboard_panel(
+    _user: User,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Markup {
+    show_dashboard(config, devices, dashboard)
+}
+
+/// Takes over the Kindle from the slideshow
+#[post("/start")]
+fn dashboard_start(
+    _admin: Admin,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    dashboard: &State<Arc<Dashboard>>,
+    slideshow: &State<Arc<Slideshow>>,
+) -> Result<Markup, ServerError> {
+    dashboard.start(&config.dashboard)?;
+    println!("Starting the dashboard on {}", devices.default().name);
+    slideshow.stop();
+    Ok(html! {
+        (show_dashboard(config, devices, dashboard))
+        (elements::refresh_slideshow())
+    })
+}
+
+#[post("/stop")]
+fn dashboard_stop(
+    _admin: Admin,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Markup {
+    dashboard.stop();
+    show_dashboard(config, devices, dashboard)
+}
+
+#[post("/refresh")]
+fn dashboard_refresh(
+    _admin: Admin,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Markup {
+    dashboard.refresh();
+    show_dashboard(config, devices, dashboard)
+}
+
+#[post("/content", data = "<form>")]
+fn dashboard_content(
+    _admin: Admin,
+    form: Form<DashboardContent>,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Markup {
+    let form = form.into_inner();
+    dashboard.set_content(form.notes, form.todo);
+    show_dashboard(config, devices, dashboard)
+}
+
+// Route /playlists
+fn find_playlist(playlists: &Playlists, name: &str) -> Result<Playlist, ServerError> {
+    playlists
+        .get(name)
+        .ok_or_else(|| ServerError::NotFound(format!("playlist {name}")))
+}
+
+#[get("/")]
+fn playlists_panel(_user: User, playlists: &State<Playlists>) -> Markup {
+    elements::playlists(&playlists.all())
+}
+
+#[post("/", data = "<form>")]
+fn create_playlist(
+    _admin: Admin,
+    form: Form<NewPlaylist<'_>>,
+    playlists: &State<Playlists>,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let playlist = playlists.get(form.name).unwrap_or_else(|| {
+        let playlist = Playlist {
+            name: form.name.to_string(),
+            items: Vec::new(),
+        };
+        playlists.save(playlist.clone());
+        playlist
+    });
+    edit_playlist_panel(&playlist, catalog)
+}
+
+fn edit_playlist_panel(playlist: &Playlist, catalog: &Catalog) -> Result<Markup, ServerError> {
+    let mut images = Vec::from_iter(catalog.names()?);
+    images.sort();
+    Ok(elements::playlist_editor(playlist, &images))
+}
+
+#[get("/<name>")]
+fn edit_playlist(
+    _user: User,
+    name: &str,
+    playlists: &State<Playlists>,
+    catalog: &State<Arc<Catalog>>,
+) -> Result<Markup, ServerError> {
+    let playlist = find_playlist(playlists, name)?;
+    edit_playlist_panel(&playlist, catalog)
+}
+
+#[post("/<name>", data = "<form>")]
+fn save_playlist(
+    _admin: Admin,
+    name: &str,
+    form: Form<EditPlaylist<'_>>,
+    playlists: &State<Playlists>,
+) -> Result<Markup, ServerError> {
+    let mut playlist = find_playlist(playlists, name)?;
+
+    let mut items: Vec<&PlaylistItemForm> = form.items.iter().filter(|i| i.enabled).collect();
+    // Stable sort, so items with the same position keep the order of the form
+    items.sort_by_key(|item| item.position.unwrap_or(u32::MAX));
+    playlist.items = items
+        .into_iter()
+        .map(|item| Slide {
+            image: item.image.to_string(),
+            duration: item.duration.filter(|&duration| duration > 0),
+        })
+        .collect();
+
+    playlists.save(playlist);
+    Ok(elements::playlists(&playlists.all()))
+}
+
+#[delete("/<name>")]
+fn delete_playlist(_admin: Admin, name: &str, playlists: &State<Playlists>) -> Markup {
+    playlists.remove(name);
+    elements::playlists(&playlists.all())
+}
+
+#[post("/<name>/slideshow", data = "<form>")]
+fn play_playlist(
+    _admin: Admin,
+    name: &str,
+    form: Form<PlayPlaylist>,
+    playlists: &State<Playlists>,
+    slideshow: &State<Arc<Slideshow>>,
+    dashboard: &State<Arc<Dashboard>>,
+) -> Result<Markup, ServerError> {
+    let playlist = find_playlist(playlists, name)?;
+    if playlist.items.is_empty() {
+        return Ok(html! {
+            (elements::playlists(&playlists.all()))
+            (oob::error_banner("Slideshow", "The playlist has no images to show."))
+        });
+    }
+
+    let order = if form.shuffle {
+        Order::Shuffle
+    } else {
+        Order::Sequential
+    };
+    println!("Starting a slideshow of playlist {name}");
+    dashboard.stop();
+    // Playlists keep the screen refresh the slideshow was last started with
+    let refresh = slideshow.state().refresh;
+    slideshow.start(playlist.items, form.interval, order, refresh);
+    Ok(html! {
+        (elements::playlists(&playlists.all()))
+        (elements::refresh_slideshow())
+        (elements::refresh_dashboard())
+    })
+}
+
+/// Pushes every image of the playlist that isn't on the Kindle yet, images converted for another
+/// screen are refused like single pushes
+#[post("/<name>/push")]
+async fn push_playlist(
+    _admin: Admin,
+    name: &str,
+    config: &State<Config>,
+    playlists: &State<Playlists>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let playlist = find_playlist(playlists, name)?;
+    let session = km.pool.get().await?;
+    let kindle_images: HashSet<String> =
+        HashSet::from_iter(km.manager.list_files(&*session).await?);
+
+    let mut failed = 0;
+    for item in &playlist.items {
+        if kindle_images.contains(&item.image) {
+            continue;
+        }
+        let path = config.converted(&item.image);
+        if let Err(err) = push_to_device(km, catalog, &path, &item.image, false).await {
+            eprintln!("> Failed to push {} from playlist {name}", item.image);
+            eprintln!("{err}");
+            failed += 1;
+        }
+    }
+
+    Ok(html! {
+        (oob_swap_server_images(km, catalog, &*session).await)
+        @if failed > 0 {
+            (oob::error_banner("Playlist", &format!("{failed} images couldn't be pushed to the Kindle.")))
+        }
+    })
+}
+
+/// Deletes every image of the playlist from the Kindle, they are kept on the server
+#[post("/<name>/remove")]
+async fn remove_playlist(
+    _admin: Admin,
+    name: &str,
+    playlists: &State<Playlists>,
+    catalog: &State<Arc<Catalog>>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let playlist = find_playlist(playlists, name)?;
+    let session = km.pool.get().await?;
+    let kindle_images: HashSet<String> =
+        HashSet::from_iter(km.manager.list_files(&*session).await?);
+
+    let mut failed = 0;
+    for item in &playlist.items {
+        if !kindle_images.contains(&item.image) {
+            continue;
+        }
+        if let Err(err) = km.manager.delete_file(&*session, &item.image).await {
+            eprintln!("> Failed to delete {} from playlist {name}", item.image);
+            eprintln!("{err}");
+            failed += 1;
+        } else {
+            catalog.set_on_kindle(&km.name, &item.image, false)?;
+        }
+    }
+
+    Ok(html! {
+        (oob_swap_server_images(km, catalog, &*session).await)
+        @if failed > 0 {
+            (oob::error_banner("Playlist", &format!("{failed} images couldn't be deleted from the Kindle.")))
+        }
+    })
+}
+
+// Route /login and /logout
+#[get("/login")]
+fn login_page(
+    remote: Option<SocketAddr>,
+    accounts: &State<Accounts>,
+) -> Result<Markup, ServerError> {
+    let empty = accounts.is_empty()?;
+    let setup = empty && is_loopback(remote);
+    let error = (empty && !setup).then_some(NO_ACCOUNTS);
+    Ok(pages::login(error, setup))
+}
+
+/// Shown to remote visitors while there are no accounts, since anyone on the network could
+/// otherwise make themselves admin
+const NO_ACCOUNTS: &str =
+    "There are no accounts yet. Create the admin from /login on the server itself, \
+    or with the admin_name and admin_password settings.";
+
+/// Whether the request comes from the server itself. The peer address is used rather than the
+/// `X-Real-IP` header, which anyone can send.
+fn is_loopback(remote: Option<SocketAddr>) -> bool {
+    remote.is_some_and(|remote| remote.ip().is_loopback())
+}
+
+#[post("/login", data = "<form>")]
+fn login(
+    form: Form<LoginForm<'_>>,
+    remote: Option<SocketAddr>,
+    accounts: &State<Accounts>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, (Status, Markup)> {
+    let empty = accounts.is_empty().unwrap_or(false);
+    let setup = empty && is_loopback(remote);
+    if empty && !setup {
+        return Err((Status::Forbidden, pages::login(Some(NO_ACCOUNTS), false)));
+    }
+    match sign_in(&form, accounts, setup) {
+        Ok(Some(account)) => {
+            auth::sign_in(cookies, &account);
+            Ok(Redirect::to("/"))
+        }
+        Ok(None) if setup => Err((
+            Status::UnprocessableEntity,
+            pages::login(
+                Some("Names need up to 32 letters, numbers, dashes or underscores and passwords at least 8 characters."),
+                setup,
+            ),
+        )),
+        Ok(None) => Err((
+            Status::Unauthorized,
+            pages::login(Some("Wrong name or password."), setup),
+        )),
+        Err(err) => {
+            eprintln!("> Failed to sign in");
+            eprintln!("{err}");
+            Err((
+                Status::InternalServerError,
+                pages::login(Some("An error occurred on the Server."), setup),
+            ))
+        }
+    }
+}
+
+/// Account the login form signs in as. Before there are any accounts, it creates the first admin.
+fn sign_in(
+    form: &LoginForm<'_>,
+    accounts: &Accounts,
+    setup: bool,
+) -> Result<Option<Account>, ServerError> {
+    if !setup {
+        return accounts.verify_password(form.name, form.password);
+    }
+
+    if !config::valid_account(form.name, form.password) {
+        return Ok(None);
+    }
+    // Another request may have created the admin since the form was checked
+    if !accounts.create_first_admin(form.name, form.password)? {
+        return accounts.verify_password(form.name, form.password);
+    }
+    println!("Created the admin account {}", form.name);
+    Ok(accounts.get(form.name)?)
+}
+
+#[post("/logout")]
+fn logout(cookies: &CookieJar<'_>) -> Redirect {
+    auth::sign_out(cookies);
+    Redirect::to("/login")
+}
+
+// Route /accounts
+#[get("/")]
+fn accounts_page(admin: Admin, accounts: &State<Accounts>) -> Result<Markup, ServerError> {
+    Ok(pages::accounts(
+        &admin.0,
+        &accounts.list()?,
+        &accounts.tokens()?,
+    ))
+}
+
+/// Accounts panel after a change, failed changes show an error banner above the unchanged panel
+fn accounts_panel(
+    accounts: &Accounts,
+    changed: Result<Option<String>, ServerError>,
+) -> Result<Markup, ServerError> {
+    let (new_token, error_banner) = match changed {
+        Ok(new_token) => (new_token, None),
+        Err(err) => (None, Some(err.to_error_banner().1)),
+    };
+    Ok(html! {
+        (elements::accounts(&accounts.list()?, &accounts.tokens()?, new_token.as_deref()))
+        @if let Some(error_banner) = error_banner {
+            (error_banner)
+        }
+    })
+}
+
+#[post("/users", data = "<form>")]
+fn save_account(
+    admin: Admin,
+    form: Form<AccountForm<'_>>,
+    accounts: &State<Accounts>,
+    cookies: &CookieJar<'_>,
+) -> Result<Markup, ServerError> {
+    let saved = accounts.save(form.name, form.password, form.role);
+    // Saving signs the account out everywhere, the admin stays signed in here
+    if saved.is_ok() && form.name == admin.0.name {
+        if let Some(account) = accounts.get(form.name)? {
+            auth::sign_in(cookies, &account);
+        }
+    }
+    accounts_panel(accounts, saved.map(|_| None))
+}
+
+#[delete("/users/<name>")]
+fn delete_account(
+    _admin: Admin,
+    name: &str,
+    accounts: &State<Accounts>,
+) -> Result<Markup, ServerError> {
+    let removed = match accounts.remove(name) {
+        Ok(true) => Ok(None),
+        Ok(false) => Err(ServerError::NotFound(format!("account {name}"))),
+        Err(err) => Err(err),
+    };
+    accounts_panel(accounts, removed)
+}
+
+#[post("/tokens", data = "<form>")]
+fn create_token(
+    _admin: Admin,
+    form: Form<NewToken<'_>>,
+    accounts: &State<Accounts>,
+) -> Result<Markup, ServerError> {
+    let created = match accounts.get(form.user)? {
+        Some(account) => accounts.create_token(&account.name, form.label).map(Some),
+        None => Err(ServerError::NotFound(format!("account {}", form.user))),
+    };
+    accounts_panel(accounts, created)
+}
+
+#[delete("/tokens/<id>")]
+fn revoke_token(
+    _admin: Admin,
+    id: &str,
+    accounts: &State<Accounts>,
+) -> Result<Markup, ServerError> {
+    let revoked = match accounts.revoke_token(id)? {
+        true => Ok(None),
+        false => Err(ServerError::NotFound(format!("token {id}"))),
+    };
+    accounts_panel(accounts, revoked)
+}
+
+// Route /devices
+/// Device switcher in the nav, there's nothing to switch with a single device
+#[get("/switcher")]
+fn device_switcher(_user: User, devices: &State<Devices>, km: &KindleM) -> Markup {
+    elements::device_switcher(&devices.names(), &km.name)
+}
+
+#[derive(Debug, FromForm)]
+struct SelectDevice<'v> {
+    device: &'v str,
+}
+
+/// Makes HTMX reload the whole page
+#[derive(Responder)]
+struct HxRefresh {
+    inner: (),
+    refresh: Header<'static>,
+}
+
+/// Picks the device the pages work on, anyone can since it only changes what they look at
+#[post("/select", data = "<form>")]
+fn select_device(
+    _user: User,
+    form: Form<SelectDevice<'_>>,
+    devices: &State<Devices>,
+    cookies: &CookieJar<'_>,
+) -> Result<HxRefresh, ServerError> {
+    let km = devices
+        .get(form.device)
+        .ok_or_else(|| ServerError::NotFound(format!("device {}", form.device)))?;
+    devices::select(cookies, km);
+    Ok(HxRefresh {
+        inner: (),
+        refresh: Header::new("HX-Refresh", "true"),
+    })
+}
+
+/// Battery and images of every device
+#[get("/")]
+async fn devices_panel(
+    _user: User,
+    config: &State<Config>,
+    devices: &State<Devices>,
+    km: &KindleM,
+) -> Markup {
+    let mut statuses = Vec::new();
+    for device in devices.all() {
+        statuses.push(device.status(&config.converted_dir).await);
+    }
+    elements::devices(&statuses, &km.name)
+}
+
+/// Prepping the current device to show images, or restoring it to read on
+#[get("/mode")]
+fn device_mode(_user: User, km: &KindleM) -> Markup {
+    elements::device_mode(&km.name, None)
+}
+
+#[post("/prep")]
+async fn prep_device(_admin: Admin, km: &KindleM) -> Result<Markup, ServerError> {
+    let report = km.prep().await?;
+    let done = match report.changed() {
+        true => "Prepared, the Kindle stays awake showing images",
+        false => "Already prepared, nothing changed",
+    };
+    Ok(elements::device_mode(&km.name, Some((done, &report))))
+}
+
+#[post("/restore")]
+async fn restore_device(
+    _admin: Admin,
+    devices: &State<Devices>,
+    slideshow: &State<Arc<Slideshow>>,
+    dashboard: &State<Arc<Dashboard>>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let report = restore(km, devices, slideshow, dashboard).await?;
+    let done = "Restored, the Kindle is back to reading mode";
+    Ok(html! {
+        (elements::device_mode(&km.name, Some((done, &report))))
+        (elements::refresh_slideshow())
+        (elements::refresh_dashboard())
+    })
+}
+
+/// Undoes the prep of `km`, stopping the slideshow and dashboard first if they run on it so they
+/// don't draw over the reader
+async fn restore(
+    km: &KindleM,
+    devices: &Devices,
+    slideshow: &Slideshow,
+    dashboard: &Dashboard,
+) -> Result<PrepReport, ServerError> {
+    if km.name == devices.default().name {
+        slideshow.stop();
+        dashboard.stop();
+    }
+    Ok(km.restore().await?)
+}
+
+/// What the screen of the current device shows, full size
+#[get("/screen")]
+async fn device_screen(_user: User, km: &KindleM) -> Result<(ContentType, Vec<u8>), ServerError> {
+    Ok((ContentType::PNG, km.screenshot().await?))
+}
+
+/// Panel pushing `image` to several devices at once
+#[get("/push/<image>")]
+fn form_push(
+    _user: User,
+    image: &str,
+    catalog: &State<Arc<Catalog>>,
+    devices: &State<Devices>,
+) -> Result<Markup, ServerError> {
+    let record = catalog
+        .get(image)?
+        .ok_or_else(|| ServerError::NotFound(format!("image {image}")))?;
+    Ok(elements::push(&record, &devices.names(), None))
+}
+
+// Push Form
+#[derive(Debug, FromForm)]
+struct PushImage<'v> {
+    image: &'v str,
+    devices: Vec<&'v str>,
+    set: bool,
+}
+
+#[post("/push", data = "<form>")]
+async fn push_image(
+    _admin: Admin,
+    form: Form<PushImage<'_>>,
+    config: &State<Config>,
+    catalog: &State<Arc<Catalog>>,
+    devices: &State<Devices>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let record = catalog
+        .get(form.image)?
+        .ok_or_else(|| ServerError::NotFound(format!("image {}", form.image)))?;
+    if form.devices.is_empty() {
+        return Err(ServerError::InvalidInput(
+            "pick at least one device to push to".into(),
+        ));
+    }
+    let targets = pick_devices(devices, &form.devices)?;
+    let results = push_to_devices(config, catalog, &targets, form.image, form.set).await?;
+
+    // The current device's images are shown on the page
+    let session = km.pool.get().await?;
+    Ok(html! {
+        (elements::push(&record, &devices.names(), Some(&results)))
+        (oob_swap_server_images(km, catalog, &*session).await)
+    })
+}
+
+/// Devices with the given names, in the order of the settings
+fn pick_devices<'d>(devices: &'d Devices, names: &[&str]) -> Result<Vec<&'d KindleM>, ServerError> {
+    if let Some(unknown) = names.iter().find(|name| devices.get(name).is_none()) {
+        return Err(ServerError::NotFound(format!("device {unknown}")));
+    }
+    Ok(devices
+        .all()
+        .filter(|device| names.contains(&device.name.as_str()))
+        .collect())
+}
+
+/// Pushes an image on the server to each device and sets it if asked to, a device failing doesn't
+/// stop the others. The image was converted for the device it was uploaded with, so devices with
+/// another screen size or other gray levels are refused.
+async fn push_to_devices(
+    config: &Config,
+    catalog: &Catalog,
+    devices: &[&KindleM],
+    image: &str,
+    set: bool,
+) -> Result<Vec<PushResult>, ServerError> {
+    let converted = config.converted(image);
+    if !converted.is_file() {
+        return Err(ServerError::NotFound(format!("image {image}")));
+    }
+
+    let mut results = Vec::new();
+    for km in devices {
+        let pushed = push_to_device(km, catalog, &converted, image, set).await;
+        results.push(PushResult {
+            device: km.name.clone(),
+            error: pushed.err().map(|err| {
+                eprintln!("> Failed to push {image} to {}", km.name);
+                eprintln!("{err}");
+                err.to_string()
+            }),
+        });
+    }
+    Ok(results)
+}
+
+async fn push_to_device(
+    km: &KindleM,
+    catalog: &Catalog,
+    converted: &Path,
+    image: &str,
+    set: bool,
+) -> Result<(), ServerError> {
+    ensure_fits(km, converted, image).await?;
+
+    let session = km.pool.get().await?;
+    km.manager.replace_file(&*session, converted, image).await?;
+    catalog.set_on_kindle(&km.name, image, true)?;
+    if set {
+        km.manager.set_image(&*session, image).await?;
+        catalog.mark_displayed(&km.name, image)?;
+    }
+    Ok(())
+}
+
+/// Whether the converted image has the screen size and gray levels of the device
+async fn fits_device(km: &KindleM, converted: &Path) -> Result<bool, ServerError> {
+    let profile = km.profile().await;
+    let palette = km
+        .palette
+        .clone()
+        .unwrap_or_else(|| Palette::for_profile(&profile));
+    let converted = converted.to_path_buf();
+    spawn_blocking(move || image_converter::fits_screen(&converted, &profile, &palette))
+        .await
+        .map_err(|err| ServerError::Other(err.to_string()))?
+        .map_err(ServerError::from)
+}
+
+/// Refuses images converted for another screen, see [`fits_device`]
+async fn ensure_fits(km: &KindleM, converted: &Path, image: &str) -> Result<(), ServerError> {
+    if fits_device(km, converted).await? {
+        return Ok(());
+    }
+    let resolution = km.profile().await.resolution();
+    Err(ServerError::InvalidInput(format!(
+        "{image} wasn't converted for the {resolution} screen of {}, upload it there instead",
+        km.name
+    )))
+}
+
+// Route /stats
+#[get("/battery")]
+async fn stats_battery(_user: User, km: &KindleM) -> Markup {
+    let battery = match km.pool.get().await {
+        Ok(session) => km.manager.battery_charge(&*session).await,
+        Err(err) => Err(err),
+    };
+    match battery {
+        Ok(battery) => html! { "Battery: " (battery) "%" },
+        Err(err) => {
+            eprintln!("> Failed to get battery info");
+            eprintln!("{err}");
+            html! { "Battery: ??" }
+        }
+    }
+}
+
+/// Thumbnail of the current screen for the nav
+#[get("/screen")]
+async fn stats_screen(_user: User, km: &KindleM) -> Markup {
+    let thumbnail = async {
+        let png = km.screenshot().await?;
+        spawn_blocking(move || image_converter::thumbnail(&png, 128))
+            .await
+            .map_err(|err| ServerError::Other(err.to_string()))?
+            .map_err(ServerError::from)
+    };
+    match thumbnail.await {
+        Ok(png) => elements::screen_thumbnail(&BASE64_STANDARD.encode(png)),
+        Err(err) => {
+            eprintln!("> Failed to take a screenshot");
+            eprintln!("{err}");
+            html! { "Screen: ??" }
+        }
+    }
+}
+
+#[get("/files")]
+async fn stats_files(
+    _user: User,
+    config: &State<Config>,
+    km: &KindleM,
+) -> Result<Markup, ServerError> {
+    let images = match km.pool.get().await {
+        Ok(session) => km.manager.list_files(&*session).await,
+        Err(err) => Err(err),
+    };
+    let count_kindle = match images {
+        Ok(images) => format!("{}", images.len()),
+        Err(err) => {
+            eprintln!("> Failed to get number of files on the Kindle");
+            eprintln!("{err}");
+            "??".into()
+        }
+    };
+    // let count_kindle = km::get_filenames().len();
+    let count_server = fs::read_dir(&config.converted_dir)?.count();
+    Ok(html! { ."text-white/70" { "Kindle/Server files: " (count_kindle)"/"(count_server) }})
+}
+
+// Route /images and /converted
+/// Stored original, only for signed in users like the pages showing it
+#[get("/<path..>")]
+async fn original_file(_user: User, path: PathBuf, config: &State<Config>) -> Option<NamedFile> {
+    NamedFile::open(config.images_dir.join(path)).await.ok()
+}
+
+/// Converted image as the Kindle shows it, only for signed in users like the pages showing it
+#[get("/<path..>")]
+async fn converted_file(_user: User, path: PathBuf, config: &State<Config>) -> Option<NamedFile> {
+    NamedFile::open(config.converted_dir.join(path)).await.ok()
+}
+
+// ------ Rocket Setup --------- //
+
+fn setup_rocket(config: &Config, temp_dir: &Path) -> std::io::Result<()> {
+    // Create necessary dirs
+    println!(
+        "{}",
+        env::current_dir()
+            .expect("could not get curr dir")
+            .to_string_lossy()
+    );
+    fs::create_dir_all(temp_dir)?;
+    fs::create_dir_all(&config.images_dir)?;
+    fs::create_dir_all(&config.converted_dir)?;
+
+    Ok(())
+}
+
+#[launch]
 fn rocket() -> _ {
-    if let Err(error) = setup_rocket() {
+    let rocket = rocket::build();
+
+    // Every setting is in `Config`, see Rocket.toml for what they do
+    let config: Config = match rocket.figment().extract() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("> Failed to read the configuration");
+            for error in errors {
+                eprintln!("{error}");
+            }
+            process::exit(1);
+        }
+    };
+    if let Err(errors) = config.validate() {
+        eprintln!("> Invalid configuration");
+        for error in errors {
+            eprintln!("- {error}");
+        }
+        process::exit(1);
+    }
+
+    let temp_dir = rocket::Config::from(rocket.figment()).temp_dir.relative();
+    if let Err(error) = setup_rocket(&config, &temp_dir) {
         panic!("{error}");
     }
 
-    rocket::build()
+    let palette = config
+        .palette
+        .as_ref()
+        .map(|path| Palette::from_image(path).expect("palette was checked with the settings"));
+
+    // The slideshow and the dashboard are saved so they resume after a restart
+    let slideshow = Arc::new(Slideshow::load(config.slideshow_state.clone()));
+    let dashboard = Arc::new(Dashboard::load(config.dashboard_state.clone()));
+
+    // Images converted before the catalog existed are added to it on startup
+    let catalog = Catalog::open(&config.catalog).unwrap_or_else(|err| panic!("{err}"));
+    match catalog.import_untracked(&config.converted_dir, &config.images_dir) {
+        Ok(0) => {}
+        Ok(imported) => println!("Added {imported} existing images to the catalog"),
+        Err(err) => panic!("{err}"),
+    }
+
+    let accounts = Accounts::open(&config.accounts).unwrap_or_else(|err| panic!("{err}"));
+    if let Some(password) = &config.admin_password {
+        match accounts.create_first_admin(&config.admin_name, password) {
+            Ok(true) => println!("Created the admin account {}", config.admin_name),
+            Ok(false) => {}
+            Err(err) => panic!("{err}"),
+        }
+    }
+    if accounts.is_empty().unwrap_or(false) {
+        println!(
+            "There are no accounts yet, open /login on this machine to create the admin account"
+        );
+    }
+
+    let devices = config
+        .devices()
+        .iter()
+        .map(|device| KindleM::new(device, palette.clone()))
+        .collect();
+    let devices = Devices::new(devices);
+
+    rocket
         // State
-        .manage(ServerImages {
-            images: Mutex::new(HashSet::from_iter(get_server_images())),
-        })
-        .manage(KindleM {
-            manager: KindleManager::new("kindle".into(), "/mnt/us/images".into()),
-        })
+        .manage(slideshow)
+        .manage(dashboard)
+        .manage(accounts)
+        .manage(Playlists::load(config.playlists.clone()))
+        .manage(Arc::new(catalog))
+        .manage(devices)
+        .manage(config)
         // Routes
         .mount(
             "/",
             routes![
                 submit_image_form,
+                preview_image,
                 view_index,
                 set_image,
+                form_print_text,
+                print_text,
                 delete_image,
-                sync,
                 form_rename,
-                rename_image
+                rename_image,
+                library,
+                form_tags,
+                set_tags,
+                form_reconvert,
+                reconvert_images,
+                login_page,
+                login,
+                logout
+            ],
+        )
+        .mount(
+            "/accounts",
+            routes![
+                accounts_page,
+                save_account,
+                delete_account,
+                create_token,
+                revoke_token
+            ],
+        )
+        .mount("/sync", routes![sync_panel, sync_apply])
+        .mount(
+            "/slideshow",
+            routes![
+                slideshow_status,
+                slideshow_panel,
+                slideshow_start,
+                slideshow_stop,
+                slideshow_skip
+            ],
+        )
+        .mount(
+            "/dashboard",
+            routes![
+                dashboard_panel,
+                dashboard_start,
+                dashboard_stop,
+                dashboard_refresh,
+                dashboard_content
+            ],
+        )
+        .mount(
+            "/playlists",
+            routes![
+                playlists_panel,
+                create_playlist,
+                edit_playlist,
+                save_playlist,
+                delete_playlist,
+                play_playlist,
+                push_playlist,
+                remove_playlist
             ],
         )
-        .mount("/stats", routes![stats_battery, stats_files])
+        .mount(
+            "/devices",
+            routes![
+                device_switcher,
+                select_device,
+                devices_panel,
+                device_mode,
+                prep_device,
+                restore_device,
+                device_screen,
+                form_push,
+                push_image
+            ],
+        )
+        .mount("/stats", routes![stats_battery, stats_screen, stats_files])
+        .mount("/api/v1", api::routes())
+        .mount("/images", routes![original_file])
+        .mount("/converted", routes![converted_file])
         // Static files
-        .mount("/images/", FileServer::from(relative!("../images")))
-        .mount("/converted/", FileServer::from(relative!("../converted")))
         .mount("/static/", FileServer::from(relative!("/static/res")))
         .mount("/", FileServer::from(relative!("/static/favicon")).rank(11))
         // Catchers
-        .register("/", catchers![not_found, unprocessable_entity])
+        .register(
+            "/",
+            catchers![not_found, unprocessable_entity, unauthorized, forbidden],
+        )
+        .register("/api/v1", api::catchers())
+        // Background tasks
+        .attach(AdHoc::on_liftoff("Slideshow", |rocket| {
+            Box::pin(async move {
+                let slideshow = rocket.state::<Arc<Slideshow>>().unwrap().clone();
+                let catalog = rocket.state::<Arc<Catalog>>().unwrap().clone();
+                let km = rocket.state::<Devices>().unwrap().default();
+                rocket::tokio::spawn(slideshow.run(
+                    km.name.clone(),
+                    km.manager.clone(),
+                    km.pool.clone(),
+                    catalog,
+                ));
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Dashboard", |rocket| {
+            Box::pin(async move {
+                let dashboard = rocket.state::<Arc<Dashboard>>().unwrap().clone();
+                let catalog = rocket.state::<Arc<Catalog>>().unwrap().clone();
+                let config = rocket.state::<Config>().unwrap().clone();
+                let km = rocket.state::<Devices>().unwrap().shared_default();
+                rocket::tokio::spawn(dashboard.run(km, config, catalog));
+            })
+        }))
 }
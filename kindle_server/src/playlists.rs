@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rocket::serde::{json, Deserialize, Serialize};
+
+use crate::slideshow::Slide;
+
+/// Named, ordered group of converted images, items have the same optional duration as slides
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Playlist {
+    pub name: String,
+    pub items: Vec<Slide>,
+}
+
+/// Every playlist, stored as JSON after every change
+#[derive(Debug)]
+pub struct Playlists {
+    playlists: Mutex<Vec<Playlist>>,
+    path: PathBuf,
+}
+
+impl Playlists {
+    /// Loads the playlists saved at `path`, starting with none if there aren't any
+    pub fn load(path: PathBuf) -> Self {
+        let playlists = match fs::read_to_string(&path) {
+            Ok(saved) => json::from_str(&saved).unwrap_or_else(|err| {
+                eprintln!("> Failed to read the playlists, starting from scratch");
+                eprintln!("{err}");
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        Playlists {
+            playlists: Mutex::new(playlists),
+            path,
+        }
+    }
+
+    pub fn all(&self) -> Vec<Playlist> {
+        self.playlists.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Playlist> {
+        self.playlists
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|playlist| playlist.name == name)
+            .cloned()
+    }
+
+    /// Creates the playlist, or replaces its items if it already exists
+    pub fn save(&self, playlist: Playlist) {
+        self.update(
+            |playlists| match playlists.iter_mut().find(|p| p.name == playlist.name) {
+                Some(existing) => *existing = playlist,
+                None => playlists.push(playlist),
+            },
+        );
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.update(|playlists| playlists.retain(|playlist| playlist.name != name));
+    }
+
+    /// Keeps playlists in sync when an image is renamed or deleted, `None` removes it
+    pub fn replace_image(&self, image: &str, new_image: Option<&str>) {
+        self.update(|playlists| {
+            for playlist in playlists.iter_mut() {
+                match new_image {
+                    Some(new_image) => {
+                        for item in playlist.items.iter_mut().filter(|i| i.image == image) {
+                            item.image = new_image.to_string();
+                        }
+                    }
+                    None => playlist.items.retain(|item| item.image != image),
+                }
+            }
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<Playlist>)) {
+        let playlists = {
+            let mut playlists = self.playlists.lock().unwrap();
+            change(&mut playlists);
+            playlists.clone()
+        };
+
+        let saved = json::to_string(&playlists).expect("Playlists should always serialize");
+        if let Err(err) = fs::write(&self.path, saved) {
+            eprintln!("> Failed to save the playlists");
+            eprintln!("{err}");
+        }
+    }
+}
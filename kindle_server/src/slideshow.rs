@@ -0,0 +1,274 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kindle_manager::fbink::RefreshOptions;
+use kindle_manager::{KindleManager, SessionPool};
+use rand::seq::SliceRandom;
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket::tokio::{self, sync::Notify};
+
+use crate::catalog::Catalog;
+
+/// Order the slides are shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Order {
+    #[default]
+    Sequential,
+    /// Reshuffled every time all the slides were shown
+    Shuffle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Slide {
+    /// Name of the image on the Kindle
+    pub image: String,
+    /// Seconds the image stays on screen, the slideshow's interval when missing
+    pub duration: Option<u64>,
+}
+
+/// How the screen is refreshed for each image, kept as text like
+/// [`crate::catalog::ConversionSettings`] and checked before being stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RefreshSettings {
+    /// full, partial or region:X,Y,WIDTH,HEIGHT
+    pub mode: String,
+    pub waveform: String,
+    /// Full refresh after this many partial ones, 0 never does
+    pub full_every: u32,
+}
+
+/// Used for slideshows started before refresh settings were kept
+impl Default for RefreshSettings {
+    fn default() -> Self {
+        RefreshSettings {
+            mode: "full".into(),
+            waveform: "auto".into(),
+            full_every: 0,
+        }
+    }
+}
+
+impl RefreshSettings {
+    pub fn options(&self) -> RefreshOptions {
+        RefreshOptions {
+            mode: self.mode.parse().unwrap_or_default(),
+            waveform: self.waveform.parse().unwrap_or_default(),
+            full_every: self.full_every,
+        }
+    }
+}
+
+/// Everything needed to resume the slideshow, stored as JSON after every change
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SlideshowState {
+    pub running: bool,
+    pub slides: Vec<Slide>,
+    /// Default seconds between slides
+    pub interval: u64,
+    pub order: Order,
+    #[serde(default)]
+    pub refresh: RefreshSettings,
+    /// Indices into `slides` in the order they are shown
+    queue: Vec<usize>,
+    /// Position in `queue` of the slide on screen
+    position: usize,
+}
+
+/// What the slideshow is doing, as returned by the JSON status route
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SlideshowStatus {
+    pub running: bool,
+    pub current: Option<Slide>,
+    pub slides: Vec<Slide>,
+    pub interval: u64,
+    pub order: Order,
+    pub refresh: RefreshSettings,
+}
+
+impl SlideshowState {
+    pub fn status(&self) -> SlideshowStatus {
+        SlideshowStatus {
+            running: self.running,
+            current: self.current().cloned(),
+            slides: self.slides.clone(),
+            interval: self.interval,
+            order: self.order,
+            refresh: self.refresh.clone(),
+        }
+    }
+
+    /// Slide currently on screen, if the slideshow is running
+    pub fn current(&self) -> Option<&Slide> {
+        if !self.running {
+            return None;
+        }
+        self.queue
+            .get(self.position)
+            .and_then(|&index| self.slides.get(index))
+    }
+
+    fn duration(&self, slide: &Slide) -> Duration {
+        Duration::from_secs(slide.duration.unwrap_or(self.interval).max(1))
+    }
+
+    fn build_queue(&mut self) {
+        self.queue = (0..self.slides.len()).collect();
+        if self.order == Order::Shuffle {
+            self.queue.shuffle(&mut rand::thread_rng());
+        }
+    }
+
+    fn advance(&mut self) {
+        self.position += 1;
+        if self.position >= self.queue.len() {
+            self.position = 0;
+            self.build_queue();
+        }
+    }
+}
+
+/// Cycles through images on the Kindle in the background, see [`Slideshow::run`]
+#[derive(Debug)]
+pub struct Slideshow {
+    state: Mutex<SlideshowState>,
+    /// Wakes up the background task when the state changes
+    changed: Notify,
+    state_path: PathBuf,
+}
+
+impl Slideshow {
+    /// Loads the state saved at `state_path`, starting stopped if there is none
+    pub fn load(state_path: PathBuf) -> Self {
+        let state = match fs::read_to_string(&state_path) {
+            Ok(saved) => json::from_str(&saved).unwrap_or_else(|err| {
+                eprintln!("> Failed to read the slideshow state, starting from scratch");
+                eprintln!("{err}");
+                SlideshowState::default()
+            }),
+            Err(_) => SlideshowState::default(),
+        };
+
+        Slideshow {
+            state: Mutex::new(state),
+            changed: Notify::new(),
+            state_path,
+        }
+    }
+
+    pub fn state(&self) -> SlideshowState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn start(&self, slides: Vec<Slide>, interval: u64, order: Order, refresh: RefreshSettings) {
+        self.update(|state| {
+            *state = SlideshowState {
+                running: !slides.is_empty(),
+                slides,
+                interval,
+                order,
+                refresh,
+                queue: Vec::new(),
+                position: 0,
+            };
+            state.build_queue();
+        });
+    }
+
+    pub fn stop(&self) {
+        self.update(|state| state.running = false);
+    }
+
+    /// Shows the next slide right away
+    pub fn skip(&self) {
+        self.update(|state| {
+            if state.running {
+                state.advance();
+            }
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut SlideshowState)) {
+        let state = {
+            let mut state = self.state.lock().unwrap();
+            change(&mut state);
+            state.clone()
+        };
+        self.save(&state);
+        self.changed.notify_one();
+    }
+
+    fn save(&self, state: &SlideshowState) {
+        let saved = json::to_string(state).expect("Slideshow state should always serialize");
+        if let Err(err) = fs::write(&self.state_path, saved) {
+            eprintln!("> Failed to save the slideshow state");
+            eprintln!("{err}");
+        }
+    }
+
+    /// Sets the current slide on `device` and waits for its duration, forever. A slide that fails
+    /// to be set is retried after its duration instead of stopping the slideshow, since the Kindle
+    /// may just be out of reach for a while.
+    pub async fn run(
+        self: Arc<Self>,
+        device: String,
+        manager: Arc<KindleManager>,
+        pool: Arc<SessionPool>,
+        catalog: Arc<Catalog>,
+    ) {
+        loop {
+            let current = {
+                let state = self.state.lock().unwrap();
+                state.current().map(|slide| {
+                    (
+                        slide.image.clone(),
+                        state.duration(slide),
+                        state.refresh.options(),
+                    )
+                })
+            };
+            let Some((image, duration, refresh)) = current else {
+                self.changed.notified().await;
+                continue;
+            };
+
+            let shown = match pool.get().await {
+                Ok(session) => manager.set_image_with(&*session, &image, &refresh).await,
+                Err(err) => Err(err),
+            };
+            match shown {
+                Ok(_) => {
+                    if let Err(err) = catalog.mark_displayed(&device, &image) {
+                        eprintln!("> Slideshow failed to update the catalog");
+                        eprintln!("{err}");
+                    }
+                }
+                Err(err) => {
+                    eprintln!("> Slideshow failed to set {image}");
+                    eprintln!("{err}");
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(duration) => {
+                    let state = {
+                        let mut state = self.state.lock().unwrap();
+                        if state.current().map(|slide| &slide.image) == Some(&image) {
+                            state.advance();
+                        }
+                        state.clone()
+                    };
+                    self.save(&state);
+                }
+                // Started, stopped or skipped, the loop picks up the new slide
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
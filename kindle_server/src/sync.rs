@@ -0,0 +1,253 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rocket::serde::{Deserialize, Serialize};
+use rocket::FromFormField;
+
+/// What syncing does to an image to make the server and the Kindle match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SyncAction {
+    /// New or changed on the server, sent to the Kindle
+    Push,
+    /// New or changed on the Kindle, copied to the server
+    Pull,
+    /// Deleted from the server since the last sync, deleted from the Kindle too
+    DeleteFromKindle,
+    /// Deleted from the Kindle since the last sync, deleted from the server too unless another
+    /// device still holds it
+    DeleteFromServer,
+    /// Changed on both sides, or changed on one and deleted on the other. Left alone unless a
+    /// side to keep is picked.
+    Conflict,
+}
+
+impl SyncAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncAction::Push => "Push",
+            SyncAction::Pull => "Pull",
+            SyncAction::DeleteFromKindle => "Delete from the Kindle",
+            SyncAction::DeleteFromServer => "Delete from the server",
+            SyncAction::Conflict => "Conflict",
+        }
+    }
+}
+
+/// Side whose version is kept when resolving a conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Side {
+    Server,
+    Kindle,
+}
+
+/// An image that differs between the server and the Kindle, with its checksum on each side
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncEntry {
+    pub name: String,
+    pub action: SyncAction,
+    pub server: Option<String>,
+    pub kindle: Option<String>,
+}
+
+impl SyncEntry {
+    /// Action resolving a conflict by making the other side match `side`
+    pub fn resolve(&self, side: Side) -> SyncAction {
+        match side {
+            Side::Server if self.server.is_some() => SyncAction::Push,
+            Side::Server => SyncAction::DeleteFromKindle,
+            Side::Kindle if self.kindle.is_some() => SyncAction::Pull,
+            Side::Kindle => SyncAction::DeleteFromServer,
+        }
+    }
+}
+
+/// Outcome of applying one entry of the plan, `error` is missing when it succeeded
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncResult {
+    pub name: String,
+    pub action: SyncAction,
+    pub error: Option<String>,
+}
+
+/// Compares the checksums of every image on the server and the Kindle, by name. `synced` has the
+/// checksum each image had on both sides after it was last transferred, which tells which side
+/// changed it since. Images that are the same on both sides are left out.
+pub fn plan(
+    server: &HashMap<String, String>,
+    kindle: &HashMap<String, String>,
+    synced: &HashMap<String, String>,
+) -> Vec<SyncEntry> {
+    let names: BTreeSet<&String> = server.keys().chain(kindle.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (server, kindle) = (server.get(name), kindle.get(name));
+            let synced = synced.get(name);
+            let action = match (server, kindle) {
+                (Some(server), Some(kindle)) if server == kindle => return None,
+                (Some(server), Some(kindle)) => match synced {
+                    Some(synced) if synced == kindle => SyncAction::Push,
+                    Some(synced) if synced == server => SyncAction::Pull,
+                    _ => SyncAction::Conflict,
+                },
+                (Some(server), None) => match synced {
+                    None => SyncAction::Push,
+                    Some(synced) if synced == server => SyncAction::DeleteFromServer,
+                    Some(_) => SyncAction::Conflict,
+                },
+                (None, Some(kindle)) => match synced {
+                    None => SyncAction::Pull,
+                    Some(synced) if synced == kindle => SyncAction::DeleteFromKindle,
+                    Some(_) => SyncAction::Conflict,
+                },
+                (None, None) => return None,
+            };
+
+            Some(SyncEntry {
+                name: name.clone(),
+                action,
+                server: server.cloned(),
+                kindle: kindle.cloned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksums(images: &[(&str, &str)]) -> HashMap<String, String> {
+        images
+            .iter()
+            .map(|(name, checksum)| (name.to_string(), checksum.to_string()))
+            .collect()
+    }
+
+    /// Action planned for a single image `a.png`, `None` for a side that doesn't have it
+    fn action(
+        server: Option<&str>,
+        kindle: Option<&str>,
+        synced: Option<&str>,
+    ) -> Option<SyncAction> {
+        let side = |checksum: Option<&str>| {
+            checksum
+                .map(|checksum| checksums(&[("a.png", checksum)]))
+                .unwrap_or_default()
+        };
+        let plan = plan(&side(server), &side(kindle), &side(synced));
+        assert!(plan.len() <= 1);
+        plan.first().map(|entry| entry.action)
+    }
+
+    #[test]
+    fn same_on_both_sides_is_left_out() {
+        assert_eq!(action(Some("1"), Some("1"), None), None);
+        assert_eq!(action(Some("1"), Some("1"), Some("0")), None);
+    }
+
+    #[test]
+    fn changed_on_one_side_is_copied_to_the_other() {
+        assert_eq!(
+            action(Some("2"), Some("1"), Some("1")),
+            Some(SyncAction::Push)
+        );
+        assert_eq!(
+            action(Some("1"), Some("2"), Some("1")),
+            Some(SyncAction::Pull)
+        );
+    }
+
+    #[test]
+    fn changed_on_both_sides_is_a_conflict() {
+        assert_eq!(
+            action(Some("2"), Some("3"), Some("1")),
+            Some(SyncAction::Conflict)
+        );
+    }
+
+    #[test]
+    fn different_without_a_sync_is_a_conflict() {
+        assert_eq!(
+            action(Some("1"), Some("2"), None),
+            Some(SyncAction::Conflict)
+        );
+    }
+
+    #[test]
+    fn never_synced_on_one_side_only_is_copied() {
+        assert_eq!(action(Some("1"), None, None), Some(SyncAction::Push));
+        assert_eq!(action(None, Some("1"), None), Some(SyncAction::Pull));
+    }
+
+    #[test]
+    fn deleted_on_one_side_after_sync_is_deleted_on_the_other() {
+        assert_eq!(
+            action(Some("1"), None, Some("1")),
+            Some(SyncAction::DeleteFromServer)
+        );
+        assert_eq!(
+            action(None, Some("1"), Some("1")),
+            Some(SyncAction::DeleteFromKindle)
+        );
+    }
+
+    #[test]
+    fn deleted_on_one_side_and_changed_on_the_other_is_a_conflict() {
+        assert_eq!(
+            action(Some("2"), None, Some("1")),
+            Some(SyncAction::Conflict)
+        );
+        assert_eq!(
+            action(None, Some("2"), Some("1")),
+            Some(SyncAction::Conflict)
+        );
+    }
+
+    #[test]
+    fn gone_from_both_sides_is_left_out() {
+        assert_eq!(action(None, None, Some("1")), None);
+    }
+
+    #[test]
+    fn plan_lists_every_image_by_name_with_both_checksums() {
+        let server = checksums(&[("b.png", "1"), ("a.png", "1"), ("c.png", "1")]);
+        let kindle = checksums(&[("c.png", "1"), ("d.png", "2")]);
+        let plan = plan(&server, &kindle, &HashMap::new());
+
+        let names: Vec<&str> = plan.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["a.png", "b.png", "d.png"]);
+        assert_eq!(plan[2].server, None);
+        assert_eq!(plan[2].kindle.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn conflicts_resolve_to_the_picked_side() {
+        let entry = |server: Option<&str>, kindle: Option<&str>| SyncEntry {
+            name: "a.png".into(),
+            action: SyncAction::Conflict,
+            server: server.map(str::to_string),
+            kindle: kindle.map(str::to_string),
+        };
+        assert_eq!(
+            entry(Some("1"), Some("2")).resolve(Side::Server),
+            SyncAction::Push
+        );
+        assert_eq!(
+            entry(Some("1"), Some("2")).resolve(Side::Kindle),
+            SyncAction::Pull
+        );
+        assert_eq!(
+            entry(None, Some("2")).resolve(Side::Server),
+            SyncAction::DeleteFromKindle
+        );
+        assert_eq!(
+            entry(Some("1"), None).resolve(Side::Kindle),
+            SyncAction::DeleteFromServer
+        );
+    }
+}
@@ -1,6 +1,21 @@
+use kindle_manager::fbink::{Font, RefreshMode, TextOptions, TextRefresh, Waveform};
+use kindle_manager::image_converter::Dither;
+use kindle_manager::prep::{PrepReport, StepOutcome};
 use maud::{html, Markup, DOCTYPE};
 
-pub fn nav() -> Markup {
+use rocket::http::RawStr;
+
+use crate::auth::{Account, ApiToken, Role};
+use crate::catalog::{ConversionSettings, ImageRecord, SortBy};
+use crate::config::{DashboardConfig, Widget};
+use crate::dashboard::{DashboardState, DASHBOARD_IMAGE};
+use crate::devices::{DeviceStatus, PushResult};
+use crate::playlists::Playlist;
+use crate::slideshow::{Order, RefreshSettings, SlideshowState};
+use crate::sync::{SyncAction, SyncEntry, SyncResult};
+
+/// Links and stats are only shown once signed in
+pub fn nav(account: Option<&Account>) -> Markup {
     html! {
         header .bg-gray-800.sticky.top-0.z-30 {
             .mx-auto.max-w-7xl.px-6 {
@@ -8,20 +23,35 @@ pub fn nav() -> Markup {
                     .logo {
                         h1 .text-white.font-bold.text-2xl { a href="/" { "Kindle Server" } }
                     }
-                    nav {
-                        ul .flex.items-center.space-x-8 {
-                            li hx-get="/stats/battery" hx-trigger="load, click, every 3m"
-                                ."text-white/70" {
-                                "Checking Battery.."
-                            }
-                            li #filecount hx-get="/stats/files" hx-trigger="load, click, updateImage from:body"
-                                ."text-white/70" {
-                                "Checking File Count.."
-                            }
-                            li .indicator hx-post="/sync" hx-swap="none" hx-indicator="closest .indicator" {
-                                button .btn-primary {
-                                    div .indicator-text { "Sync" }
-                                    img .indicator-loading width="16px" src="/static/resources/pulse-rings-2.svg";
+                    @if let Some(account) = account {
+                        nav {
+                            ul .flex.items-center.space-x-8 {
+                                li hx-get="/devices/switcher" hx-trigger="load" hx-swap="innerHTML" {}
+                                li hx-get="/stats/screen" hx-trigger="load, every 1m"
+                                    ."text-white/70" {
+                                    "Checking Screen.."
+                                }
+                                li hx-get="/stats/battery" hx-trigger="load, click, every 3m"
+                                    ."text-white/70" {
+                                    "Checking Battery.."
+                                }
+                                li #filecount hx-get="/stats/files" hx-trigger="load, click, updateImage from:body"
+                                    ."text-white/70" {
+                                    "Checking File Count.."
+                                }
+                                li .indicator hx-get="/sync" hx-target="#sync" hx-swap="outerHTML" hx-indicator="closest .indicator" {
+                                    button .btn-primary {
+                                        div .indicator-text { "Sync" }
+                                        img .indicator-loading width="16px" src="/static/resources/pulse-rings-2.svg";
+                                    }
+                                }
+                                @if account.role == Role::Admin {
+                                    li { a href="/accounts" ."text-white/70" { "Accounts" } }
+                                }
+                                li {
+                                    form method="post" action="/logout" {
+                                        button type="submit" title={"Signed in as " (account.name)} .btn-secondary { "Log out" }
+                                    }
                                 }
                             }
                         }
@@ -48,7 +78,7 @@ pub fn footer() -> Markup {
     }
 }
 
-pub fn base(title: &str, content: Markup) -> Markup {
+pub fn base(title: &str, account: Option<&Account>, content: Markup) -> Markup {
     html! {
         (DOCTYPE)
         head {
@@ -61,20 +91,46 @@ pub fn base(title: &str, content: Markup) -> Markup {
             link rel="stylesheet" href="https://rsms.me/inter/inter.css";
         }
         body .flex.flex-col.bg-gray-100.h-screen.justify-between {
-            (nav())
+            (nav(account))
             .mb-auto {(content)}
             (footer())
         }
     }
 }
 
-pub fn server_images(images: Option<&Vec<String>>) -> Markup {
+/// Sort and tag filter of the image grid, reloads it on change
+pub fn library_controls(tags: &[String]) -> Markup {
+    html! {
+        form hx-get="/library" hx-target="#server-images" hx-trigger="change" .flex.gap-2.mb-6 {
+            select name="sort"
+                .block.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                ."focus:ring-2"."focus:ring-indigo-200" {
+                @for sort in SortBy::ALL {
+                    option value=(sort.id()) selected[sort == SortBy::default()] { (sort.name()) }
+                }
+            }
+            select name="tag"
+                .block.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                ."focus:ring-2"."focus:ring-indigo-200" {
+                option value="" { "All tags" }
+                @for tag in tags {
+                    option value=(tag) { (tag) }
+                }
+            }
+            button type="button" hx-get="/forms/reconvert" hx-include="closest form"
+                hx-target="#reconvert" hx-swap="outerHTML"
+                .btn-secondary { "Re-convert..." }
+        }
+    }
+}
+
+pub fn server_images(images: Option<&Vec<ImageRecord>>) -> Markup {
     html! {
         @match images {
             Some(images) if !images.is_empty() => {
                 .grid."grid-cols-2"."sm:grid-cols-4"."md:grid-cols-5".gap-x-4.gap-y-5{
-                    @for filename in images {
-                        (self::show_image(filename))
+                    @for record in images {
+                        (self::show_image(record))
                     }
                 }
             }
@@ -130,13 +186,66 @@ pub fn show_image_name(image_name: &str) -> Markup {
     }
 }
 
-pub fn show_image(filename: &str) -> Markup {
+pub fn show_edit_image_tags(filename: &str, tags: &[String]) -> Markup {
+    html! {
+        .flex.items-center.h-8 {
+            input autocomplete="off" type="text" name="text" value=(tags.join(", "))
+                placeholder="tag, other tag"
+                .flex-1.text-gray-900.text-sm.w-1.h-full
+                .rounded-l-md.shadow-sm.ring-1.ring-inset.border-0.ring-gray-300.bg-white
+                ."focus-within:ring-inset"."focus-within:ring-indigo-600"."focus-within:ring-2"
+                ."placeholder:text-gray-400";
+            button .btn-primary.h-full.rounded-l-none.px-2
+                hx-patch={"/tags/"(filename)}
+                hx-swap="outerHTML"
+                hx-target="closest div"
+                hx-include="previous input" {
+                    svg xmlns="http://www.w3.org/2000/svg" width="16" height="16" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" {
+                        polyline points="20 6 9 17 4 12" {}
+                    }
+            }
+        }
+    }
+}
+
+pub fn show_image_tags(filename: &str, tags: &[String]) -> Markup {
+    html! {
+        .flex.items-center.gap-2.h-8 {
+            span .text-sm.text-gray-500.flex-1 {
+                @if tags.is_empty() { "No tags" } @else { (tags.join(", ")) }
+            }
+            button .btn-secondary.h-full.px-2
+                hx-get={"/forms/tags/"(filename)} hx-target="closest div" hx-swap="outerHTML" {
+                svg xmlns="http://www.w3.org/2000/svg" width="16" height="16" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" {
+                    path d="M12 2H2v10l9.29 9.29a1 1 0 0 0 1.41 0l8.59-8.59a1 1 0 0 0 0-1.41Z" {}
+                    path d="M7 7h.01" {}
+                }
+            }
+        }
+    }
+}
+
+pub fn show_image(record: &ImageRecord) -> Markup {
+    let filename = record.name.as_str();
     let image_name = filename.split(".").next().unwrap_or(filename);
+    let details = if record.width > 0 {
+        format!(
+            "{} ({}x{})",
+            record.original_name, record.width, record.height
+        )
+    } else {
+        record.original_name.clone()
+    };
+    let details = match record.devices.is_empty() {
+        true => details,
+        false => format!("{details}\nOn {}", record.devices.join(", ")),
+    };
     html! {
         form .image {
             input type="hidden" name="text" value=(filename);
             (show_image_name(image_name))
-            img .rounded-md.my-2 src={"converted/"(filename)}
+            // The checksum makes browsers load the image again after it's re-converted
+            img .rounded-md.my-2 src={"converted/"(filename)"?"(record.checksum)} title=(details)
                 onerror="this.onerror=null; this.src='static/resources/notfound.png'"
                 hx-post="/set"
                 hx-vals={"{{\"image_name\": "(filename)"}}"}
@@ -154,6 +263,749 @@ pub fn show_image(filename: &str) -> Markup {
                         div .indicator-text { "Set" }
                         img .indicator-loading width="16px" src="/static/resources/pulse-rings-2.svg";
                     }
+                button type="button" title="Re-convert" hx-get={"/forms/reconvert?image="(filename)}
+                    hx-target="#reconvert" hx-swap="outerHTML"
+                    .btn-secondary.px-2 {
+                    svg xmlns="http://www.w3.org/2000/svg" width="16" height="16" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" {
+                        path d="M21 12a9 9 0 1 1-9-9c2.52 0 4.93 1 6.74 2.74L21 8" {}
+                        path d="M21 3v5h-5" {}
+                    }
+                }
+                button type="button" title="Push to devices" hx-get={"/devices/push/"(filename)}
+                    hx-target="#push" hx-swap="outerHTML"
+                    .btn-secondary.px-2 {
+                    svg xmlns="http://www.w3.org/2000/svg" width="16" height="16" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" {
+                        rect x="5" y="2" width="14" height="20" rx="2" {}
+                        path d="M12 18h.01" {}
+                    }
+                }
+            }
+            (show_image_tags(filename, &record.tags))
+        }
+    }
+}
+
+/// Settings to convert images again from their originals, `selected` is the only image checked
+/// when given, otherwise every image is
+pub fn reconvert(
+    images: &[ImageRecord],
+    selected: Option<&str>,
+    settings: &ConversionSettings,
+) -> Markup {
+    html! {
+        #reconvert hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Re-convert" }
+            p .text-sm.text-gray-500.mb-6
+                { "Converts the images again from their originals and replaces them on the Kindle." }
+            form hx-post="/reconvert" hx-indicator="this" .grid.grid-cols-1.gap-y-5.max-w-md {
+                @if images.is_empty() {
+                    p .text-sm.text-gray-500 { "No originals left on the server." }
+                }
+                .grid."grid-cols-2".gap-2 {
+                    @for record in images {
+                        label .flex.items-center.gap-2.text-sm.text-gray-900 {
+                            input type="checkbox" name="images" value=(record.name)
+                                checked[selected.map_or(true, |image| image == record.name)];
+                            (record.name)
+                        }
+                    }
+                }
+                @let choices = [
+                    ("horizontal", "Orientation", vec![("false", "Vertical"), ("true", "Horizontal")],
+                        settings.horizontal.to_string()),
+                    ("fit", "Image Adjustment", vec![("fit", "Fit"), ("stretch", "Stretch"), ("fill", "Fill")],
+                        settings.fit.clone()),
+                    ("background_color", "Background Color", vec![
+                        ("white", "White"), ("light_gray", "Light gray"), ("dark_gray", "Dark gray"),
+                        ("black", "Black"), ("auto", "Auto"),
+                    ], settings.background.clone()),
+                ];
+                @for (name, title, options, value) in choices {
+                    div {
+                        label for={"reconvert-"(name)} .block.text-sm.font-medium.leading-6.text-gray-900
+                            { (title) }
+                        select id={"reconvert-"(name)} name=(name)
+                            .mt-2.block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                            ."focus:ring-2"."focus:ring-indigo-200" {
+                            @for (id, option) in options {
+                                option value=(id) selected[id == value] { (option) }
+                            }
+                        }
+                    }
+                }
+                div {
+                    label for="reconvert-dither" .block.text-sm.font-medium.leading-6.text-gray-900
+                        { "Dithering" }
+                    select #reconvert-dither name="dither"
+                        .mt-2.block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                        ."focus:ring-2"."focus:ring-indigo-200" {
+                        @for dither in Dither::ALL {
+                            option value=(dither.id()) selected[dither.id() == settings.dither] { (dither.name()) }
+                        }
+                    }
+                }
+                @let sliders = [
+                    ("brightness", "Brightness", "-100", "100", "1", settings.brightness.to_string()),
+                    ("contrast", "Contrast", "-100", "100", "1", settings.contrast.to_string()),
+                    ("gamma", "Gamma", "0.1", "3", "0.1", settings.gamma.to_string()),
+                    ("sharpen", "Sharpen", "0", "5", "0.5", settings.sharpen.to_string()),
+                ];
+                @for (name, title, min, max, step, value) in sliders {
+                    div {
+                        label for={"reconvert-"(name)} .flex.justify-between.text-sm.font-medium.leading-6.text-gray-900 {
+                            (title)
+                            output .text-gray-500 { (value) }
+                        }
+                        input id={"reconvert-"(name)} name=(name) type="range" min=(min) max=(max) step=(step) value=(value)
+                            oninput="this.previousElementSibling.lastElementChild.value = this.value"
+                            .w-full.cursor-pointer;
+                    }
+                }
+                .flex.items-center.justify-end.gap-x-6 {
+                    .indicator {
+                        img .indicator-loading width="16px" src="/static/resources/pulse-rings-1.svg";
+                    }
+                    button type="button" hx-on="click: this.closest('#reconvert').outerHTML = '<div id=reconvert></div>'"
+                        .btn-secondary { "Cancel" }
+                    button type="submit" .btn-primary { "Re-convert" }
+                }
+            }
+        }
+    }
+}
+
+/// Small copy of what the Kindle shows, opening the full screenshot
+pub fn screen_thumbnail(png: &str) -> Markup {
+    html! {
+        a href="/devices/screen" target="_blank" title="Current screen" {
+            img .rounded-md src={"data:image/png;base64,"(png)} style="height: 4rem";
+        }
+    }
+}
+
+/// Picks the device the pages work on, empty with a single device
+pub fn device_switcher(devices: &[&str], current: &str) -> Markup {
+    html! {
+        @if devices.len() > 1 {
+            .flex.items-center.gap-2 {
+                select name="device" hx-post="/devices/select" hx-trigger="change" title="Device"
+                    .rounded-md.border-0.bg-white.text-gray-900.text-sm.py-1 {
+                    @for device in devices {
+                        option value=(device) selected[*device == current] { (device) }
+                    }
+                }
+                button type="button" title="Every device" hx-get="/devices" hx-target="#devices" hx-swap="outerHTML"
+                    ."text-white/70" { "All" }
+            }
+        }
+    }
+}
+
+/// Status of every device, with a button to switch to each
+pub fn devices(statuses: &[DeviceStatus], current: &str) -> Markup {
+    html! {
+        #devices hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Devices" }
+            p .text-sm.text-gray-500.mb-6 { "The first one runs the slideshow." }
+            .grid."grid-cols-2".gap-2.text-sm.text-gray-900.max-w-md.mb-6 {
+                @for status in statuses {
+                    span .font-semibold {
+                        (status.name)
+                        @if status.name == current { " (selected)" }
+                    }
+                    @if status.reachable {
+                        span {
+                            (status.model) ", battery "
+                            (status.battery.map_or("??".into(), |battery| format!("{battery}%")))
+                            ", " (status.images_on_kindle.unwrap_or_default()) " images"
+                        }
+                    } @else {
+                        span .text-red-700 { (status.model) ", unreachable" }
+                    }
+                }
+            }
+            button type="button" hx-on="click: this.closest('#devices').outerHTML = '<div id=devices></div>'"
+                .btn-secondary { "Close" }
+        }
+    }
+}
+
+/// Pushes an image to the picked devices, `results` is what the last push did
+pub fn push(record: &ImageRecord, devices: &[&str], results: Option<&[PushResult]>) -> Markup {
+    html! {
+        #push hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Push " (record.name) }
+            p .text-sm.text-gray-500.mb-6
+                { "Copies the image to each device, replacing the version it has." }
+            @if let Some(results) = results {
+                .grid."grid-cols-2".gap-2.text-sm.text-gray-900.max-w-md.mb-6 {
+                    @for result in results {
+                        span .font-semibold { (result.device) }
+                        @match &result.error {
+                            Some(error) => span .text-red-700 { (error) },
+                            None => span { "Done" },
+                        }
+                    }
+                }
+            }
+            form hx-post="/devices/push" hx-indicator="this" .grid.grid-cols-1.gap-y-5.max-w-md {
+                input type="hidden" name="image" value=(record.name);
+                .grid."grid-cols-2".gap-2 {
+                    @for device in devices {
+                        label .flex.items-center.gap-2.text-sm.text-gray-900 {
+                            input type="checkbox" name="devices" value=(device)
+                                checked[!record.devices.iter().any(|name| name == device)];
+                            (device)
+                            @if record.devices.iter().any(|name| name == device) {
+                                span .text-gray-500 { " (has it)" }
+                            }
+                        }
+                    }
+                }
+                label .flex.items-center.gap-2.text-sm.font-medium.text-gray-900 {
+                    input name="set" type="checkbox";
+                    "Set it on screen"
+                }
+                .flex.items-center.justify-end.gap-x-6 {
+                    .indicator {
+                        img .indicator-loading width="16px" src="/static/resources/pulse-rings-1.svg";
+                    }
+                    button type="button" hx-on="click: this.closest('#push').outerHTML = '<div id=push></div>'"
+                        .btn-secondary { "Close" }
+                    button type="submit" .btn-primary { "Push" }
+                }
+            }
+        }
+    }
+}
+
+/// What syncing would do, applied with the button at the bottom
+pub fn sync_plan(plan: &[SyncEntry]) -> Markup {
+    html! {
+        #sync hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Sync" }
+            @if plan.is_empty() {
+                p .text-sm.text-gray-500.mb-6 { "The server and the Kindle already have the same images." }
+                (close_sync())
+            } @else {
+                p .text-sm.text-gray-500.mb-6
+                    { (plan.len()) " images differ between the server and the Kindle, nothing changes until the sync is applied." }
+                form hx-post="/sync" hx-indicator="this" .grid.grid-cols-1.gap-y-5.max-w-md {
+                    .grid."grid-cols-2".gap-2.text-sm.text-gray-900 {
+                        @for entry in plan {
+                            span .font-semibold { (entry.name) }
+                            @if entry.action == SyncAction::Conflict {
+                                select name={"resolve["(entry.name)"]"}
+                                    .block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                                    ."focus:ring-2"."focus:ring-indigo-200" {
+                                    option value="" { "Conflict, skip it" }
+                                    option value="server" { "Keep the server's" }
+                                    option value="kindle" { "Keep the Kindle's" }
+                                }
+                            } @else {
+                                span { (entry.action.name()) }
+                            }
+                        }
+                    }
+                    .flex.items-center.justify-end.gap-x-6 {
+                        .indicator {
+                            img .indicator-loading width="16px" src="/static/resources/pulse-rings-1.svg";
+                        }
+                        (close_sync())
+                        button type="submit" .btn-primary { "Apply" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of every change made by the sync
+pub fn sync_results(results: &[SyncResult]) -> Markup {
+    html! {
+        #sync hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Sync" }
+            p .text-sm.text-gray-500.mb-6 {
+                (results.iter().filter(|result| result.error.is_none()).count())
+                " of " (results.len()) " changes applied."
+            }
+            .grid."grid-cols-2".gap-2.text-sm.text-gray-900.max-w-md.mb-6 {
+                @for result in results {
+                    span .font-semibold { (result.name) }
+                    @match &result.error {
+                        Some(error) => span .text-red-700 { (result.action.name()) ": " (error) },
+                        None => span { (result.action.name()) },
+                    }
+                }
+            }
+            (close_sync())
+        }
+    }
+}
+
+fn close_sync() -> Markup {
+    html! {
+        button type="button" hx-on="click: this.closest('#sync').outerHTML = '<div id=sync></div>'"
+            .btn-secondary { "Close" }
+    }
+}
+
+/// Converted image inside a Kindle-shaped frame, `png` is base64 encoded
+pub fn kindle_preview(png: &str, horizontal: bool) -> Markup {
+    // The wider bezel goes where the Kindle's bottom is, on the right when held horizontally
+    let (bezel, size) = if horizontal {
+        ("padding: 1.25rem 2.5rem 1.25rem 1.25rem", "max-width: 100%")
+    } else {
+        (
+            "padding: 1.25rem 1.25rem 2.5rem 1.25rem",
+            "max-height: 32rem",
+        )
+    };
+    html! {
+        .w-fit.mx-auto.bg-gray-800.rounded-lg.shadow style=(bezel) {
+            img .block src={ "data:image/png;base64," (png) } style=(size)
+                alt="Preview of the image converted for the Kindle";
+        }
+    }
+}
+
+/// Slideshow status and controls, `images` are the ones on the Kindle that can be picked
+pub fn slideshow(device: &str, state: &SlideshowState, images: Option<&Vec<String>>) -> Markup {
+    html! {
+        #slideshow hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Slideshow" }
+            p .text-sm.text-gray-500.mb-6 {
+                @match state.current() {
+                    Some(slide) => {
+                        "Showing " (slide.image) " on " (device) ", " (state.slides.len()) " images"
+                        @if state.order == Order::Shuffle { " shuffled" }
+                    }
+                    None => { "Stopped, runs on " (device) }
+                }
+            }
+            form hx-post="/slideshow/start" .grid.grid-cols-1.gap-y-5.max-w-md {
+                @match images {
+                    Some(images) if !images.is_empty() => {
+                        .grid.grid-cols-1.gap-y-2 {
+                            @for (i, image) in images.iter().enumerate() {
+                                @let slide = state.slides.iter().find(|slide| &slide.image == image);
+                                .flex.items-center.gap-2 {
+                                    input #{"slide-"(i)} name={"slides["(i)"].enabled"} type="checkbox" checked[slide.is_some()];
+                                    input name={"slides["(i)"].image"} type="hidden" value=(image);
+                                    label for={"slide-"(i)} .flex-1.text-sm.text-gray-900 { (image) }
+                                    input name={"slides["(i)"].duration"} type="number" min="1" placeholder="Interval"
+                                        value=[slide.and_then(|slide| slide.duration)] title="Seconds on screen"
+                                        .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                                }
+                            }
+                        }
+                    }
+                    Some(_) => p .text-sm.text-gray-500 { "There are no images on the Kindle yet." },
+                    None => p .text-sm.text-gray-500 { "Couldn't get the images on the Kindle." },
+                }
+                .flex.items-center.gap-2 {
+                    label for="interval" .flex-1.text-sm.font-medium.text-gray-900 { "Seconds between images" }
+                    input #interval name="interval" type="number" min="1"
+                        value=(if state.interval > 0 { state.interval } else { 300 })
+                        .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                }
+                label .flex.items-center.gap-2.text-sm.font-medium.text-gray-900 {
+                    input name="shuffle" type="checkbox" checked[state.order == Order::Shuffle];
+                    "Shuffle"
+                }
+                (refresh_fields(&state.refresh))
+                .flex.items-center.justify-end.gap-x-6 {
+                    button type="button" hx-post="/slideshow/stop" .btn-secondary { "Stop" }
+                    button type="button" hx-post="/slideshow/skip" .btn-secondary { "Skip" }
+                    button type="submit" .btn-primary { "Start" }
+                }
+            }
+        }
+    }
+}
+
+/// Screen refresh picked for each image, the fields of `RefreshForm` under `refresh`
+fn refresh_fields(settings: &RefreshSettings) -> Markup {
+    let mode = settings.mode.parse().unwrap_or_default();
+    let region = match mode {
+        RefreshMode::Region {
+            x,
+            y,
+            width,
+            height,
+        } => Some(format!("{x},{y},{width},{height}")),
+        _ => None,
+    };
+    html! {
+        .flex.items-center.gap-2 {
+            label for="refresh-mode" .flex-1.text-sm.font-medium.text-gray-900 { "Screen refresh" }
+            select #refresh-mode name="refresh.mode"
+                .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                ."focus:ring-2"."focus:ring-indigo-200" {
+                option value="full" selected[mode == RefreshMode::Full] { "Full" }
+                option value="partial" selected[mode == RefreshMode::Partial] { "Partial" }
+                option value="region" selected[region.is_some()] { "Region" }
+            }
+            input name="refresh.region" type="text" placeholder="X,Y,W,H" value=[region]
+                title="Region redrawn, in pixels" style="width: 7rem"
+                .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+        }
+        .flex.items-center.gap-2 {
+            label for="refresh-waveform" .flex-1.text-sm.font-medium.text-gray-900 { "Waveform" }
+            select #refresh-waveform name="refresh.waveform"
+                .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                ."focus:ring-2"."focus:ring-indigo-200" {
+                @for waveform in Waveform::ALL {
+                    option value=(waveform.id()) selected[waveform.id() == settings.waveform] { (waveform) }
+                }
+            }
+        }
+        .flex.items-center.gap-2 {
+            label for="refresh-full-every" .flex-1.text-sm.font-medium.text-gray-900 { "Full refresh every" }
+            input #refresh-full-every name="refresh.full_every" type="number" min="0"
+                value=(settings.full_every) title="Partial refreshes before a full one, 0 never does"
+                .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+        }
+    }
+}
+
+/// Prep and restore buttons of the current device, with what was last done to it step by step
+pub fn device_mode(device: &str, done: Option<(&str, &PrepReport)>) -> Markup {
+    html! {
+        #device-mode hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Device" }
+            p .text-sm.text-gray-500.mb-6 {
+                @match done {
+                    Some((done, _)) => (done),
+                    None => {
+                        "Prep " (device) " to keep it awake showing images, restore it to read on it again"
+                    }
+                }
+            }
+            @if let Some((_, report)) = done {
+                ul .grid.grid-cols-1.mb-6.max-w-md {
+                    @for step in &report.steps {
+                        li .flex.gap-2.text-sm {
+                            span .w-20.font-medium.text-gray-900 { (step.outcome.id()) }
+                            @if step.outcome == StepOutcome::Changed {
+                                span .text-gray-900 { (step.description) }
+                            } @else {
+                                span .text-gray-500 { (step.description) }
+                            }
+                        }
+                    }
+                }
+            }
+            .flex.items-center.justify-end.gap-x-6.max-w-md {
+                button type="button" hx-post="/devices/restore" .btn-secondary { "Restore" }
+                button type="button" hx-post="/devices/prep" .btn-primary { "Prep" }
+            }
+        }
+    }
+}
+
+/// Reloads the slideshow panel once swapped in, after something else changed the slideshow
+pub fn refresh_slideshow() -> Markup {
+    html! {
+        div hx-get="/slideshow" hx-target="#slideshow" hx-swap="outerHTML" hx-trigger="load" {}
+    }
+}
+
+/// Dashboard controls with the last image shown, and the notes and to-do list if it has them
+pub fn dashboard(device: &str, state: &DashboardState, config: &DashboardConfig) -> Markup {
+    let has_notes = config.widgets.contains(&Widget::Notes);
+    let has_todo = config.widgets.contains(&Widget::Todo);
+    html! {
+        #dashboard hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Dashboard" }
+            p .text-sm.text-gray-500.mb-6 {
+                @if state.running {
+                    "Showing on " (device) ", redrawn every " (config.refresh) " seconds"
+                } @else {
+                    "Stopped, runs on " (device) " instead of the slideshow"
+                }
+            }
+            @if let Some(error) = &state.last_error {
+                p .text-sm.text-red-700.mb-6 { "Last redraw failed: " (error) }
+            }
+            @if let Some(rendered) = state.last_rendered {
+                img src={"/converted/" (DASHBOARD_IMAGE) "?v=" (rendered)} alt="Last dashboard shown"
+                    .rounded-md.shadow-sm.mb-6 style="max-height: 20rem";
+            }
+            form hx-post="/dashboard/content" .grid.grid-cols-1.gap-y-5.max-w-md {
+                @if has_notes {
+                    .grid.grid-cols-1.gap-y-2 {
+                        label for="dashboard-notes" .text-sm.font-medium.text-gray-900 { "Notes" }
+                        textarea #dashboard-notes name="notes" rows="4"
+                            .w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                            (state.notes)
+                        }
+                    }
+                } @else {
+                    input name="notes" type="hidden" value=(state.notes);
+                }
+                @if has_todo {
+                    .grid.grid-cols-1.gap-y-2 {
+                        label for="dashboard-todo" .text-sm.font-medium.text-gray-900 { "To do" }
+                        textarea #dashboard-todo name="todo" rows="5" placeholder="One per line, \"x \" in front once done"
+                            .w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                            (state.todo)
+                        }
+                    }
+                } @else {
+                    input name="todo" type="hidden" value=(state.todo);
+                }
+                .flex.items-center.justify-end.gap-x-6 {
+                    @if state.running {
+                        button type="button" hx-post="/dashboard/stop" .btn-secondary { "Stop" }
+                        button type="button" hx-post="/dashboard/refresh" .btn-secondary { "Redraw" }
+                    } @else {
+                        button type="button" hx-post="/dashboard/start" .btn-secondary { "Start" }
+                    }
+                    @if has_notes || has_todo {
+                        button type="submit" .btn-primary { "Save" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Form printing a message over what the Kindle shows, keeping the last options and text
+pub fn print_text(device: &str, options: &TextOptions, text: &str, printed: bool) -> Markup {
+    let field = "w-20 rounded-md border-0 shadow-sm ring-1 ring-inset ring-gray-300 bg-white text-gray-900 text-sm";
+    html! {
+        #print-text hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Message" }
+            p .text-sm.text-gray-500.mb-6 {
+                @if printed {
+                    "Printed on " (device) ", it stays until the next image is set"
+                } @else {
+                    "Printed on " (device) " over the image it shows"
+                }
+            }
+            form hx-post="/print" .grid.grid-cols-1.gap-y-5.max-w-md {
+                textarea name="text" rows="3" required placeholder="One row per line"
+                    .w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                    (text)
+                }
+                .flex.items-center.gap-2 {
+                    label for="print-font" .flex-1.text-sm.font-medium.text-gray-900 { "Font" }
+                    select #print-font name="font"
+                        .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                        @for font in Font::ALL {
+                            option value=(font.id()) selected[font == options.font] { (font.id()) }
+                        }
+                    }
+                }
+                @let numbers = [
+                    ("size", "Size, 0 to fit the screen", "0", options.size.to_string()),
+                    ("row", "Row, negative from the bottom", "", options.row.to_string()),
+                    ("column", "Column, negative from the right", "", options.column.to_string()),
+                ];
+                @for (name, label, min, value) in numbers {
+                    .flex.items-center.gap-2 {
+                        label for={"print-"(name)} .flex-1.text-sm.font-medium.text-gray-900 { (label) }
+                        input id={"print-"(name)} name=(name) type="number" min=[(!min.is_empty()).then_some(min)]
+                            value=(value) class=(field);
+                    }
+                }
+                @let flags = [
+                    ("centered", "Centered", options.centered),
+                    ("halfway", "Halfway down", options.halfway),
+                    ("inverse", "White on black", options.inverse),
+                    ("padded", "Hide the image behind the lines", options.padded),
+                    ("clear", "Clear the screen first", options.clear),
+                ];
+                .grid.grid-cols-2.gap-y-2 {
+                    @for (name, label, checked) in flags {
+                        label .flex.items-center.gap-2.text-sm.text-gray-900 {
+                            input name=(name) type="checkbox" checked[checked];
+                            (label)
+                        }
+                    }
+                }
+                .flex.items-center.gap-2 {
+                    label for="print-refresh" .flex-1.text-sm.font-medium.text-gray-900 { "Refresh" }
+                    select #print-refresh name="refresh"
+                        .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                        @for (refresh, label) in [
+                            (TextRefresh::Partial, "Partial"),
+                            (TextRefresh::Flash, "Flash, cleans up ghosts"),
+                            (TextRefresh::None, "None, shown by the next refresh"),
+                        ] {
+                            option value=(refresh.id()) selected[refresh == options.refresh] { (label) }
+                        }
+                    }
+                }
+                .flex.items-center.justify-end.gap-x-6 {
+                    button type="submit" .btn-primary.indicator { "Print" }
+                }
+            }
+        }
+    }
+}
+
+/// Reloads the dashboard panel once swapped in, after the slideshow took over the Kindle
+pub fn refresh_dashboard() -> Markup {
+    html! {
+        div hx-get="/dashboard" hx-target="#dashboard" hx-swap="outerHTML" hx-trigger="load" {}
+    }
+}
+
+/// Every playlist with its actions, and a form to create new ones
+pub fn playlists(playlists: &[Playlist]) -> Markup {
+    html! {
+        #playlists hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Playlists" }
+            form hx-post="/playlists" .flex.items-center.gap-2.max-w-md.mt-2.mb-6 {
+                input name="name" type="text" placeholder="Playlist name" required autocomplete="off"
+                    .flex-1.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                button type="submit" .btn-primary { "Create" }
+            }
+            @if playlists.is_empty() {
+                p .text-sm.text-gray-500 { "There are no playlists yet." }
+            }
+            .grid.grid-cols-1.gap-y-5 {
+                @for playlist in playlists {
+                    @let url = format!("/playlists/{}", RawStr::new(&playlist.name).percent_encode());
+                    div {
+                        p .text-sm.font-semibold.text-gray-900 {
+                            (playlist.name)
+                            span .font-medium.text-gray-500 { " - " (playlist.items.len()) " images" }
+                        }
+                        form hx-post={(url) "/slideshow"} .flex.items-center.gap-2.mt-2 {
+                            input name="interval" type="number" min="1" value="300" title="Seconds between images"
+                                .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                            label .flex.items-center.gap-2.text-sm.text-gray-900 {
+                                input name="shuffle" type="checkbox";
+                                "Shuffle"
+                            }
+                            button type="submit" .btn-primary { "Play" }
+                            button type="button" hx-get=(url) .btn-secondary { "Edit" }
+                            button type="button" hx-post={(url) "/push"} hx-swap="none" .btn-secondary { "Push" }
+                            button type="button" hx-post={(url) "/remove"} hx-swap="none"
+                                hx-confirm={"Delete the images of " (playlist.name) " from the Kindle?"}
+                                .btn-secondary { "Remove" }
+                            button type="button" hx-delete=(url) hx-confirm={"Delete the playlist " (playlist.name) "?"}
+                                .btn-secondary { "Delete" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Picks the images of a playlist from the ones on the server, with their order and duration
+pub fn playlist_editor(playlist: &Playlist, images: &[String]) -> Markup {
+    let url = format!(
+        "/playlists/{}",
+        RawStr::new(&playlist.name).percent_encode()
+    );
+    html! {
+        #playlists hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Playlist " (playlist.name) }
+            p .text-sm.text-gray-500.mb-6 { "Images are shown by position, leave the duration empty to use the slideshow's interval." }
+            form hx-post=(url) .grid.grid-cols-1.gap-y-5.max-w-md {
+                @if images.is_empty() {
+                    p .text-sm.text-gray-500 { "There are no images on the server yet." }
+                }
+                .grid.grid-cols-1.gap-y-2 {
+                    @for (i, image) in images.iter().enumerate() {
+                        @let position = playlist.items.iter().position(|item| &item.image == image);
+                        @let duration = position.and_then(|position| playlist.items[position].duration);
+                        .flex.items-center.gap-2 {
+                            input #{"item-"(i)} name={"items["(i)"].enabled"} type="checkbox" checked[position.is_some()];
+                            input name={"items["(i)"].image"} type="hidden" value=(image);
+                            label for={"item-"(i)} .flex-1.text-sm.text-gray-900 { (image) }
+                            input name={"items["(i)"].position"} type="number" min="1" placeholder="Position"
+                                value=[position.map(|position| position + 1)] title="Position in the playlist"
+                                .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                            input name={"items["(i)"].duration"} type="number" min="1" placeholder="Interval"
+                                value=[duration] title="Seconds on screen"
+                                .w-20.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                        }
+                    }
+                }
+                .flex.items-center.justify-end.gap-x-6 {
+                    button type="button" hx-get="/playlists" .btn-secondary { "Cancel" }
+                    button type="submit" .btn-primary { "Save" }
+                }
+            }
+        }
+    }
+}
+
+/// Accounts and API tokens with forms to add them, `new_token` is shown once after creating it
+pub fn accounts(accounts: &[Account], tokens: &[ApiToken], new_token: Option<&str>) -> Markup {
+    html! {
+        #accounts hx-target="this" hx-swap="outerHTML" .pb-12 {
+            h2 .text-lg.font-semibold.text-gray-900 { "Accounts" }
+            p .text-sm.text-gray-500 { "Admins can change anything, viewers can only look. Saving an existing name changes its password and role." }
+            form hx-post="/accounts/users" .flex.items-center.gap-2.mt-2.mb-6 {
+                input name="name" type="text" placeholder="Name" required autocomplete="off"
+                    .flex-1.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                input name="password" type="password" placeholder="Password" required minlength="8" autocomplete="new-password"
+                    .flex-1.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                select name="role"
+                    .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                    @for role in Role::ALL {
+                        option value=(role.id()) selected[role == Role::Viewer] { (role.name()) }
+                    }
+                }
+                button type="submit" .btn-primary { "Save" }
+            }
+            .grid.grid-cols-1.gap-y-2.mb-6 {
+                @for account in accounts {
+                    .flex.items-center.gap-2 {
+                        p .flex-1.text-sm.font-semibold.text-gray-900 {
+                            (account.name)
+                            span .font-medium.text-gray-500 { " - " (account.role.name()) }
+                        }
+                        button type="button" hx-delete={"/accounts/users/" (RawStr::new(&account.name).percent_encode())}
+                            hx-confirm={"Delete the account " (account.name) " and its tokens?"}
+                            .btn-secondary { "Delete" }
+                    }
+                }
+            }
+
+            h2 .text-lg.font-semibold.text-gray-900 { "API tokens" }
+            p .text-sm.text-gray-500 { "Scripts send a token as \"Authorization: Bearer <token>\" and act as its account." }
+            @if let Some(token) = new_token {
+                .rounded-md.bg-white.shadow.p-4.my-2 {
+                    p .text-sm.font-semibold.text-gray-900 { "Copy the new token now, it won't be shown again:" }
+                    code style="user-select: all" .block.text-sm.text-gray-900 { (token) }
+                }
+            }
+            form hx-post="/accounts/tokens" .flex.items-center.gap-2.mt-2.mb-6 {
+                select name="user"
+                    .rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm {
+                    @for account in accounts {
+                        option value=(account.name) { (account.name) }
+                    }
+                }
+                input name="label" type="text" placeholder="What it's for" required autocomplete="off"
+                    .flex-1.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                button type="submit" .btn-primary { "Create" }
+            }
+            @if tokens.is_empty() {
+                p .text-sm.text-gray-500 { "There are no tokens yet." }
+            }
+            .grid.grid-cols-1.gap-y-2 {
+                @for token in tokens {
+                    .flex.items-center.gap-2 {
+                        p .flex-1.text-sm.font-semibold.text-gray-900 {
+                            (token.label)
+                            span .font-medium.text-gray-500 {
+                                " - " (token.user) ", " @if token.last_used.is_some() { "used" } @else { "never used" }
+                            }
+                        }
+                        button type="button" hx-delete={"/accounts/tokens/" (token.id)}
+                            hx-confirm={"Revoke the token " (token.label) "?"}
+                            .btn-secondary { "Revoke" }
+                    }
+                }
             }
         }
     }
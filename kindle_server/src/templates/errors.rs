@@ -15,5 +15,21 @@ pub fn e404(_uri: &str) -> Markup {
             }
         }
     };
-    base("404", content)
+    base("404", None, content)
+}
+
+pub fn e403() -> Markup {
+    let content = html! {
+        .py-8.px-4.mx-auto.max-w-screen-xl."lg:py-16"."lg:px-6" {
+            .mx-auto.max-w-screen-sm.text-center {
+                h1 .mb-4.text-7xl.tracking-tight.font-extrabold."lg:text-9xl".text-indigo-600 { "403" }
+                p .mb-4.text-3xl.font-bold.text-gray-900 { "You can't go there :/" }
+                p .mb-4.text-lg.font-light.text-gray-500 { "Sorry, only admins can see this page, maybe sign in with another account?" }
+                a href="/login"
+                    .btn-primary
+                    { "Sign in" }
+            }
+        }
+    };
+    base("403", None, content)
 }
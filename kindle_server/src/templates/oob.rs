@@ -1,5 +1,6 @@
 use maud::{html, Markup};
 
+use crate::catalog::ImageRecord;
 use crate::templates::elements;
 
 // OOB = Out of Band
@@ -11,7 +12,7 @@ use crate::templates::elements;
 // Maybe we will need to do this some other way in the future, if I add something like
 // "pinned items", but we will cross that bridge when we come to it, for now on my browser
 // it seems to recognize the repeat images and just caches them, so no big problem for now
-pub fn swap_server_images(server_images: Option<&Vec<String>>) -> Markup {
+pub fn swap_server_images(server_images: Option<&Vec<ImageRecord>>) -> Markup {
     html! {
         #server-images hx-swap-oob="innerHTML" {
             (elements::server_images(server_images))
@@ -1,14 +1,29 @@
+use kindle_manager::image_converter::Dither;
 use maud::{html, Markup};
 
 use super::elements;
+use crate::auth::{Account, ApiToken};
+use crate::catalog::{ConversionSettings, ImageRecord};
 
 // Main page, shows submission form, images available on the Kindle and actions available for those.
-pub fn main(server_images: Option<&Vec<String>>) -> Markup {
+// The form starts with the `conversion` setting.
+pub fn main(
+    account: &Account,
+    defaults: &ConversionSettings,
+    server_images: Option<&Vec<ImageRecord>>,
+    tags: &[String],
+) -> Markup {
     let content = html! {
         .mx-auto.max-w-5xl.px-4.py-8 {
             // Error placeholder
             #newalert {}
 
+            // Sync plan, filled in by the Sync button
+            #sync {}
+
+            // Every device, filled in from the device switcher
+            #devices {}
+
             // Submission Form
             form hx-post="/" hx-encoding="multipart/form-data" hx-swap="none" hx-indicator="this"
                 .grid.grid-cols-1.gap-x-6.gap-y-7.pb-12 {
@@ -28,8 +43,8 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                                 .block.flex-1.border-0.bg-transparent.text-gray-900.text-sm.font-semibold
                                 ."placeholder:text-gray-400"."focus:ring-0";
                         }
-                        input type="file" id="file" name="file" accept="image/png, image/jpeg, image/webp, image/bmp" required
-                            onchange="set_filename_from_upload()"
+                        input type="file" id="file" name="file" accept="image/png, image/jpeg, image/webp, image/bmp, image/gif" required
+                            onchange="set_filename_from_upload(); update_focus_preview()"
                             .text-sm.text-gray-500
                             ."file:hidden"."focus:outline-none";
                     }
@@ -53,7 +68,7 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                         }
                         label for="horizontal" .inline-flex."items-center"."mt-2"."gap-0.5".w-full.max-w-md.rounded-md
                             .cursor-pointer.select-none.bg-white.text-gray-900 {
-                            input #horizontal name="horizontal" type="checkbox" value="on" .hidden.peer;
+                            input #horizontal name="horizontal" type="checkbox" value="on" checked[defaults.horizontal] .hidden.peer;
                             span ."w-1/2".text-center.py-1.rounded-l-md.outline.outline-2.outline-indigo-400.text-indigo-700.font-semibold.bg-indigo-100.z-0
                                 ."peer-checked:outline-gray-300"."peer-checked:bg-transparent"."peer-checked:text-gray-900"
                                 { "Vertical" }
@@ -65,7 +80,7 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
 
                     // Image adjustment
                     div {
-                        label for="stretch" .block.text-sm.font-medium.leading-6.text-gray-900.w-fit {
+                        label .block.text-sm.font-medium.leading-6.text-gray-900.w-fit {
                             "Image Adjustment"
                             ( elements::label( html! {
                                 .flex.gap-3.mx-3.my-1 {
@@ -78,17 +93,36 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                                         p .text-center.text-gray-900.text-sm.font-semibold { "Stretch" }
                                     }
                                 }
+                                p .text-center.text-gray-900.text-sm.mx-3.my-1 { "Fill covers the screen and crops the image around its focal point." }
                             }) )
                         }
-                        label for="stretch" .inline-flex."items-center"."mt-2"."gap-0.5".w-full.max-w-md.rounded-md
+                        .inline-flex."items-center"."mt-2"."gap-0.5".w-full.max-w-md.rounded-md
                             .cursor-pointer.select-none.bg-white.text-gray-900 {
-                            input #stretch name="stretch" type="checkbox" .hidden.peer;
-                            span ."w-1/2".text-center.py-1.rounded-l-md.outline.outline-2.outline-indigo-400.text-indigo-700.font-semibold.bg-indigo-100.z-0
-                                ."peer-checked:outline-gray-300"."peer-checked:bg-transparent"."peer-checked:text-gray-900"
-                                { "Fit" }
-                            span ."w-1/2".text-center.py-1.rounded-r-md.outline.outline-2.outline-gray-300
-                                ."peer-checked:outline-indigo-400"."peer-checked:z-0"."peer-checked:text-indigo-700"."peer-checked:font-semibold"."peer-checked:bg-indigo-100"
-                                { "Stretch" }
+                            @let modes = [("fit", "Fit", "rounded-l-md"), ("stretch", "Stretch", ""), ("fill", "Fill", "rounded-r-md")];
+                            @for (value, title, rounded) in modes {
+                                label .flex-1.cursor-pointer {
+                                    input name="fit" type="radio" value=(value) checked[value == defaults.fit]
+                                        onchange="toggle_focus_picker()"
+                                        .hidden.peer;
+                                    span .(rounded).block.text-center.py-1.outline.outline-2.outline-gray-300
+                                        ."peer-checked:outline-indigo-400"."peer-checked:z-0"."peer-checked:text-indigo-700"."peer-checked:font-semibold"."peer-checked:bg-indigo-100"
+                                        { (title) }
+                                }
+                            }
+                        }
+                        // Focal point picker, only shown when filling
+                        #focus-picker .hidden[defaults.fit != "fill"].mt-2.max-w-md {
+                            p .text-sm.text-gray-500
+                                { "Click on the image to choose the part kept on screen, otherwise it's picked automatically." }
+                            .relative.w-fit.mt-2 {
+                                img #focus-preview alt="Preview of the chosen image" onclick="set_focal_point(event)"
+                                    style="max-height: 16rem"
+                                    .block.cursor-pointer;
+                                #focus-marker .absolute.hidden.pointer-events-none
+                                    style="width: 14px; height: 14px; margin: -7px 0 0 -7px; border: 2px solid white; border-radius: 9999px; background: #4f46e5" {}
+                            }
+                            input #focus_x name="focus_x" type="hidden";
+                            input #focus_y name="focus_y" type="hidden";
                         }
                     }
 
@@ -97,6 +131,13 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                         label .block.text-sm.font-medium.leading-6.text-gray-900
                             { "Background Color:" }
                         .flex.gap-6.mt-3 {
+                            // Auto picks a color matching the edges of the image
+                            input name="background_color" value="auto" type="radio" title="Auto" checked[defaults.background == "auto"]
+                                style="background-image: linear-gradient(135deg, #ffffff 50%, #1f2937 50%)"
+                                .cursor-pointer.border-none.w-8.h-8.shadow-sm.bg-white
+                                ."checked:outline"."checked:outline-4"."checked:outline-indigo-300"
+                                ."checked:outline-offset-4"."focus:outline-none"."focus:outline-offset-4"."focus:outline-indigo-300"
+                                ."focus:outline-4"."focus:ring-2"."focus:ring-offset-8"."focus:ring-indigo-200";
                             // Explicit classes for tailwind css generation
                             @let colors = vec![
                                 ("bg-white", "text-white", "white"),
@@ -105,7 +146,7 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                                 ("bg-gray-800", "text-gray-800", "black"),
                             ];
                             @for (bg_color, checked_text_color, value) in &colors {
-                                input checked name="background_color" value=(value) type="radio"
+                                input name="background_color" value=(value) type="radio" checked[*value == defaults.background]
                                     .cursor-pointer.border-none.w-8.h-8.shadow-sm.(bg_color)
                                     ."checked:bg-none".(checked_text_color)."checked:outline"."checked:outline-4"."checked:outline-indigo-300"
                                     ."checked:outline-offset-4"."focus:outline-none"."focus:outline-offset-4"."focus:outline-indigo-300"
@@ -114,11 +155,52 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
                         }
                     }
 
+                    // Dithering and tone adjustments
+                    details .max-w-md {
+                        summary .cursor-pointer.select-none.text-sm.font-medium.leading-6.text-gray-900
+                            { "Adjustments" }
+                        .grid.grid-cols-1.gap-y-5.mt-3 {
+                            div {
+                                label for="dither" .block.text-sm.font-medium.leading-6.text-gray-900
+                                    { "Dithering" }
+                                select #dither name="dither"
+                                    .mt-2.block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm
+                                    ."focus:ring-2"."focus:ring-indigo-200" {
+                                    @for dither in Dither::ALL {
+                                        option value=(dither.id()) selected[dither.id() == defaults.dither] { (dither.name()) }
+                                    }
+                                }
+                            }
+                            @let sliders = [
+                                ("brightness", "Brightness", "-100", "100", "1", defaults.brightness.to_string()),
+                                ("contrast", "Contrast", "-100", "100", "1", defaults.contrast.to_string()),
+                                ("gamma", "Gamma", "0.1", "3", "0.1", defaults.gamma.to_string()),
+                                ("sharpen", "Sharpen", "0", "5", "0.5", defaults.sharpen.to_string()),
+                            ];
+                            @for (name, title, min, max, step, value) in sliders {
+                                div {
+                                    label for=(name) .flex.justify-between.text-sm.font-medium.leading-6.text-gray-900 {
+                                        (title)
+                                        output .text-gray-500 { (value) }
+                                    }
+                                    input #(name) name=(name) type="range" min=(min) max=(max) step=(step) value=(value)
+                                        oninput="this.previousElementSibling.lastElementChild.value = this.value"
+                                        .w-full.cursor-pointer;
+                                }
+                            }
+                        }
+                    }
+
+                    // Converted image, filled in by the Preview button
+                    #preview {}
+
                     // Submit buttons
                     .flex.items-center.justify-end.gap-x-6 {
                         .indicator {
                             img .indicator-loading width="16px" src="/static/resources/pulse-rings-1.svg";
                         }
+                        button type="button" hx-post="/preview" hx-target="#preview" hx-swap="innerHTML"
+                            .btn-secondary.indicator { "Preview" }
                         button name="set_image" value="false" type="submit"
                             .btn-secondary.indicator { "Upload" }
                         button name="set_image" value="true" type="submit"
@@ -129,11 +211,75 @@ pub fn main(server_images: Option<&Vec<String>>) -> Markup {
             // Separator
             .border-b."border-gray-900/10".mb-12 {}
 
+            // Slideshow controls, loaded separately since they need the images on the Kindle
+            #slideshow hx-get="/slideshow" hx-trigger="load" hx-swap="outerHTML" {}
+
+            // Playlists, loaded separately like the slideshow
+            #playlists hx-get="/playlists" hx-trigger="load" hx-swap="outerHTML" {}
+
+            // Dashboard, shown on the Kindle instead of the slideshow
+            #dashboard hx-get="/dashboard" hx-trigger="load" hx-swap="outerHTML" {}
+
+            // Message printed over the image on the Kindle
+            #print-text hx-get="/print" hx-trigger="load" hx-swap="outerHTML" {}
+
+            // Prep and restore of the Kindle
+            #device-mode hx-get="/devices/mode" hx-trigger="load" hx-swap="outerHTML" {}
+
+            // Re-convert settings, filled in from an image or the library controls
+            #reconvert {}
+
+            // Pushing an image to several devices, filled in from an image
+            #push {}
+
             // Grid of images available on the Kindle
+            (elements::library_controls(tags))
             #server-images {
                 (elements::server_images(server_images))
             }
         }
     };
-    elements::base("Main", content)
+    elements::base("Main", Some(account), content)
+}
+
+// Login form, creates the first admin account instead when there are no accounts yet
+pub fn login(error: Option<&str>, setup: bool) -> Markup {
+    let content = html! {
+        .mx-auto.max-w-md.px-4.py-8 {
+            @if let Some(error) = error {
+                .alert.bg-red-100.border.border-red-400.text-red-700.px-4.py-3.rounded.relative.mb-6 role="alert" {
+                    span .block { (error) }
+                }
+            }
+            h2 .text-lg.font-semibold.text-gray-900 {
+                @if setup { "Create the admin account" } @else { "Sign in" }
+            }
+            @if setup {
+                p .text-sm.text-gray-500 { "There are no accounts yet, this one can manage the others." }
+            }
+            form method="post" action="/login" .grid.grid-cols-1.gap-y-5.mt-2 {
+                input name="name" type="text" placeholder="Name" required autofocus autocomplete="username"
+                    .block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                input name="password" type="password" placeholder="Password" required
+                    minlength=[setup.then_some(8)]
+                    autocomplete=(if setup { "new-password" } else { "current-password" })
+                    .block.w-full.rounded-md.border-0.shadow-sm.ring-1.ring-inset.ring-gray-300.bg-white.text-gray-900.text-sm;
+                button type="submit" .btn-primary { @if setup { "Create" } @else { "Sign in" } }
+            }
+        }
+    };
+    elements::base("Sign in", None, content)
+}
+
+// Account management, only for admins
+pub fn accounts(account: &Account, accounts: &[Account], tokens: &[ApiToken]) -> Markup {
+    let content = html! {
+        .mx-auto.max-w-5xl.px-4.py-8 {
+            // Error placeholder
+            #newalert {}
+
+            (elements::accounts(accounts, tokens, None))
+        }
+    };
+    elements::base("Accounts", Some(account), content)
 }
@@ -5,4 +5,36 @@ function set_filename_from_upload() {
         let filename = file_input.files[0].name.split('.')[0];
         filename_input.value = filename
     }
-}
\ No newline at end of file
+}
+
+// Shows the chosen image so its focal point can be picked, forgetting the previous one
+function update_focus_preview() {
+    let file_input = document.getElementById("file")
+    let preview = document.getElementById("focus-preview")
+    if (preview.src) {
+        URL.revokeObjectURL(preview.src)
+    }
+    preview.src = file_input.files.length > 0 ? URL.createObjectURL(file_input.files[0]) : ""
+    document.getElementById("focus_x").value = ""
+    document.getElementById("focus_y").value = ""
+    document.getElementById("focus-marker").classList.add("hidden")
+}
+
+function toggle_focus_picker() {
+    let fill = document.querySelector("input[name=fit]:checked").value === "fill"
+    document.getElementById("focus-picker").classList.toggle("hidden", !fill)
+}
+
+// Stores where the preview was clicked, relative to its size
+function set_focal_point(event) {
+    let preview = event.currentTarget
+    let x = event.offsetX / preview.clientWidth
+    let y = event.offsetY / preview.clientHeight
+    document.getElementById("focus_x").value = x.toFixed(3)
+    document.getElementById("focus_y").value = y.toFixed(3)
+
+    let marker = document.getElementById("focus-marker")
+    marker.style.left = event.offsetX + "px"
+    marker.style.top = event.offsetY + "px"
+    marker.classList.remove("hidden")
+}